@@ -17,6 +17,35 @@ export interface LoginRequest {
 	password: string;
 }
 
+export interface TwoFactorLoginRequest {
+	token: string;
+	code: string;
+}
+
+export interface DisableTwoFactorRequest {
+	password: string;
+	code: string;
+}
+
+export interface LinkIdentityRequest {
+	password?: string;
+	jwt?: string;
+}
+
+export interface CreateApiTokenRequest {
+	name: string;
+	scopes: string[];
+	expires_in_days?: number;
+}
+
+export interface CreateOAuthClientRequest {
+	name: string;
+	redirect_uris: string[];
+	allowed_scopes: string[];
+	trusted?: boolean;
+	confidential?: boolean;
+}
+
 export interface RegisterRequest {
 	email: string;
 	username: string;
@@ -46,21 +75,58 @@ export interface ApiResponse {
 	message: string;
 }
 
+let csrfToken: string | null = null;
+
+// State-changing requests made with the session cookie must echo the token back in a header
+async function getCsrfToken(refresh = false): Promise<string | null> {
+	if (csrfToken && !refresh) {
+		return csrfToken;
+	}
+
+	try {
+		const response = await fetch(`${API_BASE_URL}/csrfToken`, {
+			credentials: 'include'
+		});
+		if (response.ok) {
+			const data: ApiResponse = await response.json();
+			csrfToken = data.message;
+		}
+	} catch (error) {
+		csrfToken = null;
+	}
+	return csrfToken;
+}
+
 async function apiCall(
 	endpoint: string,
 	method: 'GET' | 'POST' | 'PATCH' | 'POST' | 'DELETE',
-	body?: any
+	body?: any,
+	retried = false
 ): Promise<ApiResponse> {
 	try {
+		const headers: Record<string, string> = {
+			'Content-Type': 'application/json'
+		};
+		if (method != 'GET') {
+			const token = await getCsrfToken();
+			if (token) {
+				headers['X-CSRF-Token'] = token;
+			}
+		}
+
 		const response = await fetch(`${API_BASE_URL}${endpoint}`, {
 			method,
-			headers: {
-				'Content-Type': 'application/json'
-			},
+			headers,
 			credentials: 'include',
 			body: body ? JSON.stringify(body) : undefined
 		});
 
+		// The token cookie may have expired, fetch a new one and try once more
+		if (response.status == 403 && method != 'GET' && !retried) {
+			await getCsrfToken(true);
+			return apiCall(endpoint, method, body, true);
+		}
+
 		if (!response.ok) {
 			let error = await response.json().catch(() => {
 				return { response_type: 'Error', message: 'Request failed' };
@@ -84,6 +150,30 @@ export const api = {
 		return apiCall('/account/login', 'POST', credentials);
 	},
 
+	async loginTwoFactor(details: TwoFactorLoginRequest): Promise<ApiResponse> {
+		return apiCall('/account/login/twoFactor', 'POST', details);
+	},
+
+	async magicLogin(email: string): Promise<ApiResponse> {
+		return apiCall('/account/login/email', 'POST', { email });
+	},
+
+	async completeMagicLogin(email: string, code: string): Promise<ApiResponse> {
+		return apiCall('/account/login/email/complete', 'POST', { email, code });
+	},
+
+	async revertEmailChange(code: string): Promise<ApiResponse> {
+		return apiCall('/account/changeEmail/revert', 'POST', { code });
+	},
+
+	async passkeyLoginOptions(): Promise<ApiResponse> {
+		return apiCall('/account/login/passkeyOptions', 'POST', null);
+	},
+
+	async passkeyLogin(credential: any): Promise<ApiResponse> {
+		return apiCall('/account/login/passkey', 'POST', credential);
+	},
+
 	async googleLogin(jwt: GoogleLoginRequest): Promise<ApiResponse> {
 		return apiCall('/account/login/google', 'POST', jwt);
 	},
@@ -116,5 +206,130 @@ export const api = {
 
 	async getProfile(): Promise<ApiResponse> {
 		return apiCall('/account/profile', 'GET', null);
+	},
+
+	async getSessions(): Promise<ApiResponse> {
+		return apiCall('/account/sessions', 'GET', null);
+	},
+
+	async revokeSession(sessionId: number): Promise<ApiResponse> {
+		return apiCall(`/account/sessions/${sessionId}`, 'DELETE', null);
+	},
+
+	async revokeOtherSessions(): Promise<ApiResponse> {
+		return apiCall('/account/sessions', 'DELETE', null);
+	},
+
+	async getPasskeys(): Promise<ApiResponse> {
+		return apiCall('/account/passkeys', 'GET', null);
+	},
+
+	async passkeyRegistrationOptions(): Promise<ApiResponse> {
+		return apiCall('/account/passkeys/registrationOptions', 'POST', null);
+	},
+
+	async registerPasskey(name: string, credential: any): Promise<ApiResponse> {
+		return apiCall('/account/passkeys', 'POST', { name, credential });
+	},
+
+	async removePasskey(passkeyId: number): Promise<ApiResponse> {
+		return apiCall(`/account/passkeys/${passkeyId}`, 'DELETE', null);
+	},
+
+	async enrollTwoFactor(): Promise<ApiResponse> {
+		return apiCall('/account/twoFactor/enroll', 'POST', null);
+	},
+
+	async confirmTwoFactor(code: string): Promise<ApiResponse> {
+		return apiCall('/account/twoFactor/confirm', 'POST', { code });
+	},
+
+	async disableTwoFactor(details: DisableTwoFactorRequest): Promise<ApiResponse> {
+		return apiCall('/account/twoFactor/disable', 'POST', details);
+	},
+
+	async getIdentities(): Promise<ApiResponse> {
+		return apiCall('/account/identities', 'GET', null);
+	},
+
+	async linkIdentity(provider: string, details: LinkIdentityRequest): Promise<ApiResponse> {
+		return apiCall(`/account/identities/link/${provider}`, 'POST', details);
+	},
+
+	async unlinkIdentity(identityId: number): Promise<ApiResponse> {
+		return apiCall(`/account/identities/${identityId}`, 'DELETE', null);
+	},
+
+	async getApiTokens(): Promise<ApiResponse> {
+		return apiCall('/account/tokens', 'GET', null);
+	},
+
+	async createApiToken(details: CreateApiTokenRequest): Promise<ApiResponse> {
+		return apiCall('/account/tokens', 'POST', details);
+	},
+
+	async revokeApiToken(tokenId: number): Promise<ApiResponse> {
+		return apiCall(`/account/tokens/${tokenId}`, 'DELETE', null);
+	},
+
+	async getOAuthRequest(requestId: string): Promise<ApiResponse> {
+		return apiCall(`/oauth/requests/${encodeURIComponent(requestId)}`, 'GET', null);
+	},
+
+	async decideOAuthRequest(requestId: string, approved: boolean): Promise<ApiResponse> {
+		return apiCall(`/oauth/requests/${encodeURIComponent(requestId)}`, 'POST', { approved });
+	}
+};
+
+export const adminApi = {
+	async getRoles(): Promise<ApiResponse> {
+		return apiCall('/admin/roles', 'GET', null);
+	},
+
+	async listUsers(search = '', page = 1, pageSize = 20): Promise<ApiResponse> {
+		const params = new URLSearchParams({
+			search,
+			page: page.toString(),
+			page_size: pageSize.toString()
+		});
+		return apiCall(`/admin/users?${params}`, 'GET', null);
+	},
+
+	async getUser(username: string): Promise<ApiResponse> {
+		return apiCall(`/admin/users/${encodeURIComponent(username)}`, 'GET', null);
+	},
+
+	async verifyEmail(username: string): Promise<ApiResponse> {
+		return apiCall(`/admin/users/${encodeURIComponent(username)}/verifyEmail`, 'POST', null);
+	},
+
+	async unlockUser(username: string): Promise<ApiResponse> {
+		return apiCall(`/admin/users/${encodeURIComponent(username)}/unlock`, 'POST', null);
+	},
+
+	async changeAuthLevel(username: string, authLevel: string): Promise<ApiResponse> {
+		return apiCall(`/admin/users/${encodeURIComponent(username)}/authLevel`, 'PATCH', {
+			auth_level: authLevel
+		});
+	},
+
+	async revokeSessions(username: string): Promise<ApiResponse> {
+		return apiCall(`/admin/users/${encodeURIComponent(username)}/sessions`, 'DELETE', null);
+	},
+
+	async deleteUser(username: string): Promise<ApiResponse> {
+		return apiCall(`/admin/users/${encodeURIComponent(username)}`, 'DELETE', null);
+	},
+
+	async getOAuthClients(): Promise<ApiResponse> {
+		return apiCall('/admin/oauthClients', 'GET', null);
+	},
+
+	async createOAuthClient(details: CreateOAuthClientRequest): Promise<ApiResponse> {
+		return apiCall('/admin/oauthClients', 'POST', details);
+	},
+
+	async deleteOAuthClient(clientId: string): Promise<ApiResponse> {
+		return apiCall(`/admin/oauthClients/${encodeURIComponent(clientId)}`, 'DELETE', null);
 	}
 };
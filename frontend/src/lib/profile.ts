@@ -1,5 +1,6 @@
 
 export interface Profile {
+	id: string;
 	username: string;
 	email: string;
 	email_verified: boolean;
@@ -7,3 +8,43 @@ export interface Profile {
 	identity_provider: string;
 	registration_ts: number;
 }
+
+export interface Session {
+	id: number;
+	created_ts: number | null;
+	last_seen_ts: number | null;
+	user_agent: string | null;
+	ip_address: string | null;
+	current: boolean;
+}
+
+export interface UserIdentity {
+	id: number;
+	identity_provider: string;
+	created_ts: number;
+}
+
+export interface ApiToken {
+	id: number;
+	name: string;
+	scopes: string[];
+	created_ts: number;
+	expiry_ts: number | null;
+	last_used_ts: number | null;
+}
+
+export interface OAuthClient {
+	client_id: string;
+	name: string;
+	redirect_uris: string[];
+	allowed_scopes: string[];
+	trusted: boolean;
+	confidential: boolean;
+	created_ts: number;
+}
+
+export interface AuthorizationRequestDetails {
+	client_name: string;
+	scope: string[];
+	trusted: boolean;
+}
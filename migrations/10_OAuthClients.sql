@@ -0,0 +1,12 @@
+        CREATE TABLE IF NOT EXISTS oauth_clients(
+            client_id VARCHAR(32) PRIMARY KEY,
+            name VARCHAR(100),
+            hashed_client_secret VARCHAR(64),
+            redirect_uris VARCHAR(2048)[] DEFAULT '{}',
+            allowed_scopes VARCHAR(50)[] DEFAULT '{}',
+            trusted BOOLEAN DEFAULT false,
+            created_ts BIGINT
+        );
+
+        ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS client_id VARCHAR(32) references oauth_clients(client_id) ON DELETE CASCADE;
+        ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS scope VARCHAR(500);
@@ -0,0 +1,4 @@
+        -- Existing sessions stored their keys in plaintext so everyone has to log in again
+        DELETE FROM sessions;
+        ALTER TABLE sessions RENAME COLUMN session_key TO hashed_session_key;
+        ALTER TABLE sessions ALTER COLUMN hashed_session_key TYPE VARCHAR(64);
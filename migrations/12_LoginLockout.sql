@@ -0,0 +1,3 @@
+        -- Accounts locked under the old rules have no lock time so are treated as past their cool-down
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS last_failed_login_ts BIGINT;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS locked_until_ts BIGINT;
@@ -0,0 +1,23 @@
+        -- The address an email change code moves the account to, the new address or the old one for a revert
+        ALTER TABLE codes ADD COLUMN IF NOT EXISTS target_email VARCHAR(320);
+
+        -- Everything keyed by email has to follow the user when their email changes
+        ALTER TABLE sessions DROP CONSTRAINT IF EXISTS sessions_email_fkey;
+        ALTER TABLE sessions ADD CONSTRAINT sessions_email_fkey
+            FOREIGN KEY (email) REFERENCES users(email) ON DELETE CASCADE ON UPDATE CASCADE;
+
+        ALTER TABLE codes DROP CONSTRAINT IF EXISTS codes_email_fkey;
+        ALTER TABLE codes ADD CONSTRAINT codes_email_fkey
+            FOREIGN KEY (email) REFERENCES users(email) ON DELETE CASCADE ON UPDATE CASCADE;
+
+        ALTER TABLE totp_secrets DROP CONSTRAINT IF EXISTS totp_secrets_email_fkey;
+        ALTER TABLE totp_secrets ADD CONSTRAINT totp_secrets_email_fkey
+            FOREIGN KEY (email) REFERENCES users(email) ON DELETE CASCADE ON UPDATE CASCADE;
+
+        ALTER TABLE recovery_codes DROP CONSTRAINT IF EXISTS recovery_codes_email_fkey;
+        ALTER TABLE recovery_codes ADD CONSTRAINT recovery_codes_email_fkey
+            FOREIGN KEY (email) REFERENCES users(email) ON DELETE CASCADE ON UPDATE CASCADE;
+
+        ALTER TABLE passkeys DROP CONSTRAINT IF EXISTS passkeys_email_fkey;
+        ALTER TABLE passkeys ADD CONSTRAINT passkeys_email_fkey
+            FOREIGN KEY (email) REFERENCES users(email) ON DELETE CASCADE ON UPDATE CASCADE;
@@ -0,0 +1,83 @@
+        -- Users are identified by a stable id so their email can change without touching anything that refers to them
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS id UUID NOT NULL DEFAULT gen_random_uuid();
+
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE sessions SET user_id = users.id FROM users WHERE sessions.email = users.email;
+        ALTER TABLE codes ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE codes SET user_id = users.id FROM users WHERE codes.email = users.email;
+        ALTER TABLE totp_secrets ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE totp_secrets SET user_id = users.id FROM users WHERE totp_secrets.email = users.email;
+        ALTER TABLE recovery_codes ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE recovery_codes SET user_id = users.id FROM users WHERE recovery_codes.email = users.email;
+        ALTER TABLE passkeys ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE passkeys SET user_id = users.id FROM users WHERE passkeys.email = users.email;
+        ALTER TABLE user_identities ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE user_identities SET user_id = users.id FROM users WHERE user_identities.email = users.email;
+        ALTER TABLE api_tokens ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE api_tokens SET user_id = users.id FROM users WHERE api_tokens.email = users.email;
+        ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS user_id UUID;
+        UPDATE refresh_tokens SET user_id = users.id FROM users WHERE refresh_tokens.email = users.email;
+
+        -- Dropping the email columns also drops their foreign keys, indexes and the totp_secrets primary key
+        ALTER TABLE sessions DROP COLUMN IF EXISTS email;
+        ALTER TABLE codes DROP COLUMN IF EXISTS email;
+        ALTER TABLE totp_secrets DROP COLUMN IF EXISTS email;
+        ALTER TABLE recovery_codes DROP COLUMN IF EXISTS email;
+        ALTER TABLE passkeys DROP COLUMN IF EXISTS email;
+        ALTER TABLE user_identities DROP COLUMN IF EXISTS email;
+        ALTER TABLE api_tokens DROP COLUMN IF EXISTS email;
+        ALTER TABLE refresh_tokens DROP COLUMN IF EXISTS email;
+
+        ALTER TABLE users DROP CONSTRAINT IF EXISTS users_pkey;
+        ALTER TABLE users ALTER COLUMN email SET NOT NULL;
+        ALTER TABLE users ADD PRIMARY KEY(id);
+
+        -- Rows which never belonged to a user can't be migrated
+        DELETE FROM sessions WHERE user_id IS NULL;
+        DELETE FROM codes WHERE user_id IS NULL;
+        DELETE FROM totp_secrets WHERE user_id IS NULL;
+        DELETE FROM recovery_codes WHERE user_id IS NULL;
+        DELETE FROM passkeys WHERE user_id IS NULL;
+        DELETE FROM user_identities WHERE user_id IS NULL;
+        DELETE FROM api_tokens WHERE user_id IS NULL;
+        DELETE FROM refresh_tokens WHERE user_id IS NULL;
+
+        ALTER TABLE sessions ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE sessions ADD CONSTRAINT sessions_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_sessions_user_id ON sessions(user_id);
+
+        ALTER TABLE codes ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE codes ADD CONSTRAINT codes_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_codes_user_id ON codes(user_id);
+
+        ALTER TABLE totp_secrets ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE totp_secrets ADD CONSTRAINT totp_secrets_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        ALTER TABLE totp_secrets ADD PRIMARY KEY(user_id);
+
+        ALTER TABLE recovery_codes ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE recovery_codes ADD CONSTRAINT recovery_codes_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_recovery_codes_user_id ON recovery_codes(user_id);
+
+        ALTER TABLE passkeys ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE passkeys ADD CONSTRAINT passkeys_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys(user_id);
+
+        ALTER TABLE user_identities ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE user_identities ADD CONSTRAINT user_identities_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_user_identities_user_id ON user_identities(user_id);
+
+        ALTER TABLE api_tokens ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE api_tokens ADD CONSTRAINT api_tokens_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_api_tokens_user_id ON api_tokens(user_id);
+
+        ALTER TABLE refresh_tokens ALTER COLUMN user_id SET NOT NULL;
+        ALTER TABLE refresh_tokens ADD CONSTRAINT refresh_tokens_user_id_fkey
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+        CREATE INDEX IF NOT EXISTS idx_refresh_tokens_user_id ON refresh_tokens(user_id);
@@ -0,0 +1,2 @@
+        -- Session expiry timestamps are seconds since the epoch like every other timestamp, which overflow INT in 2038
+        ALTER TABLE sessions ALTER COLUMN expiry TYPE BIGINT;
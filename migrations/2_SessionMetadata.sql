@@ -0,0 +1,5 @@
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS id SERIAL UNIQUE;
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS created_ts BIGINT;
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS last_seen_ts BIGINT;
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_agent VARCHAR(512);
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ip_address VARCHAR(64);
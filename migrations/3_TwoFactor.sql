@@ -0,0 +1,17 @@
+        CREATE TABLE IF NOT EXISTS totp_secrets(
+            email VARCHAR(320) references users(email) ON DELETE CASCADE,
+            secret VARCHAR(128),
+            confirmed BOOLEAN DEFAULT false,
+            last_used_step BIGINT DEFAULT 0,
+            created_ts BIGINT,
+            PRIMARY KEY(email)
+        );
+
+        CREATE TABLE IF NOT EXISTS recovery_codes(
+            id SERIAL PRIMARY KEY,
+            email VARCHAR(320) references users(email) ON DELETE CASCADE,
+            hashed_code VARCHAR(255),
+            used BOOLEAN DEFAULT false
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_recovery_codes_email ON recovery_codes(email);
@@ -0,0 +1,12 @@
+        CREATE TABLE IF NOT EXISTS passkeys(
+            id SERIAL PRIMARY KEY,
+            email VARCHAR(320) references users(email) ON DELETE CASCADE,
+            credential_id VARCHAR(1024) unique,
+            public_key VARCHAR(255),
+            sign_count BIGINT DEFAULT 0,
+            name VARCHAR(100),
+            created_ts BIGINT,
+            last_used_ts BIGINT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_passkeys_email ON passkeys(email);
@@ -0,0 +1,15 @@
+        CREATE TABLE IF NOT EXISTS user_identities(
+            id SERIAL UNIQUE,
+            email VARCHAR(320) references users(email) ON DELETE CASCADE ON UPDATE CASCADE,
+            identity_provider VARCHAR(30),
+            sub VARCHAR(256),
+            created_ts BIGINT,
+            PRIMARY KEY(identity_provider, sub)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_user_identities_email ON user_identities(email);
+
+        INSERT INTO user_identities (email, identity_provider, sub, created_ts)
+            SELECT email, identity_provider, sub, registration_ts FROM users
+            WHERE sub IS NOT NULL AND identity_provider IS NOT NULL
+            ON CONFLICT DO NOTHING;
@@ -0,0 +1,21 @@
+        CREATE TABLE IF NOT EXISTS roles(
+            name VARCHAR(30) PRIMARY KEY,
+            description VARCHAR(255),
+            created_ts BIGINT
+        );
+
+        CREATE TABLE IF NOT EXISTS role_permissions(
+            role VARCHAR(30) references roles(name) ON DELETE CASCADE ON UPDATE CASCADE,
+            permission VARCHAR(100),
+            PRIMARY KEY(role, permission)
+        );
+
+        INSERT INTO roles (name, description, created_ts) VALUES
+            ('user', 'Default role for registered users', EXTRACT(EPOCH FROM NOW())::BIGINT),
+            ('admin', 'Full access to every route and permission', EXTRACT(EPOCH FROM NOW())::BIGINT)
+            ON CONFLICT DO NOTHING;
+
+        -- The wildcard grants every permission
+        INSERT INTO role_permissions (role, permission) VALUES ('admin', '*') ON CONFLICT DO NOTHING;
+
+        ALTER TABLE users ADD CONSTRAINT fk_users_auth_level FOREIGN KEY (auth_level) REFERENCES roles(name) ON UPDATE CASCADE;
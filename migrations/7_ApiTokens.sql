@@ -0,0 +1,12 @@
+        CREATE TABLE IF NOT EXISTS api_tokens(
+            id SERIAL PRIMARY KEY,
+            email VARCHAR(320) references users(email) ON DELETE CASCADE ON UPDATE CASCADE,
+            name VARCHAR(100),
+            hashed_token VARCHAR(64) unique,
+            scopes VARCHAR(30)[] DEFAULT '{}',
+            created_ts BIGINT,
+            expiry_ts BIGINT,
+            last_used_ts BIGINT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_api_tokens_email ON api_tokens(email);
@@ -0,0 +1,13 @@
+        CREATE TABLE IF NOT EXISTS refresh_tokens(
+            id SERIAL PRIMARY KEY,
+            email VARCHAR(320) references users(email) ON DELETE CASCADE ON UPDATE CASCADE,
+            family_id VARCHAR(32),
+            hashed_token VARCHAR(64) unique,
+            created_ts BIGINT,
+            expiry_ts BIGINT,
+            used_ts BIGINT,
+            revoked BOOLEAN DEFAULT false
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family_id ON refresh_tokens(family_id);
+        CREATE INDEX IF NOT EXISTS idx_refresh_tokens_email ON refresh_tokens(email);
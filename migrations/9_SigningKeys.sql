@@ -0,0 +1,8 @@
+        CREATE TABLE IF NOT EXISTS signing_keys(
+            kid VARCHAR(32) PRIMARY KEY,
+            algorithm VARCHAR(10),
+            private_key TEXT,
+            created_ts BIGINT,
+            activation_ts BIGINT,
+            expiry_ts BIGINT
+        );
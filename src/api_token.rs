@@ -0,0 +1,222 @@
+use crate::AppState;
+use crate::default_route_handlers::ErrorList;
+use crate::utilities::{generate_unique_id, hash_token};
+use chrono::Utc;
+use http::{HeaderMap, Method, header::AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+// The prefix makes tokens easy to recognise, e.g. by secret scanners
+const TOKEN_PREFIX: &str = "axm_";
+const TOKEN_LENGTH: u8 = 40;
+const MAX_EXPIRY_IN_DAYS: i64 = 365;
+const SECONDS_IN_DAY: i64 = 86400;
+
+// Read allows GET requests, write allows everything else and admin is also needed for admin routes
+pub const SCOPE_READ: &str = "read";
+pub const SCOPE_WRITE: &str = "write";
+pub const SCOPE_ADMIN: &str = "admin";
+const VALID_SCOPES: [&str; 3] = [SCOPE_READ, SCOPE_WRITE, SCOPE_ADMIN];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_ts: i64,
+    pub expiry_ts: Option<i64>,
+    pub last_used_ts: Option<i64>,
+}
+
+// The plain token is only ever returned here, when it is created
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedApiToken {
+    pub id: i32,
+    pub token: String,
+}
+
+// Added to the request extensions when it was authenticated with a token rather than a session
+#[derive(Clone, Debug)]
+pub struct TokenScopes(pub Vec<String>);
+
+impl TokenScopes {
+    pub fn contains(&self, scope: &str) -> bool {
+        self.0.iter().any(|s| s == scope)
+    }
+
+    pub fn allows_method(&self, method: &Method) -> bool {
+        if method == Method::GET || method == Method::HEAD {
+            self.contains(SCOPE_READ) || self.contains(SCOPE_WRITE)
+        } else {
+            self.contains(SCOPE_WRITE)
+        }
+    }
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+pub fn get_bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim().to_string())
+}
+
+pub fn validate_scopes(scopes: &[String]) -> Result<bool, ErrorList> {
+    if !scopes.is_empty()
+        && scopes
+            .iter()
+            .all(|scope| VALID_SCOPES.contains(&scope.as_str()))
+    {
+        return Ok(true);
+    }
+    Err(ErrorList::InvalidTokenScopes)
+}
+
+pub fn get_expiry_ts(expires_in_days: Option<i64>, now: i64) -> Result<Option<i64>, ErrorList> {
+    match expires_in_days {
+        None => Ok(None),
+        Some(days) if (1..=MAX_EXPIRY_IN_DAYS).contains(&days) => {
+            Ok(Some(now + days * SECONDS_IN_DAY))
+        }
+        Some(_) => Err(ErrorList::InvalidTokenExpiry),
+    }
+}
+
+pub async fn create_api_token(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    name: &str,
+    scopes: &[String],
+    expiry_ts: Option<i64>,
+) -> Result<CreatedApiToken, anyhow::Error> {
+    let token = format!("{}{}", TOKEN_PREFIX, generate_unique_id(TOKEN_LENGTH));
+
+    let row = sqlx::query!(
+        "INSERT INTO api_tokens (user_id, name, hashed_token, scopes, created_ts, expiry_ts) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        user_id,
+        name.trim(),
+        hash_token(&token),
+        scopes,
+        Utc::now().timestamp(),
+        expiry_ts
+    )
+    .fetch_one(&state.db_connection_pool)
+    .await?;
+
+    Ok(CreatedApiToken { id: row.id, token })
+}
+
+pub async fn get_api_tokens(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<Vec<ApiToken>, anyhow::Error> {
+    let tokens = sqlx::query_as!(
+        ApiToken,
+        r#"SELECT
+            id,
+            name as "name!",
+            scopes as "scopes!",
+            created_ts as "created_ts!",
+            expiry_ts,
+            last_used_ts
+        FROM api_tokens WHERE user_id = $1
+        ORDER BY created_ts DESC"#,
+        user_id
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    Ok(tokens)
+}
+
+pub async fn delete_api_token(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    token_id: i32,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM api_tokens WHERE user_id = $1 AND id = $2",
+        user_id,
+        token_id
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Returns the owner's id and the token's scopes, recording when it was used
+pub async fn validate_api_token(
+    token: &str,
+    state: Arc<AppState>,
+) -> Result<(Uuid, TokenScopes), anyhow::Error> {
+    let now = Utc::now().timestamp();
+    let row = sqlx::query!(
+        r#"UPDATE api_tokens SET last_used_ts = $1
+        WHERE hashed_token = $2 AND (expiry_ts IS NULL OR expiry_ts > $1)
+        RETURNING user_id, scopes as "scopes!""#,
+        now,
+        hash_token(token)
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    match row {
+        Some(row) => Ok((row.user_id, TokenScopes(row.scopes))),
+        None => {
+            event!(
+                Level::INFO,
+                "Bearer token was found but did not match a valid API token"
+            );
+            Err(ErrorList::Unauthorised.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_token_is_extracted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer axm_abc".parse().unwrap());
+        assert_eq!(get_bearer_token(&headers), Some("axm_abc".to_string()));
+
+        headers.insert(AUTHORIZATION, "Basic dXNlcjpwYXNz".parse().unwrap());
+        assert_eq!(get_bearer_token(&headers), None);
+    }
+
+    #[test]
+    fn unknown_scopes_are_rejected() {
+        assert!(validate_scopes(&["read".to_string()]).is_ok());
+        assert!(validate_scopes(&[]).is_err());
+        assert!(validate_scopes(&["read".to_string(), "delete".to_string()]).is_err());
+    }
+
+    #[test]
+    fn read_scope_only_allows_get() {
+        let scopes = TokenScopes(vec![SCOPE_READ.to_string()]);
+        assert!(scopes.allows_method(&Method::GET));
+        assert!(!scopes.allows_method(&Method::POST));
+        assert!(!scopes.allows_method(&Method::DELETE));
+    }
+
+    #[test]
+    fn expiry_must_be_within_limit() {
+        assert!(matches!(get_expiry_ts(None, 0), Ok(None)));
+        assert!(matches!(
+            get_expiry_ts(Some(1), 0),
+            Ok(Some(SECONDS_IN_DAY))
+        ));
+        assert!(get_expiry_ts(Some(0), 0).is_err());
+        assert!(get_expiry_ts(Some(MAX_EXPIRY_IN_DAYS + 1), 0).is_err());
+    }
+}
@@ -1,22 +1,36 @@
 use crate::AppState;
-use crate::config::AuthLevel;
+use crate::config::{AuthLevel, ServerConfig};
 use crate::default_route_handlers::{AppError, CodeType, ErrorList, RegistrationDetails};
-use crate::user::User;
-use crate::utilities::{Email, generate_unique_id, hash_password, send_email};
+use crate::session_store::NewSession;
+use crate::user::{User, get_user_by_email, get_user_by_id, get_user_by_sub, get_user_by_username};
+use crate::utilities::{Email, generate_unique_id, hash_password, hash_token, send_email};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+};
 use chrono::Utc;
 use cookie::Cookie;
 use cookie::time::Duration;
-use http::HeaderMap;
-use std::sync::Arc;
+use http::{HeaderMap, StatusCode, header::USER_AGENT};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
 use tracing::{Level, event};
+use uuid::Uuid;
 
 const HOURS_IN_DAY: u32 = 24;
 const SECONDS_IN_HOUR: u32 = 3600;
+const SECONDS_IN_MINUTE: i64 = 60;
+// How often an active session's expiry is moved forward
+const SESSION_EXTENSION_INTERVAL: i64 = 300;
+const MAX_USER_AGENT_LENGTH: usize = 512;
 
-#[derive(Clone)]
+// Any provider other than the built in ones is identified by its configured OIDC provider id
+#[derive(Clone, Debug, PartialEq)]
 pub enum IdentityProvider {
     Google,
+    GitHub,
     Default,
+    Oidc(String),
 }
 
 impl From<String> for IdentityProvider {
@@ -24,7 +38,8 @@ impl From<String> for IdentityProvider {
         match value.to_lowercase().as_str() {
             "default" => Self::Default,
             "google" => Self::Google,
-            _ => Self::Default,
+            "github" => Self::GitHub,
+            provider_id => Self::Oidc(provider_id.to_string()),
         }
     }
 }
@@ -33,66 +48,286 @@ impl From<IdentityProvider> for String {
     fn from(value: IdentityProvider) -> Self {
         match value {
             IdentityProvider::Google => "google".to_string(),
+            IdentityProvider::GitHub => "github".to_string(),
             IdentityProvider::Default => "default".to_string(),
+            IdentityProvider::Oidc(provider_id) => provider_id,
         }
     }
 }
 
-pub async fn validate_cookie(
+// Device details recorded against a session when it is created
+#[derive(Clone, Default, Debug)]
+pub struct SessionMetadata {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionMetadata
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+        // Only available when the app is served with connect info
+        let ip_address = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip().to_string());
+
+        Ok(Self {
+            user_agent,
+            ip_address,
+        })
+    }
+}
+
+// Who a request was authenticated as, inserted into the request extensions by ValidateSession.
+// The session id is only set when the session cookie was used
+#[derive(Clone, Debug)]
+pub struct AuthContext {
+    pub user_id: Uuid,
+    pub session_id: Option<i32>,
+    pub auth_level: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthContext
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthContext>()
+            .cloned()
+            .ok_or((StatusCode::UNAUTHORIZED, "Unauthorised"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub id: i32,
+    pub created_ts: Option<i64>,
+    pub last_seen_ts: Option<i64>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub current: bool,
+}
+
+pub fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    let cookies = headers.get("cookie")?.to_str().ok()?;
+    cookies
+        .split(';')
+        .filter_map(|cookie_string| Cookie::parse(cookie_string.trim()).ok())
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
+}
+
+pub fn get_session_key(headers: &HeaderMap) -> Option<String> {
+    get_cookie(headers, "session-key")
+}
+
+// A session which was validated, extended is set when its expiry moved so the cookie should be refreshed
+#[derive(Clone, Debug)]
+pub struct ValidatedSession {
+    pub id: i32,
+    pub user_id: Uuid,
+    pub session_key: String,
+    pub expiry: i64,
+    pub extended: bool,
+}
+
+pub fn session_cookie(session_key: String, max_age_in_seconds: i64) -> Cookie<'static> {
+    Cookie::build(("session-key", session_key))
+        .max_age(Duration::seconds(max_age_in_seconds))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .build()
+}
+
+// Sessions expire once idle for the idle timeout and never outlive session_length_in_days
+fn get_session_expiry(config: &ServerConfig, absolute_expiry: i64, now: i64) -> i64 {
+    (now + config.session_idle_timeout_in_minutes * SECONDS_IN_MINUTE).min(absolute_expiry)
+}
+
+fn get_absolute_expiry(config: &ServerConfig, created_ts: i64) -> i64 {
+    created_ts + config.session_length_in_days * HOURS_IN_DAY as i64 * SECONDS_IN_HOUR as i64
+}
+
+pub async fn validate_session(
     headers: &HeaderMap,
     state: Arc<AppState>,
-) -> Result<String, anyhow::Error> {
-    if let Some(cookies) = headers.get("cookie") {
-        for cookie_string in cookies.to_str()?.split(';') {
-            let cookie = Cookie::parse(cookie_string)?;
-            if cookie.name() == "session-key" {
-                let session = sqlx::query!(
-                    "SELECT email FROM sessions WHERE session_key = $1 AND expiry > $2",
-                    cookie.value(),
-                    Utc::now().timestamp() as i32
-                )
-                .fetch_optional(&state.db_connection_pool)
-                .await?;
-                if let Some(row) = session {
-                    return Ok(row.email.unwrap_or_default());
-                }
-                event!(
-                    Level::INFO,
-                    "Session key cookie was found but did not match a valid session"
-                );
-                return Err(ErrorList::Unauthorised.into());
-            }
+) -> Result<ValidatedSession, anyhow::Error> {
+    let Some(session_key) = get_session_key(headers) else {
+        event!(Level::INFO, "No session key cookie was found");
+        return Err(ErrorList::Unauthorised.into());
+    };
+    let config = &state.config.server;
+    let now = Utc::now().timestamp();
+    let idle_timeout = config.session_idle_timeout_in_minutes * SECONDS_IN_MINUTE;
+
+    let hashed_session_key = hash_token(&session_key);
+
+    let session = state
+        .session_store
+        .get(&hashed_session_key)
+        .await?
+        .filter(|session| {
+            session.expiry > now
+                && session
+                    .last_seen_ts
+                    .is_none_or(|last_seen_ts| last_seen_ts + idle_timeout > now)
+        });
+    let Some(session) = session else {
+        event!(
+            Level::INFO,
+            "Session key cookie was found but did not match a valid session"
+        );
+        return Err(ErrorList::Unauthorised.into());
+    };
+
+    // Extending is throttled so an active session isn't written to on every request
+    if session
+        .last_seen_ts
+        .is_none_or(|last_seen_ts| last_seen_ts + SESSION_EXTENSION_INTERVAL <= now)
+    {
+        let absolute_expiry = session
+            .created_ts
+            .map(|created_ts| get_absolute_expiry(config, created_ts))
+            .unwrap_or(session.expiry);
+        let expiry = get_session_expiry(config, absolute_expiry, now);
+        if state
+            .session_store
+            .extend(&hashed_session_key, expiry, now)
+            .await?
+        {
+            return Ok(ValidatedSession {
+                id: session.id,
+                user_id: session.user_id,
+                session_key,
+                expiry,
+                extended: true,
+            });
         }
     }
 
-    event!(Level::INFO, "No session key cookie was found");
-    Err(ErrorList::Unauthorised.into())
+    Ok(ValidatedSession {
+        id: session.id,
+        user_id: session.user_id,
+        session_key,
+        expiry: session.expiry,
+        extended: false,
+    })
 }
 
-pub async fn create_session(user: &User, state: Arc<AppState>) -> Result<Cookie<'_>, AppError> {
+pub async fn create_session(
+    user: &User,
+    state: Arc<AppState>,
+    metadata: &SessionMetadata,
+) -> Result<Cookie<'static>, AppError> {
     let session_key = generate_unique_id(100);
-    let session_cookie = Cookie::build(("session-key", session_key.clone()))
-        .max_age(Duration::days(state.config.server.session_length_in_days))
-        .path("/")
-        .secure(true)
-        .http_only(true)
-        .build();
+    let created_ts = Utc::now().timestamp();
+    let config = &state.config.server;
+    let expiry = get_session_expiry(config, get_absolute_expiry(config, created_ts), created_ts);
 
-    let expiry = Utc::now().timestamp()
-        + (state.config.server.session_length_in_days
-            * HOURS_IN_DAY as i64
-            * SECONDS_IN_HOUR as i64);
+    state
+        .session_store
+        .insert(NewSession {
+            hashed_session_key: hash_token(&session_key),
+            user_id: user.id,
+            expiry,
+            created_ts,
+            user_agent: metadata.user_agent.clone(),
+            ip_address: metadata.ip_address.clone(),
+        })
+        .await?;
 
-    sqlx::query!(
-        "INSERT INTO sessions (session_key, email, expiry) VALUES ($1, $2, $3)",
-        &session_key,
-        &user.email,
-        expiry as i32
-    )
-    .execute(&state.db_connection_pool)
-    .await?;
+    Ok(session_cookie(session_key, expiry - created_ts))
+}
+
+pub async fn get_user_sessions(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    current_session_key: &str,
+) -> Result<Vec<Session>, anyhow::Error> {
+    let hashed_current_session_key = hash_token(current_session_key);
+    let sessions = state
+        .session_store
+        .list(user_id, Utc::now().timestamp())
+        .await?
+        .into_iter()
+        .map(|session| Session {
+            id: session.id,
+            created_ts: session.created_ts,
+            last_seen_ts: session.last_seen_ts,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+            current: session.hashed_session_key == hashed_current_session_key,
+        })
+        .collect();
+
+    Ok(sessions)
+}
 
-    Ok(session_cookie)
+pub async fn delete_session(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    session_id: i32,
+) -> Result<bool, anyhow::Error> {
+    state.session_store.delete(user_id, session_id).await
+}
+
+pub async fn delete_session_by_key(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    session_key: &str,
+) -> Result<(), anyhow::Error> {
+    state
+        .session_store
+        .delete_by_key(user_id, &hash_token(session_key))
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_other_sessions(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    current_session_key: &str,
+) -> Result<u64, anyhow::Error> {
+    state
+        .session_store
+        .delete_others(user_id, &hash_token(current_session_key))
+        .await
+}
+
+// Refresh tokens are removed too so token based clients are also signed out
+pub async fn delete_all_sessions(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<u64, anyhow::Error> {
+    let revoked = state.session_store.delete_all(user_id).await?;
+    sqlx::query!("DELETE FROM refresh_tokens WHERE user_id = $1", user_id)
+        .execute(&state.db_connection_pool)
+        .await?;
+
+    Ok(revoked)
 }
 
 pub async fn create_registration(
@@ -111,48 +346,117 @@ pub async fn create_registration(
     let registration_ts = Utc::now().timestamp();
     let identity_provider_str = String::from(identity_provider.clone());
 
-    match identity_provider {
-        IdentityProvider::Google => {
+    let id = match identity_provider {
+        IdentityProvider::Google | IdentityProvider::GitHub | IdentityProvider::Oidc(_) => {
             let sub = registration_details
                 .sub
                 .as_ref()
-                .expect("Sub missing for Google registration");
-            sqlx::query!(
-                "INSERT INTO users (email, username, registration_ts, identity_provider, sub) VALUES ($1, $2, $3, $4, $5)",
+                .expect("Sub missing for identity provider registration");
+            let mut transaction = state.db_connection_pool.begin().await?;
+            let id = sqlx::query!(
+                "INSERT INTO users (email, username, registration_ts, identity_provider) VALUES ($1, $2, $3, $4) RETURNING id",
                 &registration_details.email,
                 &registration_details.username,
                 registration_ts,
-                &identity_provider_str,
-                sub
+                &identity_provider_str
             )
-            .execute(&state.db_connection_pool)
+            .fetch_one(&mut *transaction)
             .await?
+            .id;
+            sqlx::query!(
+                "INSERT INTO user_identities (user_id, identity_provider, sub, created_ts) VALUES ($1, $2, $3, $4)",
+                id,
+                &identity_provider_str,
+                sub,
+                registration_ts
+            )
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await?;
+            id
         }
         IdentityProvider::Default => {
             sqlx::query!(
-                "INSERT INTO users (email, username, hashed_password, registration_ts, identity_provider) VALUES ($1, $2, $3, $4, $5)",
+                "INSERT INTO users (email, username, hashed_password, registration_ts, identity_provider) VALUES ($1, $2, $3, $4, $5) RETURNING id",
                 &registration_details.email,
                 &registration_details.username,
                 &hashed_password,
                 registration_ts,
                 &identity_provider_str
             )
-            .execute(&state.db_connection_pool)
+            .fetch_one(&state.db_connection_pool)
             .await?
+            .id
         }
     };
     Ok(User {
+        id,
         username: registration_details.username.clone(),
         email: registration_details.email.clone(),
         email_verified: false,
         hashed_password: None,
         auth_level: String::from(AuthLevel::User),
         login_attempts: 0,
+        last_failed_login_ts: None,
+        locked_until_ts: None,
         registration_ts,
         identity_provider: String::from(identity_provider),
     })
 }
 
+// Returns the existing user for an external identity or registers a new one
+pub async fn find_or_create_external_user(
+    state: Arc<AppState>,
+    identity_provider: IdentityProvider,
+    sub: String,
+    email: Option<String>,
+    email_verified: bool,
+) -> Result<User, AppError> {
+    let identity_provider_str = String::from(identity_provider.clone());
+    if let Ok(user) = get_user_by_sub(state.clone(), &identity_provider_str, &sub).await {
+        event!(
+            Level::INFO,
+            "User is registered with {}",
+            identity_provider_str
+        );
+        return Ok(user);
+    }
+
+    let email = email.ok_or(ErrorList::ProviderEmailMissing)?;
+    if get_user_by_email(state.clone(), &email).await.is_ok() {
+        return Err(ErrorList::EmailRegisteredWithAnotherProvider.into());
+    }
+
+    let (proposed_username, _domain) = email.split_once('@').ok_or(ErrorList::InvalidEmail)?;
+    let username = match get_user_by_username(state.clone(), proposed_username).await {
+        Ok(_v) => generate_unique_id(20),
+        Err(_e) => proposed_username.to_string(),
+    };
+    let registration_details = RegistrationDetails {
+        username,
+        email,
+        password: String::new(),
+        confirm_password: String::new(),
+        sub: Some(sub),
+    };
+    let user = create_registration(&registration_details, state.clone(), identity_provider).await?;
+
+    if email_verified {
+        sqlx::query!(
+            "UPDATE users SET email_verified = true WHERE id = $1",
+            user.id
+        )
+        .execute(&state.db_connection_pool)
+        .await?;
+    }
+
+    let user = get_user_by_id(state.clone(), user.id).await?;
+    if !email_verified {
+        send_verification_email(&user, state).await?;
+    }
+    Ok(user)
+}
+
 pub async fn send_verification_email(user: &User, state: Arc<AppState>) -> Result<(), AppError> {
     event!(
         Level::INFO,
@@ -174,20 +478,85 @@ pub async fn send_verification_email(user: &User, state: Arc<AppState>) -> Resul
         ),
         reply_to: None,
     };
-    add_code(
-        state.clone(),
-        &user.email,
-        &code,
-        CodeType::EmailVerification,
-    )
-    .await?;
+    add_code(state.clone(), user.id, &code, CodeType::EmailVerification).await?;
+    send_email(state.clone(), email).await?;
+    Ok(())
+}
+
+pub async fn send_magic_login_email(user: &User, state: Arc<AppState>) -> Result<(), AppError> {
+    event!(
+        Level::INFO,
+        "Attempting to send a login email to {}",
+        user.email
+    );
+
+    let to = format!("{} <{}>", user.username, user.email);
+
+    let code = generate_unique_id(8);
+    let link = format!(
+        "{}/login/email?email={}&code={code}",
+        state.config.server.frontend_url,
+        urlencoding::encode(&user.email)
+    );
+
+    let email = Email {
+        to: to.as_str(),
+        from: "registration@tld.com",
+        subject: String::from("Your login link"),
+        body: format!(
+            "<p>Use the following link to log in: <a href=\"{link}\">{link}</a></p> <p>Alternatively enter the code {code}. Your link and code are valid for 1 hour and can only be used once.</p> <p>If you did not request this, please ignore this email.</p>"
+        ),
+        reply_to: None,
+    };
+    add_code(state.clone(), user.id, &code, CodeType::MagicLogin).await?;
     send_email(state.clone(), email).await?;
     Ok(())
 }
 
+pub async fn send_password_reset_email(user: &User, state: Arc<AppState>) -> Result<(), AppError> {
+    let code = generate_unique_id(8);
+    add_code(state.clone(), user.id, &code, CodeType::PasswordReset).await?;
+
+    let email = Email {
+        to: &user.email,
+        from: "registration@tld.com",
+        subject: String::from("Password Reset"),
+        body: format!(
+            "<p>A password reset was requested for your account.</p> \
+            <p>Use this code to reset your password: {code}</p> \
+            <p>If you did not request this, please ignore this email.</p>"
+        ),
+        reply_to: None,
+    };
+    send_email(state, email).await?;
+    Ok(())
+}
+
+// Sent instead of an error when someone registers with an email which already has an account
+pub async fn send_existing_account_email(
+    user: &User,
+    state: Arc<AppState>,
+) -> Result<(), AppError> {
+    let to = format!("{} <{}>", user.username, user.email);
+
+    let email = Email {
+        to: to.as_str(),
+        from: "registration@tld.com",
+        subject: String::from("You already have an account"),
+        body: format!(
+            "<p>Someone tried to register with this email address, but you already have an account with the username {}.</p> \
+            <p>If this was you, log in or reset your password instead. Otherwise you can ignore this email.</p>",
+            user.username
+        ),
+        reply_to: None,
+    };
+    send_email(state, email).await?;
+    Ok(())
+}
+
 pub async fn add_code(
     state: Arc<AppState>,
-    email: &String,
+    user_id: Uuid,
     code: &String,
     code_type: CodeType,
 ) -> Result<(), anyhow::Error> {
@@ -196,9 +565,9 @@ pub async fn add_code(
     let expiry_ts = created_ts + SECONDS_IN_HOUR as i64;
 
     sqlx::query!(
-        "INSERT INTO codes (code_type, email, code, created_ts, expiry_ts) VALUES ($1, $2, $3, $4, $5)",
+        "INSERT INTO codes (code_type, user_id, code, created_ts, expiry_ts) VALUES ($1, $2, $3, $4, $5)",
         &code_type_str,
-        email,
+        user_id,
         code,
         created_ts,
         expiry_ts
@@ -212,8 +581,8 @@ pub async fn has_valid_email_code(state: Arc<AppState>, user: &User) -> bool {
     let now = Utc::now().timestamp();
 
     let code_exists = sqlx::query!(
-        "SELECT 1 as exists FROM codes WHERE code_type = 'EmailVerification' AND email = $1 AND expiry_ts > $2",
-        &user.email,
+        "SELECT 1 as exists FROM codes WHERE code_type = 'EmailVerification' AND user_id = $1 AND expiry_ts > $2",
+        user.id,
         now
     )
     .fetch_optional(&state.db_connection_pool)
@@ -1,3 +1,7 @@
+use crate::keys::KeyStore;
+use crate::session_store::SessionStore;
+use http::{HeaderName, HeaderValue, Method};
+use jsonwebtoken::Algorithm;
 use lettre::{
     SmtpTransport,
     transport::smtp::{
@@ -7,8 +11,11 @@ use lettre::{
 };
 use serde::Deserialize;
 use std::env;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::{fs::File, io::prelude::*};
 use std::{str::FromStr, time::Duration};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{Level, event};
 
 use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
@@ -18,6 +25,9 @@ pub struct AppState {
     pub db_connection_pool: Pool<Postgres>,
     pub email_connection_pool: SmtpTransport,
     pub config: Config,
+    pub http_client: reqwest::Client,
+    pub key_store: Option<Arc<KeyStore>>,
+    pub session_store: Arc<dyn SessionStore>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -25,6 +35,17 @@ pub struct Config {
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub email: SmtpConfig,
+    pub webauthn: WebAuthnConfig,
+    #[serde(default)]
+    pub oidc_providers: Vec<OidcProviderConfig>,
+    pub github: Option<GitHubConfig>,
+    pub jwt: Option<JwtConfig>,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
 }
 
 #[derive(Deserialize, Clone)]
@@ -40,7 +61,6 @@ impl DatabaseConfig {
         let password = self
             .password
             .as_ref()
-            .clone()
             .expect("Database password must be set in environment");
         format!(
             "postgresql://{}:{}@{}",
@@ -67,13 +87,369 @@ pub struct SmtpConfig {
     pub send_emails: bool,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct WebAuthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub origin: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OidcProviderConfig {
+    pub id: String,
+    pub issuer: String,
+    pub discovery_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    pub redirect_uri: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct GitHubConfig {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    #[serde(default = "default_github_oauth_url")]
+    pub oauth_url: String,
+    #[serde(default = "default_github_api_url")]
+    pub api_url: String,
+}
+
+fn default_github_oauth_url() -> String {
+    "https://github.com".to_string()
+}
+
+fn default_github_api_url() -> String {
+    "https://api.github.com".to_string()
+}
+
+// The secret for HMAC algorithms comes from the environment, ES256 keys are generated and stored in the database
+#[derive(Deserialize, Clone)]
+pub struct JwtConfig {
+    pub issuer: String,
+    pub audience: String,
+    #[serde(default = "default_jwt_algorithm")]
+    pub algorithm: Algorithm,
+    #[serde(default = "default_access_token_ttl")]
+    pub access_token_ttl: i64,
+    #[serde(default = "default_refresh_token_ttl_in_days")]
+    pub refresh_token_ttl_in_days: i64,
+    #[serde(default = "default_key_rotation_interval_in_days")]
+    pub key_rotation_interval_in_days: i64,
+    pub secret: Option<String>,
+}
+
+fn default_jwt_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+fn default_access_token_ttl() -> i64 {
+    900
+}
+
+fn default_refresh_token_ttl_in_days() -> i64 {
+    30
+}
+
+fn default_key_rotation_interval_in_days() -> i64 {
+    30
+}
+
 #[derive(Deserialize, Clone)]
 pub struct ServerConfig {
     pub port: u16,
     pub request_timeout: u64,
     pub max_unsuccessful_login_attempts: i32,
+    // How long an account stays locked once it reaches the maximum unsuccessful login attempts
+    #[serde(default = "default_login_lockout_in_minutes")]
+    pub login_lockout_in_minutes: i64,
+    // The wait after the first unsuccessful attempt, which doubles with each one after
+    #[serde(default = "default_login_backoff_base_in_seconds")]
+    pub login_backoff_base_in_seconds: i64,
+    #[serde(default = "default_login_backoff_max_in_seconds")]
+    pub login_backoff_max_in_seconds: i64,
+    // The absolute lifetime of a session, however active it is
     pub session_length_in_days: i64,
+    #[serde(default = "default_session_idle_timeout_in_minutes")]
+    pub session_idle_timeout_in_minutes: i64,
     pub google_client_id: String,
+    pub totp_issuer: String,
+    pub frontend_url: String,
+    pub passwordless_login_enabled: bool,
+    // Login, registration and password reset respond the same whether or not the account exists
+    #[serde(default)]
+    pub enumeration_safe: bool,
+    #[serde(default)]
+    pub session_store: SessionStoreBackend,
+}
+
+fn default_login_lockout_in_minutes() -> i64 {
+    15
+}
+
+fn default_login_backoff_base_in_seconds() -> i64 {
+    1
+}
+
+fn default_login_backoff_max_in_seconds() -> i64 {
+    60
+}
+
+fn default_session_idle_timeout_in_minutes() -> i64 {
+    20160
+}
+
+// Memory is faster but sessions are lost on restart and can't be shared between instances
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionStoreBackend {
+    #[default]
+    Postgres,
+    Memory,
+}
+
+// Origins may be exact, like https://tld.com, or match any subdomain, like https://*.tld.com.
+// When no origins are configured get_config fills them in for the environment
+#[derive(Deserialize, Clone)]
+pub struct CorsConfig {
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default = "default_cors_allow_credentials")]
+    pub allow_credentials: bool,
+    #[serde(default = "default_cors_max_age_in_seconds")]
+    pub max_age_in_seconds: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: default_cors_allowed_methods(),
+            allowed_headers: default_cors_allowed_headers(),
+            allow_credentials: default_cors_allow_credentials(),
+            max_age_in_seconds: default_cors_max_age_in_seconds(),
+        }
+    }
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    ["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_cors_allowed_headers() -> Vec<String> {
+    ["content-type", "authorization", "x-csrf-token"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_cors_allow_credentials() -> bool {
+    true
+}
+
+fn default_cors_max_age_in_seconds() -> u64 {
+    3600
+}
+
+// Any origin is allowed while testing, in production only the frontend is unless configured otherwise
+fn default_cors_allowed_origins(environment: &str, frontend_url: &str) -> Vec<String> {
+    match environment {
+        "PROD" => vec![frontend_url.trim_end_matches('/').to_string()],
+        _ => vec!["*".to_string()],
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern == origin {
+        return true;
+    }
+
+    // The wildcard must be the whole first label so https://*.tld.com doesn't match https://eviltld.com
+    let Some((scheme, domain)) = pattern.split_once("://*.") else {
+        return false;
+    };
+    origin
+        .strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .and_then(|host| host.strip_suffix(domain))
+        .and_then(|subdomain| subdomain.strip_suffix('.'))
+        .is_some_and(|subdomain| !subdomain.is_empty())
+}
+
+impl CorsConfig {
+    pub fn get_cors_layer(&self) -> CorsLayer {
+        let allowed_origins = self.allowed_origins.clone();
+        let methods: Vec<Method> = self
+            .allowed_methods
+            .iter()
+            .map(|method| Method::from_str(&method.to_uppercase()).expect("Invalid CORS method"))
+            .collect();
+        let headers: Vec<HeaderName> = self
+            .allowed_headers
+            .iter()
+            .map(|header| HeaderName::from_str(header).expect("Invalid CORS header"))
+            .collect();
+
+        // The matching origin is mirrored back, which unlike a literal * also works with credentials
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(
+                move |origin: &HeaderValue, _request_parts| {
+                    origin.to_str().is_ok_and(|origin| {
+                        allowed_origins
+                            .iter()
+                            .any(|pattern| origin_matches(pattern, origin))
+                    })
+                },
+            ))
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(Duration::from_secs(self.max_age_in_seconds))
+    }
+}
+
+// An empty value leaves that header out. {nonce} in a policy is replaced with a new nonce for each response
+#[derive(Deserialize, Clone)]
+pub struct SecurityHeadersConfig {
+    #[serde(default = "default_hsts_max_age_in_seconds")]
+    pub hsts_max_age_in_seconds: u64,
+    #[serde(default = "default_hsts_include_subdomains")]
+    pub hsts_include_subdomains: bool,
+    #[serde(default = "default_content_security_policy")]
+    pub content_security_policy: String,
+    #[serde(default = "default_api_content_security_policy")]
+    pub api_content_security_policy: String,
+    #[serde(default = "default_frame_ancestors")]
+    pub frame_ancestors: String,
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: String,
+    #[serde(default = "default_permissions_policy")]
+    pub permissions_policy: String,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            hsts_max_age_in_seconds: default_hsts_max_age_in_seconds(),
+            hsts_include_subdomains: default_hsts_include_subdomains(),
+            content_security_policy: default_content_security_policy(),
+            api_content_security_policy: default_api_content_security_policy(),
+            frame_ancestors: default_frame_ancestors(),
+            referrer_policy: default_referrer_policy(),
+            permissions_policy: default_permissions_policy(),
+        }
+    }
+}
+
+fn default_hsts_max_age_in_seconds() -> u64 {
+    31536000
+}
+
+fn default_hsts_include_subdomains() -> bool {
+    true
+}
+
+// Google's sign in button loads its script, styles and iframe from accounts.google.com
+fn default_content_security_policy() -> String {
+    "default-src 'self'; \
+     script-src 'self' 'nonce-{nonce}' https://accounts.google.com/gsi/client; \
+     style-src 'self' 'unsafe-inline' https://accounts.google.com/gsi/style; \
+     frame-src https://accounts.google.com/gsi/; \
+     connect-src 'self' https://accounts.google.com/gsi/; \
+     img-src 'self' data:; object-src 'none'; base-uri 'self'"
+        .to_string()
+}
+
+fn default_api_content_security_policy() -> String {
+    "default-src 'none'".to_string()
+}
+
+fn default_frame_ancestors() -> String {
+    "'none'".to_string()
+}
+
+fn default_referrer_policy() -> String {
+    "strict-origin-when-cross-origin".to_string()
+}
+
+fn default_permissions_policy() -> String {
+    "camera=(), microphone=(), geolocation=(), payment=()".to_string()
+}
+
+// X-Forwarded-For is only believed when the request comes from one of the trusted proxies
+#[derive(Deserialize, Clone)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    #[serde(default = "default_rate_limit_policies")]
+    pub policies: Vec<RateLimitPolicy>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: vec![],
+            policies: default_rate_limit_policies(),
+        }
+    }
+}
+
+// A token bucket holding requests tokens which refills completely over period_in_seconds.
+// Each key gets its own bucket and a request is rejected if any of them is empty
+#[derive(Deserialize, Clone, Debug)]
+pub struct RateLimitPolicy {
+    pub method: String,
+    pub path: String,
+    pub requests: u32,
+    pub period_in_seconds: u64,
+    pub keys: Vec<RateLimitKey>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    Ip,
+    Account,
+}
+
+fn default_rate_limit_policies() -> Vec<RateLimitPolicy> {
+    let policy = |method: &str, path: &str, requests, period_in_seconds, keys: &[RateLimitKey]| {
+        RateLimitPolicy {
+            method: method.to_string(),
+            path: path.to_string(),
+            requests,
+            period_in_seconds,
+            keys: keys.to_vec(),
+        }
+    };
+    use RateLimitKey::{Account, Ip};
+
+    // Password attempts aren't limited per account as anyone could then lock a user out by guessing,
+    // the lockout backoff already slows guesses against a single account
+    vec![
+        policy("POST", "/account/login", 20, 60, &[Ip]),
+        policy("POST", "/account/token", 20, 60, &[Ip]),
+        policy("POST", "/account/register", 10, 3600, &[Ip]),
+        policy("POST", "/account/resetPassword", 10, 3600, &[Ip]),
+        policy("POST", "/account/resetPassword", 3, 3600, &[Account]),
+        policy("POST", "/account/login/email", 10, 3600, &[Ip]),
+        policy("POST", "/account/login/email", 3, 3600, &[Account]),
+        policy("POST", "/account/login/email/complete", 20, 600, &[Ip]),
+        policy("POST", "/account/login/email/complete", 5, 600, &[Account]),
+        policy("POST", "/account/login/twoFactor", 10, 600, &[Ip]),
+        policy("POST", "/account/login/passkey", 20, 60, &[Ip]),
+        policy("POST", "/account/verifyEmail", 5, 600, &[Account]),
+        policy("GET", "/account/verificationEmail", 3, 3600, &[Account]),
+        policy("POST", "/account/changeEmail", 3, 3600, &[Account]),
+        policy("POST", "/account/changeEmail/confirm", 5, 600, &[Account]),
+        policy("GET", "/nonce", 60, 60, &[Ip]),
+    ]
 }
 
 #[derive(Deserialize, Clone)]
@@ -116,10 +492,55 @@ pub fn get_config() -> Config {
         .expect("Couldn't read config");
 
     let mut config: Config = toml::from_str(contents.as_str()).expect("Couldn't parse config");
+    validate_oidc_providers(&config.oidc_providers).expect("Invalid OIDC provider config");
     config.populate_passwords();
+
+    if config.cors.allowed_origins.is_empty() {
+        config.cors.allowed_origins =
+            default_cors_allowed_origins(&environment, &config.server.frontend_url);
+    }
     config
 }
 
+// Provider ids are stored as the identity provider of users and identities, so they can't be one of the
+// built in providers or they would be read back as it, and must fit the column
+// Provider ids share /account/login/ with these routes and the built in providers
+const RESERVED_PROVIDER_IDS: [&str; 7] = [
+    "default",
+    "google",
+    "github",
+    "email",
+    "passkey",
+    "passkeyOptions",
+    "twoFactor",
+];
+
+fn validate_oidc_providers(providers: &[OidcProviderConfig]) -> Result<(), String> {
+    for provider in providers {
+        if RESERVED_PROVIDER_IDS
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(&provider.id))
+        {
+            return Err(format!("{} is reserved for a built in route", provider.id));
+        }
+        if provider.id.is_empty()
+            || !provider
+                .id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        {
+            return Err(format!(
+                "{} must only contain a-z, 0-9, _ and -",
+                provider.id
+            ));
+        }
+        if provider.id.chars().count() > 30 {
+            return Err(format!("{} is longer than 30 characters", provider.id));
+        }
+    }
+    Ok(())
+}
+
 impl Config {
     pub fn get_email_pool(&self) -> SmtpTransport {
         SmtpTransport::starttls_relay(self.email.server_url.as_str())
@@ -164,5 +585,96 @@ impl Config {
 
         self.database.password = Some(pg_password);
         self.email.password = Some(smtp_password);
+
+        if let Some(github) = self.github.as_mut() {
+            github.client_secret = env::var("AXUMATIC_GITHUB_CLIENT_SECRET").ok();
+        }
+
+        if let Some(jwt) = self.jwt.as_mut() {
+            jwt.secret = env::var("AXUMATIC_JWT_SECRET").ok();
+        }
+
+        // Public clients using only PKCE don't need a secret
+        for provider in self.oidc_providers.iter_mut() {
+            let variable = format!(
+                "AXUMATIC_OIDC_{}_CLIENT_SECRET",
+                provider.id.to_uppercase().replace('-', "_")
+            );
+            provider.client_secret = env::var(variable).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_origin_matches() {
+        assert!(origin_matches("https://tld.com", "https://tld.com"));
+        assert!(!origin_matches("https://tld.com", "http://tld.com"));
+        assert!(!origin_matches("https://tld.com", "https://app.tld.com"));
+        assert!(origin_matches("*", "https://anything.com"));
+    }
+
+    #[test]
+    fn wildcard_origin_only_matches_subdomains() {
+        assert!(origin_matches("https://*.tld.com", "https://app.tld.com"));
+        assert!(origin_matches("https://*.tld.com", "https://a.b.tld.com"));
+        assert!(!origin_matches("https://*.tld.com", "https://tld.com"));
+        assert!(!origin_matches("https://*.tld.com", "https://eviltld.com"));
+        assert!(!origin_matches("https://*.tld.com", "http://app.tld.com"));
+    }
+
+    #[test]
+    fn built_in_provider_ids_are_reserved() {
+        let provider = |id: &str| OidcProviderConfig {
+            id: id.to_string(),
+            issuer: String::new(),
+            discovery_url: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            scopes: vec![],
+            redirect_uri: String::new(),
+        };
+        assert!(validate_oidc_providers(&[provider("okta")]).is_ok());
+        assert!(validate_oidc_providers(&[provider("okta"), provider("GitHub")]).is_err());
+        assert!(validate_oidc_providers(&[provider("default")]).is_err());
+        assert!(validate_oidc_providers(&[provider("google")]).is_err());
+        assert!(validate_oidc_providers(&[provider("email")]).is_err());
+        assert!(validate_oidc_providers(&[provider("passkey")]).is_err());
+        assert!(validate_oidc_providers(&[provider("passkeyoptions")]).is_err());
+        assert!(validate_oidc_providers(&[provider("twoFactor")]).is_err());
+    }
+
+    #[test]
+    fn provider_ids_are_url_safe() {
+        let provider = |id: &str| OidcProviderConfig {
+            id: id.to_string(),
+            issuer: String::new(),
+            discovery_url: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            scopes: vec![],
+            redirect_uri: String::new(),
+        };
+        assert!(validate_oidc_providers(&[provider("my-okta_2")]).is_ok());
+        assert!(validate_oidc_providers(&[provider("")]).is_err());
+        assert!(validate_oidc_providers(&[provider("Okta")]).is_err());
+        assert!(validate_oidc_providers(&[provider("okta/admin")]).is_err());
+        assert!(validate_oidc_providers(&[provider("okta?x=1")]).is_err());
+        assert!(validate_oidc_providers(&[provider(&"a".repeat(31))]).is_err());
+    }
+
+    #[test]
+    fn default_origins_depend_on_environment() {
+        assert_eq!(
+            default_cors_allowed_origins("PROD", "https://tld.com/"),
+            vec!["https://tld.com"]
+        );
+        assert_eq!(
+            default_cors_allowed_origins("TEST", "https://tld.com"),
+            vec!["*"]
+        );
     }
 }
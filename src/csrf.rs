@@ -0,0 +1,109 @@
+use crate::auth::get_cookie;
+use crate::utilities::generate_unique_id;
+use cookie::{Cookie, SameSite, time::Duration};
+use http::{HeaderMap, Method};
+
+pub const CSRF_COOKIE_NAME: &str = "csrf-token";
+pub const CSRF_HEADER_NAME: &str = "x-csrf-token";
+const CSRF_TOKEN_LENGTH: u8 = 32;
+
+// Methods which must not change state so never need a token
+pub fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// An existing token is reused so a second tab doesn't invalidate the first one's
+pub fn get_or_create_token(headers: &HeaderMap) -> String {
+    get_cookie(headers, CSRF_COOKIE_NAME)
+        .filter(|token| token.len() == CSRF_TOKEN_LENGTH as usize)
+        .unwrap_or_else(|| generate_unique_id(CSRF_TOKEN_LENGTH))
+}
+
+// The frontend reads the token from the response body so the cookie can stay http only
+pub fn csrf_cookie(token: String, max_age_in_seconds: i64) -> Cookie<'static> {
+    Cookie::build((CSRF_COOKIE_NAME, token))
+        .max_age(Duration::seconds(max_age_in_seconds))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .build()
+}
+
+// Double-submit check, another site can make the browser send the cookie but can't set the header to match it.
+// Browsers which report the request as cross-site are rejected outright
+pub fn is_request_trusted(headers: &HeaderMap) -> bool {
+    let cross_site = headers
+        .get("sec-fetch-site")
+        .is_some_and(|value| value.as_bytes() == b"cross-site");
+    if cross_site {
+        return false;
+    }
+
+    let Some(header_token) = headers
+        .get(CSRF_HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    match get_cookie(headers, CSRF_COOKIE_NAME) {
+        Some(cookie_token) if !cookie_token.is_empty() => {
+            constant_time_eq(cookie_token.as_bytes(), header_token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(cookie: &str, token: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_str(cookie).unwrap());
+        if let Some(token) = token {
+            headers.insert(CSRF_HEADER_NAME, HeaderValue::from_str(token).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn matching_token_is_trusted() {
+        let headers = headers("session-key=abc; csrf-token=TOKEN", Some("TOKEN"));
+        assert!(is_request_trusted(&headers));
+    }
+
+    #[test]
+    fn missing_or_mismatched_token_is_rejected() {
+        assert!(!is_request_trusted(&headers(
+            "session-key=abc; csrf-token=TOKEN",
+            None
+        )));
+        assert!(!is_request_trusted(&headers(
+            "session-key=abc; csrf-token=TOKEN",
+            Some("OTHER")
+        )));
+        assert!(!is_request_trusted(&headers("session-key=abc", Some(""))));
+    }
+
+    #[test]
+    fn cross_site_request_is_rejected() {
+        let mut headers = headers("session-key=abc; csrf-token=TOKEN", Some("TOKEN"));
+        headers.insert("sec-fetch-site", HeaderValue::from_static("cross-site"));
+        assert!(!is_request_trusted(&headers));
+    }
+
+    #[test]
+    fn existing_token_is_reused() {
+        let token = generate_unique_id(CSRF_TOKEN_LENGTH);
+        let headers = headers(&format!("csrf-token={token}"), None);
+        assert_eq!(get_or_create_token(&headers), token);
+        assert_ne!(get_or_create_token(&HeaderMap::new()), token);
+    }
+}
@@ -1,16 +1,54 @@
 use crate::{
     NONCE_STORE,
+    api_token::{
+        TokenScopes, create_api_token, delete_api_token, get_api_tokens, get_bearer_token,
+        get_expiry_ts, validate_scopes,
+    },
     auth::{
-        IdentityProvider, add_code, create_registration, has_valid_email_code,
-        send_verification_email,
+        AuthContext, IdentityProvider, SessionMetadata, create_registration, delete_all_sessions,
+        delete_other_sessions, delete_session, delete_session_by_key, find_or_create_external_user,
+        get_session_key, get_user_sessions, has_valid_email_code, send_existing_account_email,
+        send_magic_login_email, send_password_reset_email, send_verification_email,
+    },
+    github::{self, get_github_config},
+    identity::{get_user_identities, is_reauthenticated, link_identity, unlink_identity},
+    jwt::{AccessTokenClaims, create_token_pair, get_jwks, rotate_refresh_token},
+    keys::JwkSet,
+    oauth::{
+        AuthorizationParameters, AuthorizationRequestDetails, OAuthError, OAuthTokenResponse,
+        OpenIdConfiguration, UserInfo, approve_authorization, authenticate_client, create_client,
+        delete_client, deny_authorization, exchange_authorization_code, get_authorization_request,
+        get_client_credentials, get_clients, get_openid_configuration, get_user_info,
+        refresh_client_tokens, revoke_token, store_authorization_request,
+        take_authorization_request, validate_authorization_request, validate_client_scopes,
+        validate_redirect_uri,
+    },
+    oidc::{
+        CallbackParameters, OidcState, complete_authorization, get_authorization_url, get_provider,
+        take_state,
+    },
+    passkey::{
+        AuthenticationCredential, RegistrationCredential, add_passkey, authenticate_passkey,
+        delete_passkey, get_authentication_options, get_credential_ids, get_passkeys,
+        get_registration_options, verify_registration,
+    },
+    roles::get_roles,
+    two_factor::{
+        confirm_enrollment, create_pending_login, disable_two_factor, generate_recovery_codes,
+        get_pending_login, get_totp_secret, is_two_factor_enabled, remove_pending_login,
+        start_enrollment, verify_second_factor, verify_totp_code,
+    },
+    user::{
+        AdminUserDetails, Profile, User, UserPage, delete_user, get_user_by_id, get_user_by_sub,
+        get_user_by_username, reset_login_attempts, search_users, set_auth_level,
+        set_email_verified, update_google_user_email,
     },
-    user::{Profile, User, get_user_by_sub, get_user_by_username, update_google_user_email},
 };
 use axum::{
     async_trait,
-    extract::{FromRequestParts, Json, State},
+    extract::{Extension, Form, FromRequestParts, Json, Path, Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Redirect},
 };
 use chrono::Utc;
 use cookie::Cookie;
@@ -23,6 +61,9 @@ use thiserror::Error;
 use tracing::{Level, event};
 use validations::*;
 
+use crate::csrf::{csrf_cookie, get_or_create_token};
+use crate::email_change::{confirm_email_change, revert_email_change, start_email_change};
+use crate::lockout::{check_login_allowed, record_failed_login};
 use crate::{AppState, user::get_user_by_email};
 use crate::{auth::create_session, utilities::*};
 
@@ -38,6 +79,118 @@ pub struct PasswordResetCompleteRequest {
     pub confirm_password: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableTwoFactor {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorLoginDetails {
+    pub token: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyRegistrationDetails {
+    pub name: String,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLoginRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLoginCompleteRequest {
+    pub email: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeEmail {
+    pub password: String,
+    pub new_email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmailChangeCode {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserSearchParameters {
+    pub search: Option<String>,
+    pub page: Option<i64>,
+    pub page_size: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChangeAuthLevel {
+    pub auth_level: String,
+}
+
+// Tokens never expire when expires_in_days is omitted
+#[derive(Serialize, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+
+// Public clients have no secret and must be trusted not to be impersonated, so trusted is opt in
+#[derive(Serialize, Deserialize)]
+pub struct CreateOAuthClientRequest {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    #[serde(default)]
+    pub trusted: bool,
+    #[serde(default = "default_confidential")]
+    pub confidential: bool,
+}
+
+fn default_confidential() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OAuthConsent {
+    pub approved: bool,
+}
+
+// Fields for both the authorization_code and refresh_token grants
+#[derive(Serialize, Deserialize)]
+pub struct OAuthTokenParameters {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OAuthRevocationParameters {
+    pub token: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+// Password is required when the user has one, jwt is only used when linking Google
+#[derive(Serialize, Deserialize)]
+pub struct LinkIdentityRequest {
+    pub password: Option<String>,
+    pub jwt: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GoogleToken {
     jwt: String,
@@ -48,6 +201,9 @@ pub struct GoogleToken {
 pub enum CodeType {
     EmailVerification,
     PasswordReset,
+    MagicLogin,
+    EmailChange,
+    EmailChangeRevert,
 }
 
 impl From<CodeType> for String {
@@ -55,6 +211,9 @@ impl From<CodeType> for String {
         match val {
             CodeType::EmailVerification => "EmailVerification".to_string(),
             CodeType::PasswordReset => "PasswordReset".to_string(),
+            CodeType::MagicLogin => "MagicLogin".to_string(),
+            CodeType::EmailChange => "EmailChange".to_string(),
+            CodeType::EmailChangeRevert => "EmailChangeRevert".to_string(),
         }
     }
 }
@@ -81,12 +240,20 @@ pub enum ErrorList {
     IncorrectPassword,
     #[error("Incorrect username")]
     IncorrectUsername,
+    #[error("Incorrect email or password")]
+    InvalidCredentials,
     #[error("Invalid or expired verification code")]
     InvalidVerificationCode,
-    #[error("Too many login attempts, please reset your password")]
+    #[error(
+        "Your account is temporarily locked after too many login attempts, please try again later or reset your password"
+    )]
     TooManyLoginAttempts,
+    #[error("Too many unsuccessful login attempts, please wait a moment and try again")]
+    LoginThrottled,
     #[error("Unauthorised")]
     Unauthorised,
+    #[error("Too many requests, please try again later")]
+    TooManyRequests,
     #[error("Unexpected error verifying JWT")]
     UnexpectedJwtError,
     #[error("Invalid JWT")]
@@ -103,6 +270,76 @@ pub enum ErrorList {
     PreviousCodeNotExpired,
     #[error("Password not provided")]
     PasswordNotProvided,
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("Two factor authentication is already enabled")]
+    TwoFactorAlreadyEnabled,
+    #[error("Two factor authentication is not enabled")]
+    TwoFactorNotEnabled,
+    #[error("You must start two factor enrollment before confirming it")]
+    TwoFactorNotStarted,
+    #[error("Invalid two factor code")]
+    InvalidTwoFactorCode,
+    #[error("Your login attempt has expired, please log in again")]
+    InvalidPendingLogin,
+    #[error("Invalid passkey")]
+    InvalidPasskey,
+    #[error("Passkey uses an unsupported algorithm")]
+    UnsupportedPasskeyAlgorithm,
+    #[error("Passkey not found")]
+    PasskeyNotFound,
+    #[error("Passkey name must be between 1 and 100 characters")]
+    InvalidPasskeyName,
+    #[error("Passwordless login is not enabled")]
+    PasswordlessLoginDisabled,
+    #[error("Unknown identity provider")]
+    UnknownProvider,
+    #[error("There was a problem communicating with the identity provider")]
+    OidcProviderError,
+    #[error("Your login attempt has expired or is invalid, please log in again")]
+    InvalidOidcState,
+    #[error("The identity provider did not share an email address")]
+    ProviderEmailMissing,
+    #[error("Please confirm your password or sign in again to continue")]
+    ReauthenticationRequired,
+    #[error("That identity is already linked to another account")]
+    IdentityAlreadyLinked,
+    #[error("Linked identity not found")]
+    IdentityNotFound,
+    #[error("You can't remove your only way of signing in")]
+    LastCredential,
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Role not found")]
+    RoleNotFound,
+    #[error("You can't change the role of or delete your own account")]
+    CannotModifySelf,
+    #[error("Token name must be between 1 and 100 characters")]
+    InvalidTokenName,
+    #[error("Token scopes must be one or more of read, write and admin")]
+    InvalidTokenScopes,
+    #[error("Token expiry must be between 1 and 365 days")]
+    InvalidTokenExpiry,
+    #[error("API token not found")]
+    TokenNotFound,
+    #[error("API tokens can't be used to manage API tokens")]
+    SessionRequired,
+    #[error("JWT access tokens are not enabled")]
+    JwtNotEnabled,
+    #[error("Invalid or expired refresh token")]
+    InvalidRefreshToken,
+    #[error("Unknown application or redirect URI")]
+    InvalidOAuthClient,
+    #[error("Redirect URIs must be absolute https URLs, or http for localhost")]
+    InvalidRedirectUri,
+    #[error("Application name must be between 1 and 100 characters")]
+    InvalidClientName,
+    #[error("Scopes must be one or more of openid, profile and email")]
+    InvalidOAuthScopes,
+    #[error("Application not found")]
+    OAuthClientNotFound,
+    #[error("Your authorization request has expired, please try again")]
+    AuthorizationRequestNotFound,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -123,6 +360,42 @@ pub enum ResponseType {
     UserProfile,
     Nonce,
     ResendVerificationEmailSuccess,
+    SessionList,
+    SessionRevoked,
+    OtherSessionsRevoked,
+    SecondFactorRequired,
+    TwoFactorEnrollment,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    PasskeyRegistrationOptions,
+    PasskeyRegistered,
+    PasskeyList,
+    PasskeyRemoved,
+    PasskeyLoginOptions,
+    MagicLoginEmailSent,
+    IdentityList,
+    IdentityLinkRedirect,
+    IdentityLinked,
+    IdentityUnlinked,
+    RoleList,
+    UserList,
+    UserDetails,
+    UserUpdated,
+    UserSessionsRevoked,
+    UserDeleted,
+    ApiTokenList,
+    ApiTokenCreated,
+    ApiTokenRevoked,
+    AccessToken,
+    OAuthClientList,
+    OAuthClientCreated,
+    OAuthClientDeleted,
+    OAuthAuthorizationRequest,
+    OAuthRedirect,
+    CsrfToken,
+    EmailChangeInitiated,
+    EmailChanged,
+    EmailChangeReverted,
 }
 
 impl From<ResponseType> for String {
@@ -142,6 +415,42 @@ impl From<ResponseType> for String {
             ResponseType::ResendVerificationEmailSuccess => {
                 "ResendVerificationEmailSuccess".to_string()
             }
+            ResponseType::SessionList => "SessionList".to_string(),
+            ResponseType::SessionRevoked => "SessionRevoked".to_string(),
+            ResponseType::OtherSessionsRevoked => "OtherSessionsRevoked".to_string(),
+            ResponseType::SecondFactorRequired => "SecondFactorRequired".to_string(),
+            ResponseType::TwoFactorEnrollment => "TwoFactorEnrollment".to_string(),
+            ResponseType::TwoFactorEnabled => "TwoFactorEnabled".to_string(),
+            ResponseType::TwoFactorDisabled => "TwoFactorDisabled".to_string(),
+            ResponseType::PasskeyRegistrationOptions => "PasskeyRegistrationOptions".to_string(),
+            ResponseType::PasskeyRegistered => "PasskeyRegistered".to_string(),
+            ResponseType::PasskeyList => "PasskeyList".to_string(),
+            ResponseType::PasskeyRemoved => "PasskeyRemoved".to_string(),
+            ResponseType::PasskeyLoginOptions => "PasskeyLoginOptions".to_string(),
+            ResponseType::MagicLoginEmailSent => "MagicLoginEmailSent".to_string(),
+            ResponseType::IdentityList => "IdentityList".to_string(),
+            ResponseType::IdentityLinkRedirect => "IdentityLinkRedirect".to_string(),
+            ResponseType::IdentityLinked => "IdentityLinked".to_string(),
+            ResponseType::IdentityUnlinked => "IdentityUnlinked".to_string(),
+            ResponseType::RoleList => "RoleList".to_string(),
+            ResponseType::UserList => "UserList".to_string(),
+            ResponseType::UserDetails => "UserDetails".to_string(),
+            ResponseType::UserUpdated => "UserUpdated".to_string(),
+            ResponseType::UserSessionsRevoked => "UserSessionsRevoked".to_string(),
+            ResponseType::UserDeleted => "UserDeleted".to_string(),
+            ResponseType::ApiTokenList => "ApiTokenList".to_string(),
+            ResponseType::ApiTokenCreated => "ApiTokenCreated".to_string(),
+            ResponseType::ApiTokenRevoked => "ApiTokenRevoked".to_string(),
+            ResponseType::AccessToken => "AccessToken".to_string(),
+            ResponseType::OAuthClientList => "OAuthClientList".to_string(),
+            ResponseType::OAuthClientCreated => "OAuthClientCreated".to_string(),
+            ResponseType::OAuthClientDeleted => "OAuthClientDeleted".to_string(),
+            ResponseType::OAuthAuthorizationRequest => "OAuthAuthorizationRequest".to_string(),
+            ResponseType::OAuthRedirect => "OAuthRedirect".to_string(),
+            ResponseType::CsrfToken => "CsrfToken".to_string(),
+            ResponseType::EmailChangeInitiated => "EmailChangeInitiated".to_string(),
+            ResponseType::EmailChanged => "EmailChanged".to_string(),
+            ResponseType::EmailChangeReverted => "EmailChangeReverted".to_string(),
         }
     }
 }
@@ -178,7 +487,8 @@ pub struct RegistrationDetails {
     pub sub: Option<String>,
 }
 
-// Used to extract the user from object from the email header
+// ValidateSession has already loaded the user unless an access token was used, in which case it is
+// loaded here once and kept for any other extractors. Open routes can take an Option<User> instead
 #[async_trait]
 impl FromRequestParts<Arc<AppState>> for User {
     type Rejection = (StatusCode, &'static str);
@@ -187,55 +497,21 @@ impl FromRequestParts<Arc<AppState>> for User {
         parts: &mut http::request::Parts,
         state: &Arc<AppState>,
     ) -> Result<Self, Self::Rejection> {
-        let email = parts
-            .headers
-            .get("email")
-            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Expected header missing"))?;
-        let email = email.to_str().map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Unexpected error with header value",
-            )
-        })?;
-        let row = sqlx::query!(
-            r#"SELECT
-                username as "username!",
-                email as "email!",
-                email_verified as "email_verified!",
-                hashed_password,
-                auth_level as "auth_level!",
-                login_attempts as "login_attempts!",
-                registration_ts as "registration_ts!",
-                identity_provider as "identity_provider!"
-            FROM users WHERE email = $1"#,
-            email
-        )
-        .fetch_optional(&state.db_connection_pool)
-        .await;
-
-        match row {
-            Ok(Some(r)) => {
-                return Ok(User {
-                    username: r.username,
-                    email: r.email,
-                    email_verified: r.email_verified,
-                    hashed_password: r.hashed_password,
-                    auth_level: r.auth_level,
-                    login_attempts: r.login_attempts,
-                    registration_ts: r.registration_ts,
-                    identity_provider: r.identity_provider,
-                });
-            }
-            Ok(None) => {
-                return Err((StatusCode::INTERNAL_SERVER_ERROR, "User not found"));
-            }
-            Err(_e) => {
-                return Err((
+        if let Some(user) = parts.extensions.get::<User>() {
+            return Ok(user.clone());
+        }
+        let context = AuthContext::from_request_parts(parts, state).await?;
+
+        let user = get_user_by_id(state.clone(), context.user_id)
+            .await
+            .map_err(|_e| {
+                (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "Unexpected error fetching user",
-                ));
-            }
-        }
+                )
+            })?;
+        parts.extensions.insert(user.clone());
+        Ok(user)
     }
 }
 
@@ -245,6 +521,19 @@ pub struct LoginDetails {
     pub password: String,
 }
 
+// Code is the TOTP or recovery code, which is required when the user has two factor enabled
+#[derive(Serialize, Deserialize)]
+pub struct TokenRequest {
+    pub email: String,
+    pub password: String,
+    pub code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ChangePassword {
     pub old_password: String,
@@ -266,15 +555,20 @@ pub async fn register(
     validate_email(&registration_details.email)?;
     validate_username(&registration_details.username)?;
     validate_password(&registration_details.password)?;
+    if registration_details.password != registration_details.confirm_password {
+        return Err(ErrorList::NonMatchingPasswords.into());
+    }
+
+    if state.config.server.enumeration_safe {
+        return register_enumeration_safe(state, registration_details).await;
+    }
+
     is_unique(
         &registration_details.username,
         &registration_details.email,
         state.clone(),
     )
     .await?;
-    if registration_details.password != registration_details.confirm_password {
-        return Err(ErrorList::NonMatchingPasswords.into());
-    }
 
     let user = create_registration(
         &registration_details,
@@ -291,14 +585,58 @@ pub async fn register(
     }))
 }
 
-pub async fn google_login(
-    State(state): State<Arc<AppState>>,
-    Json(token): Json<GoogleToken>,
-) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
-    let mut headers = HeaderMap::new();
+// Runs work after the response has been sent, so how long a request takes doesn't reveal whether the account exists
+fn spawn_account_task<F>(task: F)
+where
+    F: Future<Output = Result<(), AppError>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = task.await {
+            event!(Level::ERROR, "Background account task failed: {}", e.0);
+        }
+    });
+}
+
+// Usernames are public so still have to be unique, an existing email gets an email instead of an error
+async fn register_enumeration_safe(
+    state: Arc<AppState>,
+    registration_details: RegistrationDetails,
+) -> Result<Json<ApiResponse>, AppError> {
+    if username_exists(&registration_details.username, state.clone()).await {
+        return Err(ErrorList::UsernameAlreadyRegistered.into());
+    }
+
+    if email_exists(&registration_details.email, state.clone()).await {
+        event!(Level::INFO, "Attempted registration with duplicate email");
+        // Registering hashes the password, so this takes as long as a new registration
+        let _ = hash_password(&registration_details.password);
+        let user = get_user_by_email(state.clone(), &registration_details.email).await?;
+        spawn_account_task(async move { send_existing_account_email(&user, state).await });
+    } else {
+        let user = create_registration(
+            &registration_details,
+            state.clone(),
+            IdentityProvider::Default,
+        )
+        .await?;
+        spawn_account_task(async move { send_verification_email(&user, state).await });
+    }
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::RegistrationSuccess,
+        message: "Registration successful".to_string(),
+    }))
+}
 
-    let jwt = token.jwt;
+// The parts of a verified Google ID token we make use of
+pub struct GoogleIdentity {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+}
 
+// Verifies a Google ID token and consumes the nonce it was issued for
+async fn verify_google_jwt(state: Arc<AppState>, jwt: &str) -> Result<GoogleIdentity, AppError> {
     event!(Level::INFO, "Verifying JWT");
 
     let mut client = JwtVerifierClient::new()
@@ -307,7 +645,7 @@ pub async fn google_login(
 
     let claims = JwtVerifierClient::verify(
         &mut client,
-        &jwt,
+        jwt,
         true,
         &state.config.server.google_client_id,
     )
@@ -323,19 +661,46 @@ pub async fn google_login(
     }
 
     event!(Level::INFO, "JWT verified successfully");
+    Ok(GoogleIdentity {
+        sub: claims.sub,
+        email: claims.email,
+        email_verified: claims.email_verified,
+    })
+}
+
+pub async fn google_login(
+    State(state): State<Arc<AppState>>,
+    session_metadata: SessionMetadata,
+    Json(token): Json<GoogleToken>,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let claims = verify_google_jwt(state.clone(), &token.jwt).await?;
+    complete_google_login(state, claims, &session_metadata).await
+}
+
+// Finds or registers the user for a verified Google identity and signs them in, unless they have two
+// factor enabled in which case the pending login token is returned instead of a session
+#[cfg_attr(not(feature = "test-utils"), doc(hidden))]
+pub async fn complete_google_login(
+    state: Arc<AppState>,
+    claims: GoogleIdentity,
+    session_metadata: &SessionMetadata,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let mut headers = HeaderMap::new();
+    let google = String::from(IdentityProvider::Google);
 
     if let (Some(email), Some(verified)) = (claims.email, claims.email_verified) {
-        let user = get_user_by_sub(state.clone(), &claims.sub).await;
+        let user = get_user_by_sub(state.clone(), &google, &claims.sub).await;
 
-        if let Ok(mut user) = user {
+        let user = if let Ok(mut user) = user {
             event!(
                 Level::INFO,
                 "User is registered, checking email hasn't changed"
             );
 
-            if email != user.email {
+            // Only accounts registered with Google follow changes to the Google email
+            if email != user.email && user.identity_provider == google {
                 // Check if new email is already registered
-                if get_user_by_email(state.clone(), &user.email).await.is_ok() {
+                if get_user_by_email(state.clone(), &email).await.is_ok() {
                     return Err(AppError(
                         ErrorList::EmailRegisteredWithAnotherProvider.into(),
                     ));
@@ -346,68 +711,35 @@ pub async fn google_login(
                     user.email_verified = verified;
                 }
             }
-            // Check registration type
-            if user.identity_provider == "google" {
-                event!(Level::INFO, "Registered with Google, creating session");
-                let session_cookie = create_session(&user, state).await?;
-                headers.insert(SET_COOKIE, session_cookie.to_string().parse()?);
-            } else {
-                event!(
-                    Level::INFO,
-                    "Registered with another provider, returning an error"
-                );
-                return Err(AppError(
-                    ErrorList::EmailRegisteredWithAnotherProvider.into(),
-                ));
-            }
+            user
         } else {
-            let (proposed_username, _prefix) =
-                email.split_once('@').expect("Email does not contain an @");
-            let username = match get_user_by_username(state.clone(), proposed_username).await {
-                Ok(_v) => generate_unique_id(20),
-                Err(_e) => proposed_username.to_string(),
-            };
-            let registration_details = RegistrationDetails {
-                username,
-                email,
-                password: String::new(),
-                confirm_password: String::new(),
-                sub: Some(claims.sub),
-            };
-            if verified {
-                //Create new reg with email verified
-                create_registration(
-                    &registration_details,
-                    state.clone(),
-                    IdentityProvider::Google,
-                )
-                .await?;
-
-                sqlx::query!(
-                    "UPDATE users SET email_verified = true WHERE email = $1",
-                    &registration_details.email
-                )
-                .execute(&state.db_connection_pool)
-                .await?;
-
-                let user = get_user_by_email(state.clone(), &registration_details.email).await?;
-                let session_cookie = create_session(&user, state.clone()).await?;
-                headers.insert(SET_COOKIE, session_cookie.to_string().parse()?);
-            } else {
-                // Create new unverified reg and send email
-                create_registration(
-                    &registration_details,
-                    state.clone(),
-                    IdentityProvider::Google,
-                )
-                .await?;
+            find_or_create_external_user(
+                state.clone(),
+                IdentityProvider::Google,
+                claims.sub,
+                Some(email),
+                verified,
+            )
+            .await?
+        };
 
-                let user = get_user_by_email(state.clone(), &registration_details.email).await?;
-                send_verification_email(&user, state.clone()).await?;
-                let session_cookie = create_session(&user, state.clone()).await?;
-                headers.insert(SET_COOKIE, session_cookie.to_string().parse()?);
-            }
+        if is_two_factor_enabled(state.clone(), user.id).await? {
+            event!(
+                Level::INFO,
+                "Google identity verified, second factor required"
+            );
+            return Ok((
+                headers,
+                Json(ApiResponse {
+                    response_type: ResponseType::SecondFactorRequired,
+                    message: create_pending_login(user.id),
+                }),
+            ));
         }
+
+        event!(Level::INFO, "Google identity verified, creating session");
+        let session_cookie = create_session(&user, state, session_metadata).await?;
+        headers.insert(SET_COOKIE, session_cookie.to_string().parse()?);
     }
 
     Ok((
@@ -419,111 +751,474 @@ pub async fn google_login(
     ))
 }
 
-pub async fn login(
+pub async fn oidc_login(
     State(state): State<Arc<AppState>>,
-    Json(login_details): Json<LoginDetails>,
-) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
-    let row = sqlx::query!(
-        r#"SELECT
-            username as "username!",
-            email as "email!",
-            email_verified as "email_verified!",
-            hashed_password,
-            auth_level as "auth_level!",
-            login_attempts as "login_attempts!",
-            registration_ts as "registration_ts!",
-            identity_provider as "identity_provider!"
-        FROM users WHERE email = $1"#,
-        &login_details.email
-    )
-    .fetch_optional(&state.db_connection_pool)
-    .await?;
-    let user = match row {
-        Some(r) => User {
-            username: r.username,
-            email: r.email,
-            email_verified: r.email_verified,
-            hashed_password: r.hashed_password,
-            auth_level: r.auth_level,
-            login_attempts: r.login_attempts,
-            registration_ts: r.registration_ts,
-            identity_provider: r.identity_provider,
-        },
-        None => return Err(ErrorList::IncorrectUsername.into()),
+    Path(provider_id): Path<String>,
+) -> Result<(HeaderMap, Redirect), AppError> {
+    let provider = get_provider(&state, &provider_id)?;
+    let (authorization_url, state_cookie) = get_authorization_url(state, &provider, None).await?;
+
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::SET_COOKIE, state_cookie.to_string().parse()?);
+    Ok((header_map, Redirect::to(authorization_url.as_str())))
+}
+
+pub async fn oidc_callback(
+    State(state): State<Arc<AppState>>,
+    Path(provider_id): Path<String>,
+    request_headers: HeaderMap,
+    auth_context: Option<AuthContext>,
+    session_metadata: SessionMetadata,
+    Query(parameters): Query<CallbackParameters>,
+) -> Result<(HeaderMap, Redirect), AppError> {
+    let provider = get_provider(&state, &provider_id)?;
+
+    if let Some(error) = parameters.error {
+        event!(Level::WARN, "Identity provider returned error {}", error);
+        return Err(ErrorList::OidcProviderError.into());
+    }
+    let (Some(code), Some(oidc_state)) = (parameters.code, parameters.state) else {
+        return Err(ErrorList::InvalidOidcState.into());
     };
+    let oidc_state = take_state(&oidc_state, &request_headers)
+        .filter(|oidc_state| oidc_state.provider_id == provider.id)
+        .ok_or(ErrorList::InvalidOidcState)?;
+    check_link_session(&oidc_state, auth_context)?;
+
+    let claims = complete_authorization(state.clone(), &provider, &oidc_state, &code).await?;
+    event!(Level::INFO, "ID token verified successfully");
+
+    complete_external_identity(
+        state,
+        oidc_state,
+        IdentityProvider::Oidc(provider.id),
+        claims.sub,
+        claims.email,
+        claims.email_verified.unwrap_or(false),
+        &session_metadata,
+    )
+    .await
+}
 
-    if user.identity_provider != "default" {
-        return Err(ErrorList::EmailRegisteredWithAnotherProvider.into());
+pub async fn github_login(
+    State(state): State<Arc<AppState>>,
+) -> Result<(HeaderMap, Redirect), AppError> {
+    let config = get_github_config(&state)?;
+    let (authorization_url, state_cookie) = github::get_authorization_url(&config, None)?;
+
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::SET_COOKIE, state_cookie.to_string().parse()?);
+    Ok((header_map, Redirect::to(authorization_url.as_str())))
+}
+
+pub async fn github_callback(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    auth_context: Option<AuthContext>,
+    session_metadata: SessionMetadata,
+    Query(parameters): Query<CallbackParameters>,
+) -> Result<(HeaderMap, Redirect), AppError> {
+    let config = get_github_config(&state)?;
+
+    if let Some(error) = parameters.error {
+        event!(Level::WARN, "GitHub returned error {}", error);
+        return Err(ErrorList::OidcProviderError.into());
     }
+    let (Some(code), Some(oidc_state)) = (parameters.code, parameters.state) else {
+        return Err(ErrorList::InvalidOidcState.into());
+    };
+    let oidc_state = take_state(&oidc_state, &request_headers)
+        .filter(|oidc_state| oidc_state.provider_id == String::from(IdentityProvider::GitHub))
+        .ok_or(ErrorList::InvalidOidcState)?;
+    check_link_session(&oidc_state, auth_context)?;
+
+    let identity =
+        github::complete_authorization(state.clone(), &config, &oidc_state, &code).await?;
+    event!(Level::INFO, "GitHub user fetched successfully");
+
+    // GitHub only shares the primary email once it has been verified
+    complete_external_identity(
+        state,
+        oidc_state,
+        IdentityProvider::GitHub,
+        identity.sub,
+        identity.email,
+        true,
+        &session_metadata,
+    )
+    .await
+}
 
-    if user.login_attempts >= state.config.server.max_unsuccessful_login_attempts {
-        event!(Level::WARN, "Account locked due to too many login attempts");
-        return Err(ErrorList::TooManyLoginAttempts.into());
+// A link callback must arrive with the session of the user who started the link
+fn check_link_session(
+    oidc_state: &OidcState,
+    auth_context: Option<AuthContext>,
+) -> Result<(), ErrorList> {
+    let Some(link_user_id) = oidc_state.link_user_id else {
+        return Ok(());
+    };
+    let session_user_id = auth_context
+        .filter(|auth_context| auth_context.session_id.is_some())
+        .map(|auth_context| auth_context.user_id);
+    if session_user_id != Some(link_user_id) {
+        return Err(ErrorList::Unauthorised);
     }
-    let mut header_map = HeaderMap::new();
-    let hashed_password = user
-        .hashed_password
-        .as_ref()
-        .ok_or(ErrorList::PasswordNotProvided)?;
-    if verify_password(hashed_password, &login_details.password) {
-        let session_cookie = create_session(&user, state.clone()).await?;
+    Ok(())
+}
 
-        sqlx::query!(
-            "UPDATE users SET login_attempts = 0 WHERE email = $1",
-            &user.email
+// Either links the identity to the account which started the flow or signs the user in with it,
+// then sends the browser back to the frontend
+async fn complete_external_identity(
+    state: Arc<AppState>,
+    oidc_state: OidcState,
+    identity_provider: IdentityProvider,
+    sub: String,
+    email: Option<String>,
+    email_verified: bool,
+    session_metadata: &SessionMetadata,
+) -> Result<(HeaderMap, Redirect), AppError> {
+    let mut header_map = HeaderMap::new();
+    let frontend_url = &state.config.server.frontend_url;
+
+    if let Some(link_user_id) = oidc_state.link_user_id {
+        link_identity(
+            state.clone(),
+            link_user_id,
+            &String::from(identity_provider),
+            &sub,
         )
-        .execute(&state.db_connection_pool)
         .await?;
+        return Ok((
+            header_map,
+            Redirect::to(&format!("{}/profile", frontend_url)),
+        ));
+    }
+
+    let user =
+        find_or_create_external_user(state.clone(), identity_provider, sub, email, email_verified)
+            .await?;
 
-        header_map.insert(header::SET_COOKIE, session_cookie.to_string().parse()?);
-        Ok((
+    if is_two_factor_enabled(state.clone(), user.id).await? {
+        event!(Level::INFO, "Identity verified, second factor required");
+        let token = create_pending_login(user.id);
+        return Ok((
+            header_map,
+            Redirect::to(&format!("{}/login?token={}", frontend_url, token)),
+        ));
+    }
+
+    let session_cookie = create_session(&user, state.clone(), session_metadata).await?;
+    header_map.insert(header::SET_COOKIE, session_cookie.to_string().parse()?);
+
+    Ok((
+        header_map,
+        Redirect::to(&format!("{}/profile", frontend_url)),
+    ))
+}
+
+// Checks the user's password, counting failed attempts towards the account lockout
+async fn verify_login(state: Arc<AppState>, email: &str, password: &str) -> Result<User, AppError> {
+    let user = get_user_by_email(state.clone(), email).await.ok();
+
+    // In enumeration safe mode every failure looks the same and takes as long as checking a password
+    let enumeration_safe = state.config.server.enumeration_safe;
+    let reject = |error: ErrorList| -> AppError {
+        if enumeration_safe {
+            verify_dummy_password(password);
+            ErrorList::InvalidCredentials.into()
+        } else {
+            error.into()
+        }
+    };
+
+    let Some(user) = user else {
+        return Err(reject(ErrorList::IncorrectUsername));
+    };
+    let Some(hashed_password) = user.hashed_password.as_ref() else {
+        return Err(reject(ErrorList::EmailRegisteredWithAnotherProvider));
+    };
+
+    let now = Utc::now().timestamp();
+    if let Err(e) = check_login_allowed(&state.config.server, &user, now) {
+        return Err(reject(e));
+    }
+    if !verify_password(hashed_password, password) {
+        // Counting the attempt costs a database write which an unknown email doesn't make. This small
+        // difference is accepted as the password check dominates the time taken
+        record_failed_login(state, &user, now).await?;
+        return Err(if enumeration_safe {
+            ErrorList::InvalidCredentials.into()
+        } else {
+            ErrorList::IncorrectPassword.into()
+        });
+    }
+
+    Ok(user)
+}
+
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    session_metadata: SessionMetadata,
+    Json(login_details): Json<LoginDetails>,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let user = verify_login(state.clone(), &login_details.email, &login_details.password).await?;
+    let mut header_map = HeaderMap::new();
+
+    // Login attempts are only reset once the second factor has been provided
+    if is_two_factor_enabled(state.clone(), user.id).await? {
+        event!(Level::INFO, "Password verified, second factor required");
+        return Ok((
             header_map,
             Json(ApiResponse {
-                response_type: ResponseType::LoginSuccess,
-                message: "Login successful".to_string(),
+                response_type: ResponseType::SecondFactorRequired,
+                message: create_pending_login(user.id),
             }),
-        ))
-    } else {
-        sqlx::query!(
-            "UPDATE users SET login_attempts = $1 WHERE email = $2",
-            user.login_attempts + 1,
-            &login_details.email
-        )
-        .execute(&state.db_connection_pool)
-        .await?;
-        Err(ErrorList::IncorrectPassword.into())
+        ));
     }
+
+    let session_cookie = create_session(&user, state.clone(), &session_metadata).await?;
+
+    reset_login_attempts(state, user.id).await?;
+
+    header_map.insert(header::SET_COOKIE, session_cookie.to_string().parse()?);
+    Ok((
+        header_map,
+        Json(ApiResponse {
+            response_type: ResponseType::LoginSuccess,
+            message: "Login successful".to_string(),
+        }),
+    ))
 }
 
-pub async fn verify_email(
+// The token based equivalent of login for clients which can't use cookies
+pub async fn issue_token(
     State(state): State<Arc<AppState>>,
-    Json(verification_details): Json<VerificationDetails>,
+    Json(token_request): Json<TokenRequest>,
 ) -> Result<Json<ApiResponse>, AppError> {
-    let now = Utc::now().timestamp();
-
-    let code_exists = sqlx::query!(
-        "SELECT 1 as exists FROM codes WHERE code_type = 'EmailVerification' AND email = $1 AND code = $2 AND expiry_ts > $3",
-        &verification_details.email,
-        &verification_details.code,
-        now
-    )
-    .fetch_optional(&state.db_connection_pool)
-    .await?;
+    if state.key_store.is_none() {
+        return Err(ErrorList::JwtNotEnabled.into());
+    }
+    let user = verify_login(state.clone(), &token_request.email, &token_request.password).await?;
 
-    if code_exists.is_none() {
-        return Err(ErrorList::InvalidVerificationCode.into());
+    if is_two_factor_enabled(state.clone(), user.id).await? {
+        let code = token_request.code.as_deref().unwrap_or_default();
+        if !verify_second_factor(state.clone(), &user, code).await? {
+            record_failed_login(state, &user, Utc::now().timestamp()).await?;
+            return Err(ErrorList::InvalidTwoFactorCode.into());
+        }
     }
 
-    sqlx::query!(
-        "UPDATE users SET email_verified = true WHERE email = $1",
-        &verification_details.email
-    )
-    .execute(&state.db_connection_pool)
-    .await?;
+    reset_login_attempts(state.clone(), user.id).await?;
+    let token_pair = create_token_pair(state, &user, None).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::AccessToken,
+        message: serde_json::to_string(&token_pair).expect("Could not convert tokens to string"),
+    }))
+}
+
+pub async fn refresh_token(
+    State(state): State<Arc<AppState>>,
+    Json(refresh_request): Json<RefreshTokenRequest>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let token_pair = rotate_refresh_token(state, &refresh_request.refresh_token).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::AccessToken,
+        message: serde_json::to_string(&token_pair).expect("Could not convert tokens to string"),
+    }))
+}
+
+pub async fn login_two_factor(
+    State(state): State<Arc<AppState>>,
+    session_metadata: SessionMetadata,
+    Json(two_factor_details): Json<TwoFactorLoginDetails>,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let pending_login =
+        get_pending_login(&two_factor_details.token).ok_or(ErrorList::InvalidPendingLogin)?;
+    let user = get_user_by_id(state.clone(), pending_login.user_id).await?;
+
+    let now = Utc::now().timestamp();
+    if let Err(e) = check_login_allowed(&state.config.server, &user, now) {
+        // A lock ends the pending login, while a throttled one can be retried after waiting
+        if matches!(e, ErrorList::TooManyLoginAttempts) {
+            remove_pending_login(&two_factor_details.token);
+        }
+        return Err(e.into());
+    }
+
+    if !verify_second_factor(state.clone(), &user, &two_factor_details.code).await? {
+        record_failed_login(state, &user, now).await?;
+        return Err(ErrorList::InvalidTwoFactorCode.into());
+    }
+
+    remove_pending_login(&two_factor_details.token);
+
+    reset_login_attempts(state.clone(), user.id).await?;
+
+    let session_cookie = create_session(&user, state.clone(), &session_metadata).await?;
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::SET_COOKIE, session_cookie.to_string().parse()?);
+
+    Ok((
+        header_map,
+        Json(ApiResponse {
+            response_type: ResponseType::LoginSuccess,
+            message: "Login successful".to_string(),
+        }),
+    ))
+}
+
+pub async fn magic_login_initiate(
+    State(state): State<Arc<AppState>>,
+    Json(magic_login_request): Json<MagicLoginRequest>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if !state.config.server.passwordless_login_enabled {
+        return Err(ErrorList::PasswordlessLoginDisabled.into());
+    }
+
+    let user = get_user_by_email(state.clone(), &magic_login_request.email).await;
+
+    if state.config.server.enumeration_safe {
+        if let Ok(user) = user {
+            spawn_account_task(async move { send_magic_login_email(&user, state).await });
+        }
+        return Ok(Json(ApiResponse {
+            response_type: ResponseType::MagicLoginEmailSent,
+            message: "If an account exists for that email, a login email has been sent".to_string(),
+        }));
+    }
+
+    let user = user.map_err(|_| ErrorList::IncorrectUsername)?;
+    send_magic_login_email(&user, state).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::MagicLoginEmailSent,
+        message: "Login email sent".to_string(),
+    }))
+}
+
+pub async fn magic_login_complete(
+    State(state): State<Arc<AppState>>,
+    session_metadata: SessionMetadata,
+    Json(magic_login_details): Json<MagicLoginCompleteRequest>,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    if !state.config.server.passwordless_login_enabled {
+        return Err(ErrorList::PasswordlessLoginDisabled.into());
+    }
+
+    // Marking the code as used in the same statement stops it being redeemed twice
+    let code = sqlx::query!(
+        "UPDATE codes SET used = true WHERE code_type = 'MagicLogin' AND used = false AND expiry_ts > $1 AND code = $3
+        AND user_id = (SELECT id FROM users WHERE email = $2) RETURNING id",
+        Utc::now().timestamp(),
+        &magic_login_details.email,
+        &magic_login_details.code
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    if code.is_none() {
+        return Err(ErrorList::InvalidVerificationCode.into());
+    }
+
+    // Following the link proves ownership of the email
+    sqlx::query!(
+        "UPDATE users SET email_verified = true WHERE email = $1",
+        &magic_login_details.email
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    let user = get_user_by_email(state.clone(), &magic_login_details.email).await?;
+    let mut header_map = HeaderMap::new();
+
+    if is_two_factor_enabled(state.clone(), user.id).await? {
+        event!(Level::INFO, "Login code verified, second factor required");
+        return Ok((
+            header_map,
+            Json(ApiResponse {
+                response_type: ResponseType::SecondFactorRequired,
+                message: create_pending_login(user.id),
+            }),
+        ));
+    }
+
+    let session_cookie = create_session(&user, state.clone(), &session_metadata).await?;
+    header_map.insert(header::SET_COOKIE, session_cookie.to_string().parse()?);
+
+    Ok((
+        header_map,
+        Json(ApiResponse {
+            response_type: ResponseType::LoginSuccess,
+            message: "Login successful".to_string(),
+        }),
+    ))
+}
+
+pub async fn passkey_login_options(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let options = get_authentication_options(state);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::PasskeyLoginOptions,
+        message: options.to_string(),
+    }))
+}
+
+pub async fn passkey_login(
+    State(state): State<Arc<AppState>>,
+    session_metadata: SessionMetadata,
+    Json(credential): Json<AuthenticationCredential>,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let user_id = authenticate_passkey(state.clone(), &credential).await?;
+    let user = get_user_by_id(state.clone(), user_id).await?;
+
+    event!(Level::INFO, "Passkey verified, creating session");
+    let session_cookie = create_session(&user, state.clone(), &session_metadata).await?;
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::SET_COOKIE, session_cookie.to_string().parse()?);
+
+    Ok((
+        header_map,
+        Json(ApiResponse {
+            response_type: ResponseType::LoginSuccess,
+            message: "Login successful".to_string(),
+        }),
+    ))
+}
+
+pub async fn verify_email(
+    State(state): State<Arc<AppState>>,
+    Json(verification_details): Json<VerificationDetails>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let now = Utc::now().timestamp();
+
+    let code_exists = sqlx::query!(
+        "SELECT 1 as exists FROM codes JOIN users ON users.id = codes.user_id
+        WHERE code_type = 'EmailVerification' AND users.email = $1 AND code = $2 AND expiry_ts > $3",
+        &verification_details.email,
+        &verification_details.code,
+        now
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    if code_exists.is_none() {
+        return Err(ErrorList::InvalidVerificationCode.into());
+    }
+
+    sqlx::query!(
+        "UPDATE users SET email_verified = true WHERE email = $1",
+        &verification_details.email
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
 
     // Clean up used code
     sqlx::query!(
-        "UPDATE codes SET used = true WHERE email = $1 AND code = $2 AND code_type = 'EmailVerification'",
+        "UPDATE codes SET used = true WHERE user_id = (SELECT id FROM users WHERE email = $1) AND code = $2 AND code_type = 'EmailVerification'",
         &verification_details.email,
         &verification_details.code
     )
@@ -541,7 +1236,7 @@ pub async fn change_password(
     user: User,
     Json(password_details): Json<ChangePassword>,
 ) -> Result<Json<ApiResponse>, AppError> {
-    if user.identity_provider != *"default" {
+    if user.hashed_password.is_none() {
         return Err(ErrorList::UserDoesNotUsePassword.into());
     }
     if !verify_password(
@@ -574,6 +1269,62 @@ pub async fn change_password(
     }))
 }
 
+pub async fn change_email(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(change_details): Json<ChangeEmail>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let Some(hashed_password) = user.hashed_password.as_ref() else {
+        return Err(ErrorList::UserDoesNotUsePassword.into());
+    };
+    if !verify_password(hashed_password, &change_details.password) {
+        return Err(ErrorList::IncorrectPassword.into());
+    }
+    validate_email(&change_details.new_email)?;
+
+    let response = Json(ApiResponse {
+        message: "A confirmation code has been sent to your new email".to_string(),
+        response_type: ResponseType::EmailChangeInitiated,
+    });
+
+    if change_details.new_email == user.email
+        || email_exists(&change_details.new_email, state.clone()).await
+    {
+        if state.config.server.enumeration_safe {
+            return Ok(response);
+        }
+        return Err(ErrorList::EmailAlreadyRegistered.into());
+    }
+
+    start_email_change(state, &user, &change_details.new_email).await?;
+    Ok(response)
+}
+
+pub async fn email_change_confirm(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(EmailChangeCode { code }): Json<EmailChangeCode>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let new_email = confirm_email_change(state, &user, &code).await?;
+
+    Ok(Json(ApiResponse {
+        message: format!("Your email has been changed to {new_email}"),
+        response_type: ResponseType::EmailChanged,
+    }))
+}
+
+pub async fn email_change_revert(
+    State(state): State<Arc<AppState>>,
+    Json(EmailChangeCode { code }): Json<EmailChangeCode>,
+) -> Result<Json<ApiResponse>, AppError> {
+    revert_email_change(state, &code).await?;
+
+    Ok(Json(ApiResponse {
+        message: "Your email change has been undone and you have been signed out everywhere. If you didn't request it, please reset your password".to_string(),
+        response_type: ResponseType::EmailChangeReverted,
+    }))
+}
+
 pub async fn password_reset_initiate(
     State(state): State<Arc<AppState>>,
     Json(password_reset_request): Json<PasswordResetInitiateRequest>,
@@ -581,12 +1332,15 @@ pub async fn password_reset_initiate(
     // Check if user exists for provided email
     let row = sqlx::query!(
         r#"SELECT
+            id,
             username as "username!",
             email as "email!",
             email_verified as "email_verified!",
             hashed_password,
             auth_level as "auth_level!",
             login_attempts as "login_attempts!",
+            last_failed_login_ts,
+            locked_until_ts,
             registration_ts as "registration_ts!",
             identity_provider as "identity_provider!"
         FROM users WHERE email = $1"#,
@@ -595,40 +1349,33 @@ pub async fn password_reset_initiate(
     .fetch_optional(&state.db_connection_pool)
     .await?;
 
-    let user = match row {
-        Some(r) => User {
-            username: r.username,
-            email: r.email,
-            email_verified: r.email_verified,
-            hashed_password: r.hashed_password,
-            auth_level: r.auth_level,
-            login_attempts: r.login_attempts,
-            registration_ts: r.registration_ts,
-            identity_provider: r.identity_provider,
-        },
-        None => return Err(ErrorList::IncorrectUsername.into()),
-    };
-
-    // Generate a code
-    let code = generate_unique_id(8);
-
-    // Add code to database
-    add_code(state.clone(), &user.email, &code, CodeType::PasswordReset).await?;
-
-    // Send email
-    let email = Email {
-        to: &user.email,
-        from: "registration@tld.com",
-        subject: String::from("Password Reset"),
-        body: format!(
-            "<p>A password reset was requested for your account.</p> \
-            <p>Use this code to reset your password: {code}</p> \
-            <p>If you did not request this, please ignore this email.</p>"
-        ),
-        reply_to: None,
-    };
+    let user = row.map(|r| User {
+        id: r.id,
+        username: r.username,
+        email: r.email,
+        email_verified: r.email_verified,
+        hashed_password: r.hashed_password,
+        auth_level: r.auth_level,
+        login_attempts: r.login_attempts,
+        last_failed_login_ts: r.last_failed_login_ts,
+        locked_until_ts: r.locked_until_ts,
+        registration_ts: r.registration_ts,
+        identity_provider: r.identity_provider,
+    });
+
+    if state.config.server.enumeration_safe {
+        if let Some(user) = user {
+            spawn_account_task(async move { send_password_reset_email(&user, state).await });
+        }
+        return Ok(Json(ApiResponse {
+            message: "If an account exists for that email, a password reset email has been sent"
+                .to_string(),
+            response_type: ResponseType::PasswordResetInitiationSuccess,
+        }));
+    }
 
-    send_email(state, email).await?;
+    let user = user.ok_or(ErrorList::IncorrectUsername)?;
+    send_password_reset_email(&user, state).await?;
 
     Ok(Json(ApiResponse {
         message: "Password reset email sent".to_string(),
@@ -649,7 +1396,7 @@ pub async fn password_reset_complete(
 
     // Check if code is valid
     let code = sqlx::query!(
-        "SELECT code, email FROM codes WHERE code_type = 'PasswordReset' AND used = false AND expiry_ts > $1 AND code = $2",
+        "SELECT code, user_id FROM codes WHERE code_type = 'PasswordReset' AND used = false AND expiry_ts > $1 AND code = $2",
         now,
         &password_reset_response.code
     )
@@ -657,14 +1404,13 @@ pub async fn password_reset_complete(
     .await?;
 
     if let Some(code_row) = code {
-        let email = code_row.email.unwrap_or_default();
         let code_value = code_row.code.unwrap_or_default();
 
         // Update password
         sqlx::query!(
-            "UPDATE users SET hashed_password = $1, login_attempts = 0 WHERE email = $2",
+            "UPDATE users SET hashed_password = $1, login_attempts = 0, last_failed_login_ts = NULL, locked_until_ts = NULL WHERE id = $2",
             hash_password(password_reset_response.password.as_str()),
-            &email
+            code_row.user_id
         )
         .execute(&state.db_connection_pool)
         .await?;
@@ -692,10 +1438,14 @@ pub async fn get_profile(user: User) -> Result<Json<ApiResponse>, AppError> {
     }))
 }
 
-pub async fn logout(State(state): State<Arc<AppState>>, user: User) -> Result<HeaderMap, AppError> {
-    sqlx::query!("DELETE FROM sessions WHERE email = $1", &user.email)
-        .execute(&state.db_connection_pool)
-        .await?;
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    user: User,
+) -> Result<HeaderMap, AppError> {
+    // Only end the session which made the request, other devices stay logged in
+    let session_key = get_session_key(&request_headers).ok_or(ErrorList::Unauthorised)?;
+    delete_session_by_key(state.clone(), user.id, &session_key).await?;
 
     let logout_cookie = Cookie::build(("session-key", ""))
         .max_age(Duration::days(-state.config.server.session_length_in_days))
@@ -709,6 +1459,329 @@ pub async fn logout(State(state): State<Arc<AppState>>, user: User) -> Result<He
     Ok(headers)
 }
 
+pub async fn two_factor_enroll(
+    State(state): State<Arc<AppState>>,
+    user: User,
+) -> Result<Json<ApiResponse>, AppError> {
+    if user.hashed_password.is_none() {
+        return Err(ErrorList::UserDoesNotUsePassword.into());
+    }
+    if is_two_factor_enabled(state.clone(), user.id).await? {
+        return Err(ErrorList::TwoFactorAlreadyEnabled.into());
+    }
+
+    let enrollment = start_enrollment(state, &user).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::TwoFactorEnrollment,
+        message: serde_json::to_string(&enrollment)
+            .expect("Could not convert enrollment to string"),
+    }))
+}
+
+pub async fn two_factor_confirm(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(two_factor_code): Json<TwoFactorCode>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let totp_secret = get_totp_secret(state.clone(), user.id)
+        .await?
+        .ok_or(ErrorList::TwoFactorNotStarted)?;
+    if totp_secret.confirmed {
+        return Err(ErrorList::TwoFactorAlreadyEnabled.into());
+    }
+
+    if !verify_totp_code(state.clone(), &user, &totp_secret, &two_factor_code.code).await? {
+        return Err(ErrorList::InvalidTwoFactorCode.into());
+    }
+
+    confirm_enrollment(state.clone(), user.id).await?;
+    let recovery_codes = generate_recovery_codes(state, user.id).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::TwoFactorEnabled,
+        message: serde_json::to_string(&recovery_codes)
+            .expect("Could not convert recovery codes to string"),
+    }))
+}
+
+pub async fn two_factor_disable(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(disable_details): Json<DisableTwoFactor>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if !is_two_factor_enabled(state.clone(), user.id).await? {
+        return Err(ErrorList::TwoFactorNotEnabled.into());
+    }
+
+    let hashed_password = user
+        .hashed_password
+        .as_ref()
+        .ok_or(ErrorList::UserDoesNotUsePassword)?;
+    if !verify_password(hashed_password, &disable_details.password) {
+        return Err(ErrorList::IncorrectPassword.into());
+    }
+    if !verify_second_factor(state.clone(), &user, &disable_details.code).await? {
+        return Err(ErrorList::InvalidTwoFactorCode.into());
+    }
+
+    disable_two_factor(state, user.id).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::TwoFactorDisabled,
+        message: "Two factor authentication disabled".to_string(),
+    }))
+}
+
+pub async fn passkey_registration_options(
+    State(state): State<Arc<AppState>>,
+    user: User,
+) -> Result<Json<ApiResponse>, AppError> {
+    let existing_credential_ids = get_credential_ids(state.clone(), user.id).await?;
+    let options = get_registration_options(state, &user, &existing_credential_ids);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::PasskeyRegistrationOptions,
+        message: options.to_string(),
+    }))
+}
+
+pub async fn register_passkey(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Json(registration_details): Json<PasskeyRegistrationDetails>,
+) -> Result<Json<ApiResponse>, AppError> {
+    validate_passkey_name(&registration_details.name)?;
+
+    let credential = verify_registration(state.clone(), user.id, &registration_details.credential)?;
+    add_passkey(state, user.id, &registration_details.name, &credential).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::PasskeyRegistered,
+        message: "Passkey registered successfully".to_string(),
+    }))
+}
+
+pub async fn get_user_passkeys(
+    State(state): State<Arc<AppState>>,
+    user: User,
+) -> Result<Json<ApiResponse>, AppError> {
+    let passkeys = get_passkeys(state, user.id).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::PasskeyList,
+        message: serde_json::to_string(&passkeys).expect("Could not convert passkeys to string"),
+    }))
+}
+
+pub async fn remove_passkey(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Path(passkey_id): Path<i32>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if !delete_passkey(state, user.id, passkey_id).await? {
+        return Err(ErrorList::PasskeyNotFound.into());
+    }
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::PasskeyRemoved,
+        message: "Passkey removed successfully".to_string(),
+    }))
+}
+
+pub async fn get_sessions(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    user: User,
+) -> Result<Json<ApiResponse>, AppError> {
+    let session_key = get_session_key(&request_headers).ok_or(ErrorList::Unauthorised)?;
+    let sessions = get_user_sessions(state, user.id, &session_key).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::SessionList,
+        message: serde_json::to_string(&sessions).expect("Could not convert sessions to string"),
+    }))
+}
+
+pub async fn get_identities(
+    State(state): State<Arc<AppState>>,
+    user: User,
+) -> Result<Json<ApiResponse>, AppError> {
+    let identities = get_user_identities(state, user.id).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::IdentityList,
+        message: serde_json::to_string(&identities)
+            .expect("Could not convert identities to string"),
+    }))
+}
+
+// Google identities are linked straight away, redirect based providers return the
+// URL to send the browser to and are linked when it returns to the callback
+pub async fn link_user_identity(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    user: User,
+    Path(provider_id): Path<String>,
+    Json(link_details): Json<LinkIdentityRequest>,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let session_key = get_session_key(&request_headers).ok_or(ErrorList::Unauthorised)?;
+    if !is_reauthenticated(
+        state.clone(),
+        &user,
+        &session_key,
+        link_details.password.as_deref(),
+    )
+    .await?
+    {
+        return Err(ErrorList::ReauthenticationRequired.into());
+    }
+
+    let (authorization_url, state_cookie) = match IdentityProvider::from(provider_id.clone()) {
+        IdentityProvider::Google => {
+            let jwt = link_details.jwt.ok_or(ErrorList::InvalidJwt)?;
+            let identity = verify_google_jwt(state.clone(), &jwt).await?;
+            link_identity(state, user.id, &provider_id, &identity.sub).await?;
+
+            return Ok((
+                HeaderMap::new(),
+                Json(ApiResponse {
+                    response_type: ResponseType::IdentityLinked,
+                    message: "Identity linked successfully".to_string(),
+                }),
+            ));
+        }
+        IdentityProvider::GitHub => {
+            let config = get_github_config(&state)?;
+            github::get_authorization_url(&config, Some(user.id))?
+        }
+        IdentityProvider::Oidc(provider_id) => {
+            let provider = get_provider(&state, &provider_id)?;
+            get_authorization_url(state, &provider, Some(user.id)).await?
+        }
+        IdentityProvider::Default => return Err(ErrorList::UnknownProvider.into()),
+    };
+
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::SET_COOKIE, state_cookie.to_string().parse()?);
+    Ok((
+        header_map,
+        Json(ApiResponse {
+            response_type: ResponseType::IdentityLinkRedirect,
+            message: authorization_url.to_string(),
+        }),
+    ))
+}
+
+pub async fn unlink_user_identity(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Path(identity_id): Path<i32>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if !unlink_identity(state, &user, identity_id).await? {
+        return Err(ErrorList::IdentityNotFound.into());
+    }
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::IdentityUnlinked,
+        message: "Identity unlinked successfully".to_string(),
+    }))
+}
+
+// A token can't be used to create or revoke tokens, otherwise a read only token could mint a write one
+pub async fn get_user_api_tokens(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    token_scopes: Option<Extension<TokenScopes>>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if token_scopes.is_some() {
+        return Err(ErrorList::SessionRequired.into());
+    }
+    let tokens = get_api_tokens(state, user.id).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::ApiTokenList,
+        message: serde_json::to_string(&tokens).expect("Could not convert API tokens to string"),
+    }))
+}
+
+pub async fn create_user_api_token(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    token_scopes: Option<Extension<TokenScopes>>,
+    Json(token_request): Json<CreateApiTokenRequest>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if token_scopes.is_some() {
+        return Err(ErrorList::SessionRequired.into());
+    }
+    validate_token_name(&token_request.name)?;
+    validate_scopes(&token_request.scopes)?;
+    let expiry_ts = get_expiry_ts(token_request.expires_in_days, Utc::now().timestamp())?;
+
+    let token = create_api_token(
+        state,
+        user.id,
+        &token_request.name,
+        &token_request.scopes,
+        expiry_ts,
+    )
+    .await?;
+    event!(Level::INFO, "API token {} created", token.id);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::ApiTokenCreated,
+        message: serde_json::to_string(&token).expect("Could not convert API token to string"),
+    }))
+}
+
+pub async fn revoke_api_token(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    token_scopes: Option<Extension<TokenScopes>>,
+    Path(token_id): Path<i32>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if token_scopes.is_some() {
+        return Err(ErrorList::SessionRequired.into());
+    }
+    if !delete_api_token(state, user.id, token_id).await? {
+        return Err(ErrorList::TokenNotFound.into());
+    }
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::ApiTokenRevoked,
+        message: "API token revoked successfully".to_string(),
+    }))
+}
+
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    user: User,
+    Path(session_id): Path<i32>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if !delete_session(state, user.id, session_id).await? {
+        return Err(ErrorList::SessionNotFound.into());
+    }
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::SessionRevoked,
+        message: "Session revoked successfully".to_string(),
+    }))
+}
+
+pub async fn revoke_other_sessions(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    user: User,
+) -> Result<Json<ApiResponse>, AppError> {
+    let session_key = get_session_key(&request_headers).ok_or(ErrorList::Unauthorised)?;
+    let revoked = delete_other_sessions(state, user.id, &session_key).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::OtherSessionsRevoked,
+        message: format!("{revoked} other session(s) revoked"),
+    }))
+}
+
 pub async fn resend_verification_email(
     State(state): State<Arc<AppState>>,
     user: User,
@@ -726,10 +1799,429 @@ pub async fn resend_verification_email(
     }
 }
 
+pub async fn get_all_roles(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let roles = get_roles(state).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::RoleList,
+        message: serde_json::to_string(&roles).expect("Could not convert roles to string"),
+    }))
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+pub async fn admin_list_users(
+    State(state): State<Arc<AppState>>,
+    Query(parameters): Query<UserSearchParameters>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let page = parameters.page.unwrap_or(1).max(1);
+    let page_size = parameters
+        .page_size
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let search = parameters
+        .search
+        .as_deref()
+        .map(str::trim)
+        .filter(|search| !search.is_empty());
+
+    let (users, total) = search_users(state, search, page_size, (page - 1) * page_size).await?;
+    let user_page = UserPage {
+        users: users.into_iter().map(Profile::from).collect(),
+        total,
+        page,
+        page_size,
+    };
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserList,
+        message: serde_json::to_string(&user_page).expect("Could not convert users to string"),
+    }))
+}
+
+async fn get_target_user(state: Arc<AppState>, username: &str) -> Result<User, AppError> {
+    get_user_by_username(state, username)
+        .await
+        .map_err(|_| ErrorList::UserNotFound.into())
+}
+
+pub async fn admin_get_user(
+    State(state): State<Arc<AppState>>,
+    Path(username): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let user = get_target_user(state.clone(), &username).await?;
+    let sessions = get_user_sessions(state, user.id, "").await?;
+    let details = AdminUserDetails {
+        login_attempts: user.login_attempts,
+        locked_until_ts: user.locked_until_ts,
+        profile: Profile::from(user),
+        sessions,
+    };
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserDetails,
+        message: serde_json::to_string(&details).expect("Could not convert user to string"),
+    }))
+}
+
+pub async fn admin_verify_email(
+    State(state): State<Arc<AppState>>,
+    Path(username): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let user = get_target_user(state.clone(), &username).await?;
+    set_email_verified(state, user.id).await?;
+    event!(Level::INFO, "Admin verified email for {}", user.username);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserUpdated,
+        message: "Email verified successfully".to_string(),
+    }))
+}
+
+pub async fn admin_unlock_user(
+    State(state): State<Arc<AppState>>,
+    Path(username): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let user = get_target_user(state.clone(), &username).await?;
+    reset_login_attempts(state, user.id).await?;
+    event!(Level::INFO, "Admin unlocked {}", user.username);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserUpdated,
+        message: "Account unlocked successfully".to_string(),
+    }))
+}
+
+pub async fn admin_change_auth_level(
+    State(state): State<Arc<AppState>>,
+    admin: User,
+    Path(username): Path<String>,
+    Json(change_details): Json<ChangeAuthLevel>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let user = get_target_user(state.clone(), &username).await?;
+    // Stops the last admin from locking everyone out of the admin routes
+    if user.id == admin.id {
+        return Err(ErrorList::CannotModifySelf.into());
+    }
+    if !set_auth_level(state, user.id, &change_details.auth_level.to_lowercase()).await? {
+        return Err(ErrorList::RoleNotFound.into());
+    }
+    event!(
+        Level::INFO,
+        "Admin changed auth level for {} to {}",
+        user.username,
+        change_details.auth_level
+    );
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserUpdated,
+        message: "Auth level changed successfully".to_string(),
+    }))
+}
+
+pub async fn admin_revoke_sessions(
+    State(state): State<Arc<AppState>>,
+    Path(username): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let user = get_target_user(state.clone(), &username).await?;
+    let revoked = delete_all_sessions(state, user.id).await?;
+    event!(
+        Level::INFO,
+        "Admin revoked {} sessions for {}",
+        revoked,
+        user.username
+    );
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserSessionsRevoked,
+        message: format!("{} sessions revoked", revoked),
+    }))
+}
+
+pub async fn admin_delete_user(
+    State(state): State<Arc<AppState>>,
+    admin: User,
+    Path(username): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let user = get_target_user(state.clone(), &username).await?;
+    if user.id == admin.id {
+        return Err(ErrorList::CannotModifySelf.into());
+    }
+    delete_user(state, user.id).await?;
+    event!(Level::INFO, "Admin deleted {}", user.username);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::UserDeleted,
+        message: "User deleted successfully".to_string(),
+    }))
+}
+
+// Well-known documents are returned as is rather than wrapped in an ApiResponse
+pub async fn jwks(State(state): State<Arc<AppState>>) -> Result<Json<JwkSet>, AppError> {
+    Ok(Json(get_jwks(&state).await?))
+}
+
+pub async fn openid_configuration(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<OpenIdConfiguration>, AppError> {
+    Ok(Json(get_openid_configuration(&state)?))
+}
+
+// Unknown clients are shown an error rather than redirected, everything else goes back to the client.
+// Only a session counts as being signed in here, bearer tokens are ignored
+pub async fn oauth_authorize(
+    State(state): State<Arc<AppState>>,
+    auth_context: Option<AuthContext>,
+    Query(parameters): Query<AuthorizationParameters>,
+) -> Result<Redirect, AppError> {
+    let request = match validate_authorization_request(&state, parameters).await? {
+        Ok(request) => request,
+        Err(error_url) => return Ok(Redirect::to(error_url.as_str())),
+    };
+    let frontend_url = &state.config.server.frontend_url;
+
+    match auth_context.filter(|context| context.session_id.is_some()) {
+        Some(context) if request.trusted => {
+            let redirect_url = approve_authorization(request, context.user_id)?;
+            Ok(Redirect::to(redirect_url.as_str()))
+        }
+        Some(_) => {
+            let request_id = store_authorization_request(request);
+            Ok(Redirect::to(&format!(
+                "{}/oauth/consent/?request={}",
+                frontend_url, request_id
+            )))
+        }
+        None => {
+            let request_id = store_authorization_request(request);
+            let consent_path = format!("/oauth/consent/?request={}", request_id);
+            Ok(Redirect::to(&format!(
+                "{}/login?redirect={}",
+                frontend_url,
+                urlencoding::encode(&consent_path)
+            )))
+        }
+    }
+}
+
+// Consent has to come from a session so a token can't be used to authorize another application
+pub async fn get_oauth_request(
+    token_scopes: Option<Extension<TokenScopes>>,
+    access_token: Option<Extension<AccessTokenClaims>>,
+    Path(request_id): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if token_scopes.is_some() || access_token.is_some() {
+        return Err(ErrorList::SessionRequired.into());
+    }
+    let request =
+        get_authorization_request(&request_id).ok_or(ErrorList::AuthorizationRequestNotFound)?;
+    let details = AuthorizationRequestDetails {
+        client_name: request.client_name,
+        scope: request.scope,
+        trusted: request.trusted,
+    };
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::OAuthAuthorizationRequest,
+        message: serde_json::to_string(&details)
+            .expect("Could not convert authorization request to string"),
+    }))
+}
+
+pub async fn decide_oauth_request(
+    user: User,
+    token_scopes: Option<Extension<TokenScopes>>,
+    access_token: Option<Extension<AccessTokenClaims>>,
+    Path(request_id): Path<String>,
+    Json(consent): Json<OAuthConsent>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if token_scopes.is_some() || access_token.is_some() {
+        return Err(ErrorList::SessionRequired.into());
+    }
+    let request =
+        take_authorization_request(&request_id).ok_or(ErrorList::AuthorizationRequestNotFound)?;
+    let client_id = request.client_id.clone();
+
+    let redirect_url = if consent.approved {
+        approve_authorization(request, user.id)?
+    } else {
+        deny_authorization(request)?
+    };
+    event!(
+        Level::INFO,
+        "User {} {} access for OAuth client {}",
+        user.username,
+        if consent.approved {
+            "granted"
+        } else {
+            "denied"
+        },
+        client_id
+    );
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::OAuthRedirect,
+        message: redirect_url.to_string(),
+    }))
+}
+
+pub async fn oauth_token(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    Form(parameters): Form<OAuthTokenParameters>,
+) -> Result<(HeaderMap, Json<OAuthTokenResponse>), OAuthError> {
+    let (client_id, client_secret) = get_client_credentials(
+        &request_headers,
+        parameters.client_id,
+        parameters.client_secret,
+    )
+    .ok_or(OAuthError::InvalidClient)?;
+    let client = authenticate_client(&state, &client_id, client_secret.as_deref()).await?;
+
+    let tokens = match parameters.grant_type.as_str() {
+        "authorization_code" => {
+            let (Some(code), Some(redirect_uri), Some(code_verifier)) = (
+                parameters.code,
+                parameters.redirect_uri,
+                parameters.code_verifier,
+            ) else {
+                return Err(OAuthError::InvalidRequest);
+            };
+            exchange_authorization_code(&state, &client, &code, &redirect_uri, &code_verifier)
+                .await?
+        }
+        "refresh_token" => {
+            let refresh_token = parameters.refresh_token.ok_or(OAuthError::InvalidRequest)?;
+            refresh_client_tokens(&state, &client, &refresh_token).await?
+        }
+        _ => return Err(OAuthError::UnsupportedGrantType),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CACHE_CONTROL,
+        "no-store".parse().expect("Invalid header value"),
+    );
+    Ok((headers, Json(tokens)))
+}
+
+// Unknown tokens are not an error so clients can't use this to probe for valid tokens
+pub async fn oauth_revoke(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+    Form(parameters): Form<OAuthRevocationParameters>,
+) -> Result<StatusCode, OAuthError> {
+    let (client_id, client_secret) = get_client_credentials(
+        &request_headers,
+        parameters.client_id,
+        parameters.client_secret,
+    )
+    .ok_or(OAuthError::InvalidClient)?;
+    let client = authenticate_client(&state, &client_id, client_secret.as_deref()).await?;
+
+    revoke_token(&state, &client, &parameters.token).await?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn oauth_userinfo(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+) -> Result<Json<UserInfo>, OAuthError> {
+    let access_token = get_bearer_token(&request_headers).ok_or(OAuthError::InvalidToken)?;
+    Ok(Json(get_user_info(&state, &access_token).await?))
+}
+
+pub async fn admin_list_oauth_clients(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<ApiResponse>, AppError> {
+    let clients = get_clients(&state).await?;
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::OAuthClientList,
+        message: serde_json::to_string(&clients).expect("Could not convert clients to string"),
+    }))
+}
+
+pub async fn admin_create_oauth_client(
+    State(state): State<Arc<AppState>>,
+    Json(client_request): Json<CreateOAuthClientRequest>,
+) -> Result<Json<ApiResponse>, AppError> {
+    validate_client_name(&client_request.name)?;
+    validate_client_scopes(&client_request.allowed_scopes)?;
+    if client_request.redirect_uris.is_empty() {
+        return Err(ErrorList::InvalidRedirectUri.into());
+    }
+    for redirect_uri in &client_request.redirect_uris {
+        validate_redirect_uri(redirect_uri)?;
+    }
+
+    let client = create_client(
+        &state,
+        &client_request.name,
+        &client_request.redirect_uris,
+        &client_request.allowed_scopes,
+        client_request.trusted,
+        client_request.confidential,
+    )
+    .await?;
+    event!(
+        Level::INFO,
+        "Admin created OAuth client {}",
+        client.client_id
+    );
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::OAuthClientCreated,
+        message: serde_json::to_string(&client).expect("Could not convert client to string"),
+    }))
+}
+
+pub async fn admin_delete_oauth_client(
+    State(state): State<Arc<AppState>>,
+    Path(client_id): Path<String>,
+) -> Result<Json<ApiResponse>, AppError> {
+    if !delete_client(&state, &client_id).await? {
+        return Err(ErrorList::OAuthClientNotFound.into());
+    }
+    event!(Level::INFO, "Admin deleted OAuth client {}", client_id);
+
+    Ok(Json(ApiResponse {
+        response_type: ResponseType::OAuthClientDeleted,
+        message: "Application deleted successfully".to_string(),
+    }))
+}
+
 pub async fn health_check() -> http::status::StatusCode {
     http::status::StatusCode::NO_CONTENT
 }
 
+// Must be sent back in the X-CSRF-Token header on state-changing requests made with the session cookie
+pub async fn get_csrf_token(
+    State(state): State<Arc<AppState>>,
+    request_headers: HeaderMap,
+) -> Result<(HeaderMap, Json<ApiResponse>), AppError> {
+    let token = get_or_create_token(&request_headers);
+    let max_age = Duration::days(state.config.server.session_length_in_days).whole_seconds();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::SET_COOKIE,
+        csrf_cookie(token.clone(), max_age).to_string().parse()?,
+    );
+    headers.insert(header::CACHE_CONTROL, "no-store".parse()?);
+
+    Ok((
+        headers,
+        Json(ApiResponse {
+            response_type: ResponseType::CsrfToken,
+            message: token,
+        }),
+    ))
+}
+
 pub async fn get_nonce() -> Result<Json<ApiResponse>, AppError> {
     const NONCE_EXPIRATION: i64 = 300;
 
@@ -10,6 +10,9 @@ const MAX_PASSWORD_LENGTH: usize = 100;
 const MIN_USERNAME_LENGTH: usize = 3;
 const MAX_USERNAME_LENGTH: usize = 100;
 const MIN_EMAIL_LENGTH: usize = 3;
+const MAX_PASSKEY_NAME_LENGTH: usize = 100;
+const MAX_TOKEN_NAME_LENGTH: usize = 100;
+const MAX_CLIENT_NAME_LENGTH: usize = 100;
 
 pub fn validate_email(email: &str) -> Result<bool, ErrorList> {
     if email.contains('@') && email.len() >= MIN_EMAIL_LENGTH {
@@ -32,6 +35,46 @@ pub fn validate_username(username: &str) -> Result<bool, ErrorList> {
     Err(ErrorList::InvalidUsername)
 }
 
+pub fn validate_passkey_name(name: &str) -> Result<bool, ErrorList> {
+    if !name.trim().is_empty() && name.len() <= MAX_PASSKEY_NAME_LENGTH {
+        return Ok(true);
+    }
+    Err(ErrorList::InvalidPasskeyName)
+}
+
+pub fn validate_token_name(name: &str) -> Result<bool, ErrorList> {
+    if !name.trim().is_empty() && name.len() <= MAX_TOKEN_NAME_LENGTH {
+        return Ok(true);
+    }
+    Err(ErrorList::InvalidTokenName)
+}
+
+pub fn validate_client_name(name: &str) -> Result<bool, ErrorList> {
+    if !name.trim().is_empty() && name.len() <= MAX_CLIENT_NAME_LENGTH {
+        return Ok(true);
+    }
+    Err(ErrorList::InvalidClientName)
+}
+
+pub async fn username_exists(username: &str, state: Arc<AppState>) -> bool {
+    let username_exists = sqlx::query!(
+        "SELECT 1 as exists FROM users WHERE username = $1",
+        username
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await;
+
+    matches!(username_exists, Ok(Some(_)))
+}
+
+pub async fn email_exists(email: &str, state: Arc<AppState>) -> bool {
+    let email_exists = sqlx::query!("SELECT email FROM users WHERE email = $1", email)
+        .fetch_optional(&state.db_connection_pool)
+        .await;
+
+    matches!(email_exists, Ok(Some(_)))
+}
+
 pub async fn is_unique(
     username: &String,
     email: &String,
@@ -44,14 +87,7 @@ pub async fn is_unique(
         &email
     );
 
-    let username_exists = sqlx::query!(
-        "SELECT 1 as exists FROM users WHERE username = $1",
-        username
-    )
-    .fetch_optional(&state.db_connection_pool)
-    .await;
-
-    if let Ok(Some(_)) = username_exists {
+    if username_exists(username, state.clone()).await {
         event!(
             Level::INFO,
             "Attempted registration with duplicate username"
@@ -59,11 +95,7 @@ pub async fn is_unique(
         return Err(ErrorList::UsernameAlreadyRegistered);
     }
 
-    let email_exists = sqlx::query!("SELECT email FROM users WHERE email = $1", email)
-        .fetch_optional(&state.db_connection_pool)
-        .await;
-
-    if let Ok(Some(_)) = email_exists {
+    if email_exists(email, state).await {
         event!(Level::INFO, "Attempted registration with duplicate email");
         return Err(ErrorList::EmailAlreadyRegistered);
     }
@@ -147,4 +179,44 @@ mod tests {
     fn empty_username() {
         assert!(validate_username("").is_err());
     }
+
+    // Passkey name validation tests
+    #[test]
+    fn valid_passkey_name() {
+        assert!(validate_passkey_name("My laptop").is_ok());
+    }
+
+    #[test]
+    fn blank_passkey_name() {
+        assert!(validate_passkey_name("   ").is_err());
+    }
+
+    #[test]
+    fn too_long_passkey_name() {
+        let too_long_name = "a".repeat(MAX_PASSKEY_NAME_LENGTH + 1);
+        assert!(validate_passkey_name(&too_long_name).is_err());
+    }
+
+    // API token name validation tests
+    #[test]
+    fn valid_token_name() {
+        assert!(validate_token_name("CI deploys").is_ok());
+    }
+
+    #[test]
+    fn blank_token_name() {
+        assert!(validate_token_name("").is_err());
+    }
+
+    #[test]
+    fn too_long_token_name() {
+        let too_long_name = "a".repeat(MAX_TOKEN_NAME_LENGTH + 1);
+        assert!(validate_token_name(&too_long_name).is_err());
+    }
+
+    #[test]
+    fn blank_client_name() {
+        assert!(validate_client_name(" ").is_err());
+        assert!(validate_client_name("Mobile app").is_ok());
+    }
 }
@@ -0,0 +1,199 @@
+use crate::AppState;
+use crate::auth::delete_all_sessions;
+use crate::default_route_handlers::{CodeType, ErrorList};
+use crate::user::User;
+use crate::utilities::{Email, generate_unique_id, send_email};
+use chrono::Utc;
+use std::sync::Arc;
+use tracing::{Level, event};
+
+const CONFIRMATION_CODE_LENGTH: u8 = 8;
+const REVERT_CODE_LENGTH: u8 = 30;
+const SECONDS_IN_HOUR: i64 = 3600;
+const CONFIRMATION_VALID_FOR_HOURS: i64 = 1;
+// The old address may not be checked often, so it has longer to undo a change than the new one has to confirm it
+const REVERT_VALID_FOR_HOURS: i64 = 24 * 7;
+
+async fn add_email_change_code(
+    state: Arc<AppState>,
+    user: &User,
+    code: &str,
+    code_type: CodeType,
+    target_email: &str,
+    valid_for_hours: i64,
+) -> Result<(), anyhow::Error> {
+    let code_type_str: String = code_type.into();
+    let created_ts = Utc::now().timestamp();
+    let expiry_ts = created_ts + valid_for_hours * SECONDS_IN_HOUR;
+
+    sqlx::query!(
+        "INSERT INTO codes (code_type, user_id, code, target_email, created_ts, expiry_ts) VALUES ($1, $2, $3, $4, $5, $6)",
+        &code_type_str,
+        user.id,
+        code,
+        target_email,
+        created_ts,
+        expiry_ts
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+    Ok(())
+}
+
+// Sends a code to the new address to prove it is the user's, and a link to the old one in case it isn't
+pub async fn start_email_change(
+    state: Arc<AppState>,
+    user: &User,
+    new_email: &str,
+) -> Result<(), anyhow::Error> {
+    let code = generate_unique_id(CONFIRMATION_CODE_LENGTH);
+    let revert_code = generate_unique_id(REVERT_CODE_LENGTH);
+    add_email_change_code(
+        state.clone(),
+        user,
+        &code,
+        CodeType::EmailChange,
+        new_email,
+        CONFIRMATION_VALID_FOR_HOURS,
+    )
+    .await?;
+    add_email_change_code(
+        state.clone(),
+        user,
+        &revert_code,
+        CodeType::EmailChangeRevert,
+        &user.email,
+        REVERT_VALID_FOR_HOURS,
+    )
+    .await?;
+
+    let to = format!("{} <{}>", user.username, new_email);
+    let email = Email {
+        to: to.as_str(),
+        from: "registration@tld.com",
+        subject: String::from("Confirm your new email"),
+        body: format!(
+            "<p>Please confirm this is your new email using the following code {code}. Your code is valid for 1 hour.</p> \
+            <p>If you did not request this, please ignore this email.</p>"
+        ),
+        reply_to: None,
+    };
+    send_email(state.clone(), email).await?;
+
+    let link = format!(
+        "{}/email/revert?code={revert_code}",
+        state.config.server.frontend_url
+    );
+    let to = format!("{} <{}>", user.username, user.email);
+    let email = Email {
+        to: to.as_str(),
+        from: "registration@tld.com",
+        subject: String::from("Your email is being changed"),
+        body: format!(
+            "<p>A request was made to change the email for your account to {new_email}.</p> \
+            <p>If this wasn't you, use the following link to keep this email and sign out everywhere: <a href=\"{link}\">{link}</a>. \
+            The link is valid for 7 days, including after the change has been confirmed.</p>"
+        ),
+        reply_to: None,
+    };
+    send_email(state, email).await
+}
+
+// Returns the new email
+pub async fn confirm_email_change(
+    state: Arc<AppState>,
+    user: &User,
+    code: &str,
+) -> Result<String, anyhow::Error> {
+    let now = Utc::now().timestamp();
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let new_email = sqlx::query!(
+        r#"SELECT target_email as "target_email!" FROM codes
+        WHERE code_type = 'EmailChange' AND user_id = $1 AND code = $2 AND expiry_ts > $3 AND used = false AND target_email IS NOT NULL
+        FOR UPDATE"#,
+        user.id,
+        code,
+        now
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(ErrorList::InvalidVerificationCode)?
+    .target_email;
+
+    // Someone may have registered the address since the change was requested
+    let taken = sqlx::query!("SELECT 1 as exists FROM users WHERE email = $1", &new_email)
+        .fetch_optional(&mut *transaction)
+        .await?;
+    if taken.is_some() {
+        return Err(ErrorList::EmailAlreadyRegistered.into());
+    }
+
+    sqlx::query!(
+        "UPDATE codes SET used = true WHERE user_id = $1 AND code_type = 'EmailChange'",
+        user.id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE users SET email = $1, email_verified = true WHERE id = $2",
+        &new_email,
+        user.id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    event!(Level::INFO, "Email changed for {}", user.username);
+    Ok(new_email)
+}
+
+// Cancels a pending change or moves the account back, then signs out everywhere as someone else may have access
+pub async fn revert_email_change(state: Arc<AppState>, code: &str) -> Result<(), anyhow::Error> {
+    let now = Utc::now().timestamp();
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let revert = sqlx::query!(
+        r#"SELECT codes.user_id, users.email, target_email as "target_email!" FROM codes
+        JOIN users ON users.id = codes.user_id
+        WHERE code_type = 'EmailChangeRevert' AND code = $1 AND expiry_ts > $2 AND used = false AND target_email IS NOT NULL
+        FOR UPDATE"#,
+        code,
+        now
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(ErrorList::InvalidVerificationCode)?;
+
+    sqlx::query!(
+        "UPDATE codes SET used = true WHERE user_id = $1 AND code_type IN ('EmailChange', 'EmailChangeRevert')",
+        revert.user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+
+    if revert.email != revert.target_email {
+        let taken = sqlx::query!(
+            "SELECT 1 as exists FROM users WHERE email = $1",
+            &revert.target_email
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+        if taken.is_some() {
+            return Err(ErrorList::EmailAlreadyRegistered.into());
+        }
+
+        sqlx::query!(
+            "UPDATE users SET email = $1, email_verified = true WHERE id = $2",
+            &revert.target_email,
+            revert.user_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    delete_all_sessions(state, revert.user_id).await?;
+    event!(Level::WARN, "Email change reverted");
+    Ok(())
+}
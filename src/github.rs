@@ -0,0 +1,179 @@
+use crate::AppState;
+use crate::auth::IdentityProvider;
+use crate::config::GitHubConfig;
+use crate::default_route_handlers::ErrorList;
+use crate::oidc::{OidcState, create_state, pkce_challenge};
+use cookie::Cookie;
+use http::header::{ACCEPT, USER_AGENT};
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+// GitHub rejects API requests without a user agent
+const GITHUB_USER_AGENT: &str = "axumatic";
+const GITHUB_SCOPES: &str = "read:user user:email";
+
+#[derive(Deserialize, Debug)]
+pub struct AccessTokenResponse {
+    pub access_token: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GitHubUser {
+    pub id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GitHubEmail {
+    pub email: String,
+    pub primary: bool,
+    pub verified: bool,
+}
+
+// Details of the GitHub account needed to find or register the user
+pub struct GitHubIdentity {
+    pub sub: String,
+    pub email: Option<String>,
+}
+
+pub fn get_github_config(state: &Arc<AppState>) -> Result<GitHubConfig, ErrorList> {
+    state
+        .config
+        .github
+        .clone()
+        .ok_or(ErrorList::UnknownProvider)
+}
+
+pub fn get_authorization_url(
+    config: &GitHubConfig,
+    link_user_id: Option<Uuid>,
+) -> Result<(Url, Cookie<'static>), anyhow::Error> {
+    let (state_value, oidc_state, cookie) =
+        create_state(&String::from(IdentityProvider::GitHub), link_user_id);
+    let code_challenge = pkce_challenge(&oidc_state.code_verifier);
+
+    let url = Url::parse_with_params(
+        &format!("{}/login/oauth/authorize", config.oauth_url),
+        &[
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("scope", GITHUB_SCOPES),
+            ("state", state_value.as_str()),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )?;
+    Ok((url, cookie))
+}
+
+// Only the primary email is used and only once GitHub has verified it
+pub fn primary_verified_email(emails: Vec<GitHubEmail>) -> Option<String> {
+    emails
+        .into_iter()
+        .find(|email| email.primary && email.verified)
+        .map(|email| email.email)
+}
+
+// Exchanges the authorization code and fetches the user's id and primary email
+pub async fn complete_authorization(
+    state: Arc<AppState>,
+    config: &GitHubConfig,
+    oidc_state: &OidcState,
+    code: &str,
+) -> Result<GitHubIdentity, anyhow::Error> {
+    let mut form = vec![
+        ("client_id", config.client_id.as_str()),
+        ("code", code),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("code_verifier", oidc_state.code_verifier.as_str()),
+    ];
+    if let Some(client_secret) = &config.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+
+    // GitHub reports a failed exchange with a 200 and an error field
+    let token_response: AccessTokenResponse = state
+        .http_client
+        .post(format!("{}/login/oauth/access_token", config.oauth_url))
+        .header(ACCEPT, "application/json")
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let Some(access_token) = token_response.access_token else {
+        event!(
+            Level::WARN,
+            "GitHub token exchange failed with {:?}",
+            token_response.error
+        );
+        return Err(ErrorList::OidcProviderError.into());
+    };
+
+    let user: GitHubUser = state
+        .http_client
+        .get(format!("{}/user", config.api_url))
+        .bearer_auth(&access_token)
+        .header(USER_AGENT, GITHUB_USER_AGENT)
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let emails: Vec<GitHubEmail> = state
+        .http_client
+        .get(format!("{}/user/emails", config.api_url))
+        .bearer_auth(&access_token)
+        .header(USER_AGENT, GITHUB_USER_AGENT)
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(GitHubIdentity {
+        sub: user.id.to_string(),
+        email: primary_verified_email(emails),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(email: &str, primary: bool, verified: bool) -> GitHubEmail {
+        GitHubEmail {
+            email: email.to_string(),
+            primary,
+            verified,
+        }
+    }
+
+    #[test]
+    fn primary_verified_email_is_used() {
+        let emails = vec![
+            email("secondary@example.com", false, true),
+            email("primary@example.com", true, true),
+        ];
+        assert_eq!(
+            primary_verified_email(emails),
+            Some("primary@example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn unverified_primary_email_is_ignored() {
+        let emails = vec![
+            email("secondary@example.com", false, true),
+            email("primary@example.com", true, false),
+        ];
+        assert_eq!(primary_verified_email(emails), None);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::AppState;
+use crate::default_route_handlers::ErrorList;
+use crate::user::User;
+use crate::utilities::{hash_token, verify_password};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+// How recently a user without a password must have signed in to link an identity
+const REAUTHENTICATION_WINDOW: i64 = 300;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserIdentity {
+    pub id: i32,
+    pub identity_provider: String,
+    pub created_ts: i64,
+}
+
+pub async fn get_user_identities(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<Vec<UserIdentity>, anyhow::Error> {
+    let identities = sqlx::query_as!(
+        UserIdentity,
+        r#"SELECT
+            id,
+            identity_provider as "identity_provider!",
+            created_ts as "created_ts!"
+        FROM user_identities WHERE user_id = $1
+        ORDER BY created_ts"#,
+        user_id
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    Ok(identities)
+}
+
+// Users with a password must provide it, otherwise their session must have only just been created
+pub async fn is_reauthenticated(
+    state: Arc<AppState>,
+    user: &User,
+    session_key: &str,
+    password: Option<&str>,
+) -> Result<bool, anyhow::Error> {
+    if let Some(hashed_password) = &user.hashed_password {
+        return Ok(password.is_some_and(|password| verify_password(hashed_password, password)));
+    }
+
+    let session = state.session_store.get(&hash_token(session_key)).await?;
+
+    Ok(session.is_some_and(|session| {
+        session.user_id == user.id
+            && session.created_ts.is_some_and(|created_ts| {
+                created_ts + REAUTHENTICATION_WINDOW > Utc::now().timestamp()
+            })
+    }))
+}
+
+pub async fn link_identity(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    identity_provider: &str,
+    sub: &str,
+) -> Result<(), anyhow::Error> {
+    let result = sqlx::query!(
+        "INSERT INTO user_identities (user_id, identity_provider, sub, created_ts) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (identity_provider, sub) DO NOTHING",
+        user_id,
+        identity_provider,
+        sub,
+        Utc::now().timestamp()
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        // Linking an identity which is already linked to this account is a no-op
+        let owner = sqlx::query!(
+            "SELECT user_id FROM user_identities WHERE identity_provider = $1 AND sub = $2",
+            identity_provider,
+            sub
+        )
+        .fetch_one(&state.db_connection_pool)
+        .await?;
+        if owner.user_id != user_id {
+            return Err(ErrorList::IdentityAlreadyLinked.into());
+        }
+    }
+
+    event!(Level::INFO, "Linked {} identity", identity_provider);
+    Ok(())
+}
+
+// Refuses to remove the user's last way of signing in. The user's row is locked so concurrent removals
+// can't both pass the check
+pub async fn unlink_identity(
+    state: Arc<AppState>,
+    user: &User,
+    identity_id: i32,
+) -> Result<bool, anyhow::Error> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let has_password = sqlx::query!(
+        r#"SELECT hashed_password IS NOT NULL as "has_password!" FROM users WHERE id = $1 FOR UPDATE"#,
+        user.id
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .has_password;
+    let credentials = sqlx::query!(
+        r#"SELECT
+            (SELECT COUNT(*) FROM user_identities WHERE user_id = $1) as "identities!",
+            (SELECT COUNT(*) FROM passkeys WHERE user_id = $1) as "passkeys!",
+            (SELECT id FROM user_identities WHERE user_id = $1 AND id = $2) as identity_id
+        FROM (SELECT 1) as credentials"#,
+        user.id,
+        identity_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    if credentials.identity_id.is_none() {
+        return Ok(false);
+    }
+    let remaining = credentials.identities - 1 + credentials.passkeys + i64::from(has_password);
+    if remaining < 1 {
+        return Err(ErrorList::LastCredential.into());
+    }
+
+    sqlx::query!(
+        "DELETE FROM user_identities WHERE user_id = $1 AND id = $2",
+        user.id,
+        identity_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(true)
+}
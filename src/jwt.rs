@@ -0,0 +1,324 @@
+use crate::AppState;
+use crate::config::JwtConfig;
+use crate::default_route_handlers::ErrorList;
+use crate::keys::{JwkSet, KeyStore};
+use crate::user::{User, get_user_by_id};
+use crate::utilities::{generate_unique_id, hash_token};
+use chrono::Utc;
+use jsonwebtoken::{Header, Validation, decode, decode_header, encode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+const REFRESH_TOKEN_LENGTH: u8 = 64;
+const FAMILY_ID_LENGTH: u8 = 32;
+const SECONDS_IN_DAY: i64 = 86400;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: Uuid,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub jti: String,
+    pub email: String,
+    pub auth_level: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefreshTokenOwner {
+    pub user_id: Uuid,
+    pub family_id: String,
+    pub scope: Option<String>,
+}
+
+pub fn get_jwt_config(state: &AppState) -> Result<(&JwtConfig, &KeyStore), ErrorList> {
+    match (&state.config.jwt, &state.key_store) {
+        (Some(config), Some(key_store)) => Ok((config, key_store)),
+        _ => Err(ErrorList::JwtNotEnabled),
+    }
+}
+
+pub async fn get_jwks(state: &AppState) -> Result<JwkSet, anyhow::Error> {
+    let (_config, key_store) = get_jwt_config(state)?;
+    key_store.jwks(state).await
+}
+
+pub fn access_token_claims(config: &JwtConfig, user: &User, now: i64) -> AccessTokenClaims {
+    AccessTokenClaims {
+        iss: config.issuer.clone(),
+        sub: user.id,
+        aud: config.audience.clone(),
+        exp: now + config.access_token_ttl,
+        iat: now,
+        jti: generate_unique_id(20),
+        email: user.email.clone(),
+        auth_level: user.auth_level.clone(),
+    }
+}
+
+// Signs with the key store's current key so the token can be verified using the JWKS
+pub async fn sign_claims<T: Serialize>(
+    state: &AppState,
+    claims: &T,
+) -> Result<String, anyhow::Error> {
+    let (config, key_store) = get_jwt_config(state)?;
+    let (kid, encoding_key) = key_store.signing_key(state).await?;
+
+    let mut header = Header::new(config.algorithm);
+    header.kid = Some(kid);
+    Ok(encode(&header, claims, &encoding_key)?)
+}
+
+// Without an audience the caller must check the aud claim itself
+pub fn get_validation(config: &JwtConfig, audience: Option<&str>) -> Validation {
+    let mut validation = Validation::new(config.algorithm);
+    validation.set_issuer(&[&config.issuer]);
+    match audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    validation
+}
+
+pub async fn verify_token<T: DeserializeOwned>(
+    token: &str,
+    state: &AppState,
+    audience: Option<&str>,
+) -> Result<T, anyhow::Error> {
+    let (config, key_store) = get_jwt_config(state)?;
+    let header = decode_header(token)?;
+    let decoding_key = key_store
+        .decoding_key(header.kid.as_deref(), state)
+        .await?
+        .ok_or(ErrorList::InvalidJwt)?;
+
+    let token_data = decode::<T>(token, &decoding_key, &get_validation(config, audience))?;
+    Ok(token_data.claims)
+}
+
+// Only the signature and claims are checked so access tokens can be validated without the database
+pub async fn validate_access_token(
+    token: &str,
+    state: &AppState,
+) -> Result<AccessTokenClaims, anyhow::Error> {
+    let (config, _key_store) = get_jwt_config(state)?;
+    verify_token(token, state, Some(&config.audience)).await
+}
+
+// Refresh tokens issued by rotating another token share its family so reuse can revoke them all
+pub async fn store_refresh_token(
+    state: &AppState,
+    user_id: Uuid,
+    family_id: Option<String>,
+    client_id: Option<&str>,
+    scope: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    let (config, _key_store) = get_jwt_config(state)?;
+    let now = Utc::now().timestamp();
+    let refresh_token = generate_unique_id(REFRESH_TOKEN_LENGTH);
+    let family_id = family_id.unwrap_or_else(|| generate_unique_id(FAMILY_ID_LENGTH));
+
+    sqlx::query!(
+        "INSERT INTO refresh_tokens (user_id, family_id, hashed_token, created_ts, expiry_ts, client_id, scope) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        user_id,
+        family_id,
+        hash_token(&refresh_token),
+        now,
+        now + config.refresh_token_ttl_in_days * SECONDS_IN_DAY,
+        client_id,
+        scope
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(refresh_token)
+}
+
+// Each refresh token can only be used once, using one again revokes every token in its family
+pub async fn use_refresh_token(
+    state: &AppState,
+    refresh_token: &str,
+    client_id: Option<&str>,
+) -> Result<RefreshTokenOwner, anyhow::Error> {
+    let now = Utc::now().timestamp();
+    let hashed_token = hash_token(refresh_token);
+
+    let owner = sqlx::query_as!(
+        RefreshTokenOwner,
+        r#"UPDATE refresh_tokens SET used_ts = $1
+        WHERE hashed_token = $2 AND used_ts IS NULL AND NOT revoked AND expiry_ts > $1
+            AND client_id IS NOT DISTINCT FROM $3
+        RETURNING user_id, family_id as "family_id!", scope"#,
+        now,
+        &hashed_token,
+        client_id
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    let Some(owner) = owner else {
+        let reused = sqlx::query!(
+            r#"UPDATE refresh_tokens SET revoked = true
+            WHERE family_id = (
+                SELECT family_id FROM refresh_tokens WHERE hashed_token = $1 AND used_ts IS NOT NULL
+            )"#,
+            &hashed_token
+        )
+        .execute(&state.db_connection_pool)
+        .await?;
+        if reused.rows_affected() > 0 {
+            event!(
+                Level::WARN,
+                "Refresh token was reused, revoked its token family"
+            );
+        }
+        return Err(ErrorList::InvalidRefreshToken.into());
+    };
+
+    Ok(owner)
+}
+
+pub async fn revoke_refresh_token_family(
+    state: &AppState,
+    refresh_token: &str,
+    client_id: Option<&str>,
+) -> Result<u64, anyhow::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE refresh_tokens SET revoked = true
+        WHERE family_id = (
+            SELECT family_id FROM refresh_tokens
+            WHERE hashed_token = $1 AND client_id IS NOT DISTINCT FROM $2
+        )"#,
+        hash_token(refresh_token),
+        client_id
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn create_token_pair(
+    state: Arc<AppState>,
+    user: &User,
+    family_id: Option<String>,
+) -> Result<TokenPair, anyhow::Error> {
+    let (config, _key_store) = get_jwt_config(&state)?;
+    let now = Utc::now().timestamp();
+
+    let access_token = sign_claims(&state, &access_token_claims(config, user, now)).await?;
+    let refresh_token = store_refresh_token(&state, user.id, family_id, None, None).await?;
+
+    Ok(TokenPair {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: config.access_token_ttl,
+        refresh_token,
+    })
+}
+
+pub async fn rotate_refresh_token(
+    state: Arc<AppState>,
+    refresh_token: &str,
+) -> Result<TokenPair, anyhow::Error> {
+    get_jwt_config(&state)?;
+    let owner = use_refresh_token(&state, refresh_token, None).await?;
+
+    let user = get_user_by_id(state.clone(), owner.user_id).await?;
+    create_token_pair(state, &user, Some(owner.family_id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+
+    const SECRET: &[u8] = b"secret";
+
+    fn jwt_config(audience: &str) -> JwtConfig {
+        JwtConfig {
+            issuer: "https://tld.com".to_string(),
+            audience: audience.to_string(),
+            algorithm: Algorithm::HS256,
+            access_token_ttl: 900,
+            refresh_token_ttl_in_days: 30,
+            key_rotation_interval_in_days: 30,
+            secret: None,
+        }
+    }
+
+    fn user() -> User {
+        User {
+            id: Uuid::from_u128(1),
+            username: "user".to_string(),
+            email: "user@tld.com".to_string(),
+            email_verified: true,
+            hashed_password: None,
+            auth_level: "user".to_string(),
+            login_attempts: 0,
+            last_failed_login_ts: None,
+            locked_until_ts: None,
+            registration_ts: 0,
+            identity_provider: "default".to_string(),
+        }
+    }
+
+    fn create_token(config: &JwtConfig, issued: i64) -> String {
+        let claims = access_token_claims(config, &user(), issued);
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn decode_token(
+        token: &str,
+        config: &JwtConfig,
+        audience: Option<&str>,
+    ) -> Result<AccessTokenClaims, jsonwebtoken::errors::Error> {
+        let validation = get_validation(config, audience);
+        decode(token, &DecodingKey::from_secret(SECRET), &validation).map(|data| data.claims)
+    }
+
+    #[test]
+    fn access_token_round_trip() {
+        let config = jwt_config("axumatic");
+        let token = create_token(&config, Utc::now().timestamp());
+
+        let claims = decode_token(&token, &config, Some("axumatic")).unwrap();
+        assert_eq!(claims.sub, Uuid::from_u128(1));
+        assert_eq!(claims.email, "user@tld.com");
+    }
+
+    #[test]
+    fn access_token_for_another_audience_is_rejected() {
+        let config = jwt_config("axumatic");
+        let token = create_token(&config, Utc::now().timestamp());
+
+        assert!(decode_token(&token, &config, Some("another-service")).is_err());
+        assert!(decode_token(&token, &config, None).is_ok());
+    }
+
+    #[test]
+    fn expired_access_token_is_rejected() {
+        let config = jwt_config("axumatic");
+        let token = create_token(&config, Utc::now().timestamp() - 3600);
+
+        assert!(decode_token(&token, &config, Some("axumatic")).is_err());
+    }
+}
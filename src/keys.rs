@@ -0,0 +1,358 @@
+use crate::AppState;
+use crate::config::JwtConfig;
+use crate::passkey::BASE64_URL;
+use crate::utilities::generate_unique_id;
+use anyhow::anyhow;
+use base64::Engine;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use p256::ecdsa::SigningKey as EcSigningKey;
+use p256::elliptic_curve::rand_core::OsRng;
+use p256::pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use tracing::{Level, event};
+
+const KEY_ID_LENGTH: u8 = 16;
+const SECONDS_IN_DAY: i64 = 86400;
+// New keys are published this long before they are used so verifiers have time to fetch them
+const KEY_PUBLICATION_DELAY: i64 = 3600;
+// Limits how often an unknown key id can cause the keys to be reloaded from the database
+const KEY_RELOAD_INTERVAL: i64 = 60;
+const HMAC_KEY_ID: &str = "hmac";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PublicJwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub y: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JwkSet {
+    pub keys: Vec<PublicJwk>,
+}
+
+// HMAC keys are shared secrets so they have no public JWK and are never published
+pub struct SigningKey {
+    pub kid: String,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    pub jwk: Option<PublicJwk>,
+    pub activation_ts: i64,
+}
+
+// Asymmetric keys are persisted in Postgres so every instance signs with and publishes the same keys
+pub struct KeyStore {
+    algorithm: Algorithm,
+    keys: RwLock<Vec<SigningKey>>,
+    last_loaded_ts: AtomicI64,
+}
+
+impl KeyStore {
+    pub fn new(config: &JwtConfig) -> Result<Self, anyhow::Error> {
+        match config.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = config
+                    .secret
+                    .as_ref()
+                    .ok_or(anyhow!("AXUMATIC_JWT_SECRET variable not set"))?;
+                let key = SigningKey {
+                    kid: HMAC_KEY_ID.to_string(),
+                    encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+                    decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+                    jwk: None,
+                    activation_ts: 0,
+                };
+                Ok(Self {
+                    algorithm: config.algorithm,
+                    keys: RwLock::new(vec![key]),
+                    last_loaded_ts: AtomicI64::new(i64::MAX),
+                })
+            }
+            Algorithm::ES256 => Ok(Self {
+                algorithm: config.algorithm,
+                keys: RwLock::new(vec![]),
+                last_loaded_ts: AtomicI64::new(0),
+            }),
+            _ => Err(anyhow!(
+                "Unsupported JWT algorithm, use HS256, HS384, HS512 or ES256"
+            )),
+        }
+    }
+
+    fn is_persisted(&self) -> bool {
+        self.algorithm == Algorithm::ES256
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.read().expect("Couldn't acquire lock").is_empty()
+    }
+
+    async fn ensure_loaded(&self, state: &AppState) -> Result<(), anyhow::Error> {
+        if self.is_persisted() && self.is_empty() {
+            self.rotate(state).await?;
+        }
+        Ok(())
+    }
+
+    // Signs with the newest key which has been published for long enough, or the newest key if none have
+    pub async fn signing_key(
+        &self,
+        state: &AppState,
+    ) -> Result<(String, EncodingKey), anyhow::Error> {
+        self.ensure_loaded(state).await?;
+        let now = Utc::now().timestamp();
+
+        let keys = self.keys.read().expect("Couldn't acquire lock");
+        let key = keys
+            .iter()
+            .filter(|key| key.activation_ts <= now)
+            .max_by_key(|key| key.activation_ts)
+            .or_else(|| keys.iter().max_by_key(|key| key.activation_ts))
+            .ok_or(anyhow!("No signing keys available"))?;
+        Ok((key.kid.clone(), key.encoding_key.clone()))
+    }
+
+    // Another instance may have rotated in a key this one hasn't loaded yet
+    pub async fn decoding_key(
+        &self,
+        kid: Option<&str>,
+        state: &AppState,
+    ) -> Result<Option<DecodingKey>, anyhow::Error> {
+        let kid = kid.unwrap_or(HMAC_KEY_ID);
+        if let Some(key) = self.find_decoding_key(kid) {
+            return Ok(Some(key));
+        }
+
+        let now = Utc::now().timestamp();
+        if self.is_persisted()
+            && now - self.last_loaded_ts.load(Ordering::Relaxed) > KEY_RELOAD_INTERVAL
+        {
+            self.load(state).await?;
+            return Ok(self.find_decoding_key(kid));
+        }
+        Ok(None)
+    }
+
+    fn find_decoding_key(&self, kid: &str) -> Option<DecodingKey> {
+        self.keys
+            .read()
+            .expect("Couldn't acquire lock")
+            .iter()
+            .find(|key| key.kid == kid)
+            .map(|key| key.decoding_key.clone())
+    }
+
+    pub async fn jwks(&self, state: &AppState) -> Result<JwkSet, anyhow::Error> {
+        self.ensure_loaded(state).await?;
+        let keys = self
+            .keys
+            .read()
+            .expect("Couldn't acquire lock")
+            .iter()
+            .filter_map(|key| key.jwk.clone())
+            .collect();
+        Ok(JwkSet { keys })
+    }
+
+    pub async fn load(&self, state: &AppState) -> Result<(), anyhow::Error> {
+        let now = Utc::now().timestamp();
+        let rows = sqlx::query!(
+            r#"SELECT
+                kid as "kid!",
+                private_key as "private_key!",
+                activation_ts as "activation_ts!"
+            FROM signing_keys WHERE algorithm = $1 AND expiry_ts > $2
+            ORDER BY activation_ts"#,
+            algorithm_name(self.algorithm),
+            now
+        )
+        .fetch_all(&state.db_connection_pool)
+        .await?;
+
+        let keys = rows
+            .into_iter()
+            .map(|row| parse_signing_key(row.kid, &row.private_key, row.activation_ts))
+            .collect::<Result<Vec<SigningKey>, anyhow::Error>>()?;
+
+        *self.keys.write().expect("Couldn't acquire lock") = keys;
+        self.last_loaded_ts.store(now, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Adds a new key once the newest is due to be replaced and removes keys no token can still use
+    pub async fn rotate(&self, state: &AppState) -> Result<(), anyhow::Error> {
+        let Some(config) = &state.config.jwt else {
+            return Ok(());
+        };
+        if !self.is_persisted() {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp();
+        let rotation_interval = config.key_rotation_interval_in_days * SECONDS_IN_DAY;
+
+        sqlx::query!("DELETE FROM signing_keys WHERE $1 > expiry_ts", now)
+            .execute(&state.db_connection_pool)
+            .await?;
+
+        let newest = sqlx::query!(
+            "SELECT MAX(activation_ts) as activation_ts FROM signing_keys WHERE algorithm = $1",
+            algorithm_name(self.algorithm)
+        )
+        .fetch_one(&state.db_connection_pool)
+        .await?;
+
+        let activation_ts = match newest.activation_ts {
+            Some(activation_ts)
+                if activation_ts + rotation_interval - KEY_PUBLICATION_DELAY > now =>
+            {
+                None
+            }
+            Some(_) => Some(now + KEY_PUBLICATION_DELAY),
+            None => Some(now),
+        };
+
+        if let Some(activation_ts) = activation_ts {
+            // Guarded so instances rotating at the same time only add one key
+            let result = sqlx::query!(
+                "INSERT INTO signing_keys (kid, algorithm, private_key, created_ts, activation_ts, expiry_ts)
+                SELECT $1::VARCHAR, $2::VARCHAR, $3::TEXT, $4::BIGINT, $5::BIGINT, $6::BIGINT
+                WHERE NOT EXISTS (SELECT 1 FROM signing_keys WHERE algorithm = $2 AND activation_ts > $7)",
+                generate_unique_id(KEY_ID_LENGTH),
+                algorithm_name(self.algorithm),
+                generate_private_key_pem()?,
+                now,
+                activation_ts,
+                activation_ts + rotation_interval + KEY_PUBLICATION_DELAY + config.access_token_ttl,
+                now - rotation_interval + KEY_PUBLICATION_DELAY
+            )
+            .execute(&state.db_connection_pool)
+            .await?;
+            if result.rows_affected() > 0 {
+                event!(Level::INFO, "Generated a new signing key");
+            }
+        }
+
+        self.load(state).await
+    }
+}
+
+fn algorithm_name(algorithm: Algorithm) -> String {
+    format!("{:?}", algorithm)
+}
+
+pub fn generate_private_key_pem() -> Result<String, anyhow::Error> {
+    let key = EcSigningKey::random(&mut OsRng);
+    Ok(key.to_pkcs8_pem(LineEnding::LF)?.to_string())
+}
+
+pub fn parse_signing_key(
+    kid: String,
+    private_key: &str,
+    activation_ts: i64,
+) -> Result<SigningKey, anyhow::Error> {
+    let key = EcSigningKey::from_pkcs8_pem(private_key)?;
+    let point = key.verifying_key().to_encoded_point(false);
+    let x = BASE64_URL.encode(point.x().ok_or(anyhow!("Invalid public key"))?);
+    let y = BASE64_URL.encode(point.y().ok_or(anyhow!("Invalid public key"))?);
+
+    Ok(SigningKey {
+        encoding_key: EncodingKey::from_ec_pem(private_key.as_bytes())?,
+        decoding_key: DecodingKey::from_ec_components(&x, &y)?,
+        jwk: Some(PublicJwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            x,
+            y,
+            kid: kid.clone(),
+            alg: algorithm_name(Algorithm::ES256),
+            key_use: "sig".to_string(),
+        }),
+        kid,
+        activation_ts,
+    })
+}
+
+pub async fn start_key_rotation(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        loop {
+            if let Some(key_store) = &state.key_store
+                && let Err(e) = key_store.rotate(&state).await
+            {
+                event!(Level::WARN, "Failed to rotate signing keys due to {}", e);
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{Header, Validation, decode, encode};
+    use serde_json::{Value, json};
+
+    #[test]
+    fn generated_key_signs_and_verifies() {
+        let pem = generate_private_key_pem().unwrap();
+        let key = parse_signing_key("kid".to_string(), &pem, 0).unwrap();
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(key.kid.clone());
+        let token = encode(&header, &json!({"sub": "user"}), &key.encoding_key).unwrap();
+
+        let mut validation = Validation::new(Algorithm::ES256);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        let claims = decode::<Value>(&token, &key.decoding_key, &validation).unwrap();
+        assert_eq!(claims.claims["sub"], "user");
+    }
+
+    #[test]
+    fn public_jwk_has_p256_coordinates() {
+        let pem = generate_private_key_pem().unwrap();
+        let key = parse_signing_key("kid".to_string(), &pem, 0).unwrap();
+        let jwk = key.jwk.unwrap();
+
+        assert_eq!(jwk.alg, "ES256");
+        assert_eq!(BASE64_URL.decode(&jwk.x).unwrap().len(), 32);
+        assert_eq!(BASE64_URL.decode(&jwk.y).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn hmac_keys_are_not_published() {
+        let config = JwtConfig {
+            issuer: "https://tld.com".to_string(),
+            audience: "axumatic".to_string(),
+            algorithm: Algorithm::HS256,
+            access_token_ttl: 900,
+            refresh_token_ttl_in_days: 30,
+            key_rotation_interval_in_days: 30,
+            secret: Some("secret".to_string()),
+        };
+        let key_store = KeyStore::new(&config).unwrap();
+        assert!(key_store.find_decoding_key(HMAC_KEY_ID).is_some());
+        assert!(key_store.keys.read().unwrap()[0].jwk.is_none());
+    }
+
+    #[test]
+    fn unsupported_algorithm_is_rejected() {
+        let config = JwtConfig {
+            issuer: "https://tld.com".to_string(),
+            audience: "axumatic".to_string(),
+            algorithm: Algorithm::RS256,
+            access_token_ttl: 900,
+            refresh_token_ttl_in_days: 30,
+            key_rotation_interval_in_days: 30,
+            secret: None,
+        };
+        assert!(KeyStore::new(&config).is_err());
+    }
+}
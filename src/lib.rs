@@ -4,9 +4,16 @@ use axum::Router;
 use axum::body::Body;
 use axum::extract::Request;
 use axum::response::{IntoResponse, Response};
-use config::AppState;
+use config::{AppState, AuthLevel};
 use http::StatusCode;
-use middleware::ValidateSessionLayer;
+use middleware::{
+    CspNonce, CsrfProtectionLayer, RateLimitLayer, RequireRoleLayer, SecurityHeaders,
+    SecurityHeadersLayer, ValidateSessionLayer,
+};
+use oauth::{AuthorizationCode, AuthorizationRequest};
+use oidc::OidcState;
+use passkey::PasskeyChallenge;
+use rate_limit::TokenBucket;
 use routes::*;
 use rust_embed::Embed;
 use sqlx::migrate;
@@ -16,40 +23,95 @@ use std::{
     sync::{Arc, LazyLock, RwLock},
 };
 use tower::ServiceBuilder;
-use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
+use tower_http::timeout::TimeoutLayer;
 use tracing::{Level, event};
+use two_factor::PendingLogin;
 
+pub mod api_token;
 pub mod auth;
 pub mod config;
+pub mod csrf;
 pub mod custom_route_handlers;
 pub mod default_route_handlers;
+pub mod email_change;
+pub mod github;
+pub mod identity;
+pub mod jwt;
+pub mod keys;
+pub mod lockout;
 pub mod middleware;
+pub mod oauth;
+pub mod oidc;
+pub mod passkey;
+pub mod rate_limit;
+pub mod roles;
 pub mod routes;
+pub mod session_store;
+pub mod two_factor;
 pub mod user;
 pub mod utilities;
 
 static NONCE_STORE: LazyLock<Arc<RwLock<HashMap<String, i64>>>> =
     LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
 
+static PENDING_LOGIN_STORE: LazyLock<Arc<RwLock<HashMap<String, PendingLogin>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+
+static OIDC_STATE_STORE: LazyLock<Arc<RwLock<HashMap<String, OidcState>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+
+static OAUTH_REQUEST_STORE: LazyLock<Arc<RwLock<HashMap<String, AuthorizationRequest>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+
+static OAUTH_CODE_STORE: LazyLock<Arc<RwLock<HashMap<String, AuthorizationCode>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+
+static PASSKEY_CHALLENGE_STORE: LazyLock<Arc<RwLock<HashMap<String, PasskeyChallenge>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+
+static RATE_LIMIT_STORE: LazyLock<Arc<RwLock<HashMap<String, TokenBucket>>>> =
+    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+
 #[derive(Embed)]
 #[folder = "frontend/build"]
 pub struct Asset;
 
 pub fn get_app(state: Arc<AppState>) -> Router {
+    let admin_routes =
+        get_admin_routes().layer(RequireRoleLayer::new(state.clone(), AuthLevel::Admin));
     let protected_routes = get_protected_routes();
     let open_routes = get_open_routes();
 
     Router::new()
+        .merge(admin_routes)
         .merge(protected_routes)
-        .layer(ServiceBuilder::new().layer(ValidateSessionLayer::new(state.clone())))
-        .merge(open_routes)
+        .layer(
+            ServiceBuilder::new()
+                .layer(CsrfProtectionLayer)
+                .layer(ValidateSessionLayer::new(state.clone()))
+                .layer(RateLimitLayer::authenticated(state.clone())),
+        )
+        .merge(
+            open_routes.layer(
+                ServiceBuilder::new()
+                    .layer(RateLimitLayer::new(state.clone()))
+                    .layer(ValidateSessionLayer::optional(state.clone())),
+            ),
+        )
+        // API responses are never rendered so they get a stricter policy than the frontend
+        .layer(SecurityHeadersLayer::new(SecurityHeaders::for_api(
+            &state.config.security_headers,
+        )))
         .fallback(serve_frontend)
         .with_state(state.clone())
         .layer(ServiceBuilder::new().layer(TimeoutLayer::with_status_code(
             StatusCode::REQUEST_TIMEOUT,
             Duration::from_secs(state.config.server.request_timeout),
         )))
-        .layer(ServiceBuilder::new().layer(CorsLayer::very_permissive()))
+        .layer(ServiceBuilder::new().layer(state.config.cors.get_cors_layer()))
+        .layer(SecurityHeadersLayer::new(SecurityHeaders::for_frontend(
+            &state.config.security_headers,
+        )))
 }
 
 async fn serve_frontend(request: Request) -> Response {
@@ -77,10 +139,19 @@ async fn serve_frontend(request: Request) -> Response {
     if let Some(asset) = Asset::get(&path) {
         let mime_type = mime_guess::from_path(&path).first_or_octet_stream();
 
+        // SvelteKit starts the app from an inline script, which only runs if it carries the CSP nonce
+        let body = match request.extensions().get::<CspNonce>() {
+            Some(CspNonce(nonce)) if mime_type == mime_guess::mime::TEXT_HTML => Body::from(
+                String::from_utf8_lossy(&asset.data)
+                    .replace("<script", &format!("<script nonce=\"{nonce}\"")),
+            ),
+            _ => Body::from(asset.data),
+        };
+
         Response::builder()
             .status(StatusCode::OK)
             .header("Content-Type", mime_type.as_ref())
-            .body(Body::from(asset.data))
+            .body(body)
             .unwrap_or("<h1>404 - Not found</h1>".into_response())
     } else {
         event!(Level::WARN, "Frontend file not found: {}", path);
@@ -103,10 +174,27 @@ pub async fn get_app_state() -> Arc<AppState> {
     event!(Level::INFO, "Creating database connection pool");
     let db_connection_pool = config.get_db_pool().await;
 
+    // Persisted keys are loaded on first use as the migrations may not have run yet
+    let key_store = config.jwt.as_ref().map(|jwt_config| {
+        Arc::new(keys::KeyStore::new(jwt_config).expect("Unable to create JWT key store"))
+    });
+
+    let session_store: Arc<dyn session_store::SessionStore> = match config.server.session_store {
+        config::SessionStoreBackend::Postgres => Arc::new(
+            session_store::PostgresSessionStore::new(db_connection_pool.clone()),
+        ),
+        config::SessionStoreBackend::Memory => {
+            Arc::new(session_store::MemorySessionStore::default())
+        }
+    };
+
     Arc::new(AppState {
         db_connection_pool,
         email_connection_pool,
         config,
+        http_client: reqwest::Client::new(),
+        key_store,
+        session_store,
     })
 }
 
@@ -0,0 +1,231 @@
+use crate::AppState;
+use crate::config::ServerConfig;
+use crate::default_route_handlers::ErrorList;
+use crate::user::User;
+use crate::utilities::{Email, send_email};
+use chrono::DateTime;
+use std::sync::Arc;
+use tracing::{Level, event};
+
+const SECONDS_IN_MINUTE: i64 = 60;
+
+#[derive(Debug, PartialEq)]
+pub enum LoginThrottle {
+    Allowed,
+    // Seconds until the next attempt is allowed
+    Throttled(i64),
+    // When the account unlocks
+    Locked(i64),
+}
+
+// Failed attempts which still count, a lock which has expired or predates lock timestamps starts the count again
+fn get_current_attempts(config: &ServerConfig, user: &User, now: i64) -> i32 {
+    let lock_expired = user.locked_until_ts.is_some_and(|until| until <= now);
+    let legacy_lock = user.locked_until_ts.is_none()
+        && user.login_attempts >= config.max_unsuccessful_login_attempts;
+
+    if lock_expired || legacy_lock {
+        0
+    } else {
+        user.login_attempts
+    }
+}
+
+// Doubles with each failed attempt, up to the configured maximum
+fn get_backoff_in_seconds(config: &ServerConfig, attempts: i32) -> i64 {
+    if attempts <= 0 || config.login_backoff_base_in_seconds <= 0 {
+        return 0;
+    }
+
+    let multiplier = 2_i64.saturating_pow((attempts - 1).min(62) as u32);
+    config
+        .login_backoff_base_in_seconds
+        .saturating_mul(multiplier)
+        .min(config.login_backoff_max_in_seconds)
+}
+
+pub fn get_login_throttle(config: &ServerConfig, user: &User, now: i64) -> LoginThrottle {
+    if let Some(until) = user.locked_until_ts
+        && until > now
+    {
+        return LoginThrottle::Locked(until);
+    }
+
+    let attempts = get_current_attempts(config, user, now);
+    if let Some(last_failed) = user.last_failed_login_ts {
+        let next_attempt = last_failed + get_backoff_in_seconds(config, attempts);
+        if attempts > 0 && next_attempt > now {
+            return LoginThrottle::Throttled(next_attempt - now);
+        }
+    }
+
+    LoginThrottle::Allowed
+}
+
+pub fn check_login_allowed(config: &ServerConfig, user: &User, now: i64) -> Result<(), ErrorList> {
+    match get_login_throttle(config, user, now) {
+        LoginThrottle::Allowed => Ok(()),
+        LoginThrottle::Throttled(_) => Err(ErrorList::LoginThrottled),
+        LoginThrottle::Locked(_) => {
+            event!(Level::WARN, "Attempt to log in to a locked account");
+            Err(ErrorList::TooManyLoginAttempts)
+        }
+    }
+}
+
+// Counts a failed password or second factor, locking the account once it reaches the maximum. The count
+// is incremented in the database, restarting it as get_current_attempts does, so concurrent failures all count
+pub async fn record_failed_login(
+    state: Arc<AppState>,
+    user: &User,
+    now: i64,
+) -> Result<(), anyhow::Error> {
+    let config = &state.config.server;
+
+    // SET sees the row as it was, so the lock repeats the count's expression to decide in the same statement
+    let locked_until = now + config.login_lockout_in_minutes * SECONDS_IN_MINUTE;
+    let attempts = sqlx::query!(
+        r#"UPDATE users SET
+            login_attempts = CASE
+                WHEN locked_until_ts <= $1 OR (locked_until_ts IS NULL AND login_attempts >= $2) THEN 1
+                ELSE COALESCE(login_attempts, 0) + 1
+            END,
+            last_failed_login_ts = $1,
+            locked_until_ts = CASE
+                WHEN CASE
+                    WHEN locked_until_ts <= $1 OR (locked_until_ts IS NULL AND login_attempts >= $2) THEN 1
+                    ELSE COALESCE(login_attempts, 0) + 1
+                END >= $2 THEN $4
+                WHEN locked_until_ts > $1 THEN locked_until_ts
+            END
+        WHERE id = $3
+        RETURNING login_attempts as "login_attempts!""#,
+        now,
+        config.max_unsuccessful_login_attempts,
+        user.id,
+        locked_until
+    )
+    .fetch_one(&state.db_connection_pool)
+    .await?
+    .login_attempts;
+
+    if attempts >= config.max_unsuccessful_login_attempts {
+        event!(Level::WARN, "Account locked due to too many login attempts");
+        // Sent after the response so the email doesn't slow down the failed attempt
+        let user = user.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_lockout_email(state, &user, attempts, locked_until).await {
+                event!(Level::ERROR, "Failed to send lockout email due to {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn send_lockout_email(
+    state: Arc<AppState>,
+    user: &User,
+    attempts: i32,
+    locked_until: i64,
+) -> Result<(), anyhow::Error> {
+    let to = format!("{} <{}>", user.username, user.email);
+    let unlock_time = DateTime::from_timestamp(locked_until, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default();
+
+    let email = Email {
+        to: to.as_str(),
+        from: "registration@tld.com",
+        subject: String::from("Your account has been locked"),
+        body: format!(
+            "<p>There have been {attempts} unsuccessful attempts to log in to your account, so it has been locked until {unlock_time}.</p> \
+            <p>If this wasn't you, someone may be trying to guess your password and you should reset it.</p>"
+        ),
+        reply_to: None,
+    };
+    send_email(state, email).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn config() -> ServerConfig {
+        toml::from_str(
+            r#"
+            port = 3000
+            request_timeout = 5
+            max_unsuccessful_login_attempts = 5
+            session_length_in_days = 1
+            google_client_id = ""
+            totp_issuer = ""
+            frontend_url = ""
+            passwordless_login_enabled = false
+            login_backoff_base_in_seconds = 2
+            login_backoff_max_in_seconds = 10
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn user(login_attempts: i32, last_failed: Option<i64>, locked_until: Option<i64>) -> User {
+        User {
+            id: Uuid::from_u128(1),
+            username: "user".to_string(),
+            email: "user@tld.com".to_string(),
+            email_verified: true,
+            hashed_password: None,
+            auth_level: "user".to_string(),
+            login_attempts,
+            last_failed_login_ts: last_failed,
+            locked_until_ts: locked_until,
+            registration_ts: 0,
+            identity_provider: "default".to_string(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = config();
+        assert_eq!(get_backoff_in_seconds(&config, 0), 0);
+        assert_eq!(get_backoff_in_seconds(&config, 1), 2);
+        assert_eq!(get_backoff_in_seconds(&config, 3), 8);
+        assert_eq!(get_backoff_in_seconds(&config, 4), 10);
+        assert_eq!(get_backoff_in_seconds(&config, 100), 10);
+    }
+
+    #[test]
+    fn failed_attempts_are_throttled() {
+        let config = config();
+        assert_eq!(
+            get_login_throttle(&config, &user(0, None, None), 100),
+            LoginThrottle::Allowed
+        );
+        assert_eq!(
+            get_login_throttle(&config, &user(2, Some(100), None), 101),
+            LoginThrottle::Throttled(3)
+        );
+        assert_eq!(
+            get_login_throttle(&config, &user(2, Some(100), None), 104),
+            LoginThrottle::Allowed
+        );
+    }
+
+    #[test]
+    fn lock_expires_after_cool_down() {
+        let config = config();
+        let locked = user(5, Some(100), Some(1000));
+        assert_eq!(
+            get_login_throttle(&config, &locked, 999),
+            LoginThrottle::Locked(1000)
+        );
+        assert_eq!(
+            get_login_throttle(&config, &locked, 1000),
+            LoginThrottle::Allowed
+        );
+        assert_eq!(get_current_attempts(&config, &locked, 1000), 0);
+        assert_eq!(get_current_attempts(&config, &user(5, None, None), 0), 0);
+    }
+}
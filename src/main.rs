@@ -1,6 +1,9 @@
 #![warn(unused_extern_crates)]
 
-use axumatic::{get_app, get_app_state, migrations, utilities::start_session_cleaner};
+use axumatic::{
+    get_app, get_app_state, keys::start_key_rotation, migrations, utilities::start_session_cleaner,
+};
+use std::net::SocketAddr;
 use tracing::{Level, event, span};
 
 #[tokio::main]
@@ -22,11 +25,17 @@ async fn main() -> Result<(), anyhow::Error> {
         .await
         .expect("Couldn't complete migrations");
 
+    start_key_rotation(app_state.clone()).await;
+
     let app = get_app(app_state.clone());
 
     let listener =
         tokio::net::TcpListener::bind(("127.0.0.1", app_state.config.server.port)).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -1,26 +1,63 @@
 use axum::{
-    extract::Request,
+    Json,
+    body::{Body, to_bytes},
+    extract::{ConnectInfo, MatchedPath, Request},
     response::{IntoResponse, Response},
 };
+use chrono::Utc;
 use futures_util::future::BoxFuture;
-use http::HeaderValue;
+use http::{
+    HeaderName, HeaderValue,
+    header::{
+        CONTENT_SECURITY_POLICY, REFERRER_POLICY, RETRY_AFTER, SET_COOKIE,
+        STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+    },
+};
 use std::{
+    net::SocketAddr,
     sync::Arc,
     task::{Context, Poll},
 };
 use tower::{Layer, Service};
 use tracing::{Level, event};
 
-use crate::{AppState, auth::validate_cookie};
+use crate::{
+    AppState,
+    api_token::{SCOPE_ADMIN, TokenScopes, get_bearer_token, is_api_token, validate_api_token},
+    auth::{AuthContext, ValidatedSession, get_session_key, session_cookie, validate_session},
+    config::SecurityHeadersConfig,
+    csrf::{is_request_trusted, is_safe_method},
+    default_route_handlers::{ApiResponse, ErrorList, ResponseType},
+    jwt::validate_access_token,
+    rate_limit::{check_rate_limit, get_bucket_keys, get_client_ip},
+    roles::{user_has_permission, user_has_role},
+    user::get_user_by_id,
+    utilities::generate_unique_id,
+};
 
+// Authenticates the request and inserts an AuthContext into its extensions, along with the User unless
+// an access token was used as those are checked without the database. Required layers reject requests
+// which aren't authenticated, optional ones let them through without an AuthContext
 #[derive(Clone)]
 pub struct ValidateSessionLayer {
     pub state: Arc<AppState>,
+    pub optional: bool,
 }
 
 impl ValidateSessionLayer {
     pub fn new(state: Arc<AppState>) -> Self {
-        Self { state }
+        Self {
+            state,
+            optional: false,
+        }
+    }
+
+    // For open routes, so handlers can take an Option<User>
+    pub fn optional(state: Arc<AppState>) -> Self {
+        Self {
+            state,
+            optional: true,
+        }
     }
 }
 
@@ -31,6 +68,7 @@ impl<S> Layer<S> for ValidateSessionLayer {
         ValidateSession {
             inner,
             state: self.state.clone(),
+            optional: self.optional,
         }
     }
 }
@@ -39,6 +77,83 @@ impl<S> Layer<S> for ValidateSessionLayer {
 pub struct ValidateSession<S> {
     pub inner: S,
     pub state: Arc<AppState>,
+    pub optional: bool,
+}
+
+// Inserts the extensions for whichever credentials the request carries. The session is returned so
+// its cookie can be refreshed, the error is the status to reject the request with and why
+async fn authenticate(
+    request: &mut Request,
+    state: Arc<AppState>,
+) -> Result<Option<ValidatedSession>, (http::StatusCode, &'static str)> {
+    // JWT access tokens are checked without the database and act like a session
+    if let Some(token) = get_bearer_token(request.headers()).filter(|token| !is_api_token(token)) {
+        let claims = validate_access_token(&token, &state).await.map_err(|e| {
+            event!(Level::INFO, "Invalid access token: {}", e);
+            (http::StatusCode::UNAUTHORIZED, "Invalid access token")
+        })?;
+        request.extensions_mut().insert(AuthContext {
+            user_id: claims.sub,
+            session_id: None,
+            auth_level: claims.auth_level.clone(),
+        });
+        request.extensions_mut().insert(claims);
+        return Ok(None);
+    }
+
+    // API tokens take precedence over the session cookie and are limited by their scopes
+    if let Some(token) = get_bearer_token(request.headers()) {
+        let (user_id, scopes) = validate_api_token(&token, state.clone())
+            .await
+            .map_err(|_e| {
+                (
+                    http::StatusCode::UNAUTHORIZED,
+                    "Attempt to access protected route with invalid API token",
+                )
+            })?;
+        if !scopes.allows_method(request.method()) {
+            return Err((
+                http::StatusCode::FORBIDDEN,
+                "Attempt to use API token without the required scope",
+            ));
+        }
+        let user = get_user_by_id(state, user_id).await.map_err(|_e| {
+            (
+                http::StatusCode::UNAUTHORIZED,
+                "API token belongs to a user which doesn't exist",
+            )
+        })?;
+        request.extensions_mut().insert(AuthContext {
+            user_id,
+            session_id: None,
+            auth_level: user.auth_level.clone(),
+        });
+        request.extensions_mut().insert(user);
+        request.extensions_mut().insert(scopes);
+        return Ok(None);
+    }
+
+    let session = validate_session(request.headers(), state.clone())
+        .await
+        .map_err(|_e| {
+            (
+                http::StatusCode::UNAUTHORIZED,
+                "Attempt to access protected route without valid session",
+            )
+        })?;
+    let user = get_user_by_id(state, session.user_id).await.map_err(|_e| {
+        (
+            http::StatusCode::UNAUTHORIZED,
+            "Session belongs to a user which doesn't exist",
+        )
+    })?;
+    request.extensions_mut().insert(AuthContext {
+        user_id: user.id,
+        session_id: Some(session.id),
+        auth_level: user.auth_level.clone(),
+    });
+    request.extensions_mut().insert(user);
+    Ok(Some(session))
 }
 
 impl<S> Service<Request> for ValidateSession<S>
@@ -58,27 +173,504 @@ where
     fn call(&mut self, mut request: Request) -> Self::Future {
         let mut inner = self.inner.clone();
         let state = self.state.clone();
+        let optional = self.optional;
 
         Box::pin(async move {
-            let response: Response = match validate_cookie(request.headers(), state).await {
-                Ok(email) => {
-                    request.headers_mut().insert(
-                        "email",
-                        HeaderValue::from_str(&email).expect("Unable to set email as header"),
+            let has_credentials = get_bearer_token(request.headers()).is_some()
+                || get_session_key(request.headers()).is_some();
+            if optional && !has_credentials {
+                return inner.call(request).await;
+            }
+
+            let session = match authenticate(&mut request, state).await {
+                Ok(session) => session,
+                Err(_) if optional => return inner.call(request).await,
+                Err((status, message)) => {
+                    event!(Level::WARN, "{}", message);
+                    return Ok(status.into_response());
+                }
+            };
+
+            let mut response = inner.call(request).await?;
+            // The cookie's max-age follows the session's new expiry, unless the handler replaced it
+            if let Some(session) = session {
+                let sets_session_cookie = response
+                    .headers()
+                    .get_all(SET_COOKIE)
+                    .iter()
+                    .any(|value| value.as_bytes().starts_with(b"session-key="));
+                if session.extended && !sets_session_cookie {
+                    let now = Utc::now().timestamp();
+                    let cookie = session_cookie(session.session_key, session.expiry - now);
+                    response.headers_mut().append(
+                        SET_COOKIE,
+                        HeaderValue::from_str(&cookie.to_string())
+                            .expect("Unable to set cookie header"),
                     );
+                }
+            }
+            Ok(response)
+        })
+    }
+}
+
+// Rejects state-changing requests authenticated by the session cookie unless they carry the CSRF token.
+// Bearer tokens aren't sent automatically by the browser so those requests aren't checked
+#[derive(Clone)]
+pub struct CsrfProtectionLayer;
+
+impl<S> Layer<S> for CsrfProtectionLayer {
+    type Service = CsrfProtection<S>;
 
-                    let future = inner.call(request);
-                    future.await?
+    fn layer(&self, inner: S) -> Self::Service {
+        CsrfProtection { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct CsrfProtection<S> {
+    pub inner: S,
+}
+
+impl<S> Service<Request> for CsrfProtection<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut inner = self.inner.clone();
+
+        Box::pin(async move {
+            let headers = request.headers();
+            let uses_session_cookie =
+                get_bearer_token(headers).is_none() && get_session_key(headers).is_some();
+
+            if uses_session_cookie
+                && !is_safe_method(request.method())
+                && !is_request_trusted(headers)
+            {
+                event!(
+                    Level::WARN,
+                    "Rejected state-changing request without a valid CSRF token"
+                );
+                return Ok(http::StatusCode::FORBIDDEN.into_response());
+            }
+
+            inner.call(request).await
+        })
+    }
+}
+
+// What the user's role must satisfy to access routes behind an authorisation layer
+#[derive(Clone)]
+pub enum Requirement {
+    Role(String),
+    Permission(String),
+}
+
+// Must be applied inside ValidateSessionLayer as it relies on the AuthContext extension
+#[derive(Clone)]
+pub struct RequireRoleLayer {
+    pub state: Arc<AppState>,
+    pub requirement: Requirement,
+}
+
+impl RequireRoleLayer {
+    pub fn new(state: Arc<AppState>, role: impl Into<String>) -> Self {
+        Self {
+            state,
+            requirement: Requirement::Role(role.into()),
+        }
+    }
+
+    pub fn with_permission(state: Arc<AppState>, permission: impl Into<String>) -> Self {
+        Self {
+            state,
+            requirement: Requirement::Permission(permission.into()),
+        }
+    }
+}
+
+impl<S> Layer<S> for RequireRoleLayer {
+    type Service = RequireRole<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireRole {
+            inner,
+            state: self.state.clone(),
+            requirement: self.requirement.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireRole<S> {
+    pub inner: S,
+    pub state: Arc<AppState>,
+    pub requirement: Requirement,
+}
+
+impl<S> Service<Request> for RequireRole<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let state = self.state.clone();
+        let requirement = self.requirement.clone();
+
+        Box::pin(async move {
+            let Some(user_id) = request
+                .extensions()
+                .get::<AuthContext>()
+                .map(|context| context.user_id)
+            else {
+                return Ok(http::StatusCode::UNAUTHORIZED.into_response());
+            };
+
+            // Requests made with an API token also need the token to carry the admin scope
+            if request
+                .extensions()
+                .get::<TokenScopes>()
+                .is_some_and(|scopes| !scopes.contains(SCOPE_ADMIN))
+            {
+                event!(
+                    Level::WARN,
+                    "Attempt to use API token without the admin scope on a restricted route"
+                );
+                return Ok(http::StatusCode::FORBIDDEN.into_response());
+            }
+
+            let authorised = match &requirement {
+                Requirement::Role(role) => user_has_role(state, user_id, role).await,
+                Requirement::Permission(permission) => {
+                    user_has_permission(state, user_id, permission).await
                 }
-                _ => {
+            };
+
+            match authorised {
+                Ok(true) => inner.call(request).await,
+                Ok(false) => {
                     event!(
                         Level::WARN,
-                        "Attempt to access protected route without valid session"
+                        "Attempt to access route without the required role"
                     );
-                    http::StatusCode::UNAUTHORIZED.into_response()
+                    Ok(http::StatusCode::FORBIDDEN.into_response())
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "Unable to check user's role: {}", e);
+                    Ok(http::StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                }
+            }
+        })
+    }
+}
+
+const NONCE_PLACEHOLDER: &str = "{nonce}";
+const CSP_NONCE_LENGTH: u8 = 24;
+
+// The nonce in the response's Content-Security-Policy, for pages which need to mark their inline scripts
+#[derive(Clone, Debug)]
+pub struct CspNonce(pub String);
+
+// The header values set by a SecurityHeadersLayer, None leaves the header out
+#[derive(Clone, Debug, Default)]
+pub struct SecurityHeaders {
+    pub strict_transport_security: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    (!value.trim().is_empty()).then(|| value.trim().to_string())
+}
+
+impl SecurityHeaders {
+    fn from_config(config: &SecurityHeadersConfig, content_security_policy: &str) -> Self {
+        let strict_transport_security = (config.hsts_max_age_in_seconds > 0).then(|| {
+            let mut value = format!("max-age={}", config.hsts_max_age_in_seconds);
+            if config.hsts_include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            value
+        });
+
+        let mut directives: Vec<String> = non_empty(content_security_policy).into_iter().collect();
+        if let Some(frame_ancestors) = non_empty(&config.frame_ancestors)
+            && !content_security_policy.contains("frame-ancestors")
+        {
+            directives.push(format!("frame-ancestors {frame_ancestors}"));
+        }
+
+        Self {
+            strict_transport_security,
+            content_security_policy: non_empty(&directives.join("; ")),
+            referrer_policy: non_empty(&config.referrer_policy),
+            permissions_policy: non_empty(&config.permissions_policy),
+        }
+    }
+
+    pub fn for_frontend(config: &SecurityHeadersConfig) -> Self {
+        Self::from_config(config, &config.content_security_policy)
+    }
+
+    pub fn for_api(config: &SecurityHeadersConfig) -> Self {
+        Self::from_config(config, &config.api_content_security_policy)
+    }
+}
+
+// Headers already on the response are left alone, so a layer applied to a group of routes overrides an outer one
+#[derive(Clone)]
+pub struct SecurityHeadersLayer {
+    pub headers: Arc<SecurityHeaders>,
+}
+
+impl SecurityHeadersLayer {
+    pub fn new(headers: SecurityHeaders) -> Self {
+        Self {
+            headers: Arc::new(headers),
+        }
+    }
+}
+
+impl<S> Layer<S> for SecurityHeadersLayer {
+    type Service = SecurityHeadersService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SecurityHeadersService {
+            inner,
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SecurityHeadersService<S> {
+    pub inner: S,
+    pub headers: Arc<SecurityHeaders>,
+}
+
+impl<S> Service<Request> for SecurityHeadersService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let headers = self.headers.clone();
+
+        Box::pin(async move {
+            let content_security_policy = match &headers.content_security_policy {
+                Some(policy) if policy.contains(NONCE_PLACEHOLDER) => {
+                    let nonce = generate_unique_id(CSP_NONCE_LENGTH);
+                    request.extensions_mut().insert(CspNonce(nonce.clone()));
+                    Some(policy.replace(NONCE_PLACEHOLDER, &nonce))
                 }
+                policy => policy.clone(),
             };
+
+            let mut response = inner.call(request).await?;
+            let response_headers = response.headers_mut();
+
+            let values = [
+                (
+                    STRICT_TRANSPORT_SECURITY,
+                    headers.strict_transport_security.clone(),
+                ),
+                (CONTENT_SECURITY_POLICY, content_security_policy),
+                (X_CONTENT_TYPE_OPTIONS, Some("nosniff".to_string())),
+                (REFERRER_POLICY, headers.referrer_policy.clone()),
+                (
+                    HeaderName::from_static("permissions-policy"),
+                    headers.permissions_policy.clone(),
+                ),
+            ];
+            for (name, value) in values {
+                if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+                    response_headers.entry(name).or_insert(value);
+                }
+            }
+
             Ok(response)
         })
     }
 }
+
+// Bodies are only read to find the account being targeted, anything larger isn't a valid request
+const MAX_RATE_LIMITED_BODY_SIZE: usize = 65536;
+
+// Applies the configured rate limit policies for the matched route. Buckets are kept in memory so each
+// instance of the app counts separately. On protected routes the layer must be applied inside
+// ValidateSessionLayer, the account is then the signed in user's id rather than the email in the body
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    pub state: Arc<AppState>,
+    pub authenticated: bool,
+}
+
+impl RateLimitLayer {
+    pub fn new(state: Arc<AppState>) -> Self {
+        Self {
+            state,
+            authenticated: false,
+        }
+    }
+
+    pub fn authenticated(state: Arc<AppState>) -> Self {
+        Self {
+            state,
+            authenticated: true,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            state: self.state.clone(),
+            authenticated: self.authenticated,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    pub inner: S,
+    pub state: Arc<AppState>,
+    pub authenticated: bool,
+}
+
+fn too_many_requests(retry_after: u64) -> Response {
+    (
+        http::StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.to_string())],
+        Json(ApiResponse {
+            response_type: ResponseType::Error,
+            message: ErrorList::TooManyRequests.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static + Clone,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let state = self.state.clone();
+        let authenticated = self.authenticated;
+
+        Box::pin(async move {
+            let config = &state.config.rate_limits;
+            let path = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str().to_string())
+                .unwrap_or_else(|| request.uri().path().to_string());
+            let policies: Vec<_> = config
+                .policies
+                .iter()
+                .filter(|policy| {
+                    policy.path == path
+                        && policy
+                            .method
+                            .eq_ignore_ascii_case(request.method().as_str())
+                })
+                .collect();
+
+            if policies.is_empty() {
+                return inner.call(request).await;
+            }
+
+            let peer = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| address.ip());
+            let client_ip = get_client_ip(peer, request.headers(), &config.trusted_proxies);
+
+            let account = if authenticated {
+                request
+                    .extensions()
+                    .get::<AuthContext>()
+                    .map(|context| context.user_id.to_string())
+            } else {
+                let (parts, body) = request.into_parts();
+                let Ok(bytes) = to_bytes(body, MAX_RATE_LIMITED_BODY_SIZE).await else {
+                    return Ok(http::StatusCode::PAYLOAD_TOO_LARGE.into_response());
+                };
+                // Either an email field or, as with password resets, a body which is just the email
+                let account = serde_json::from_slice::<serde_json::Value>(&bytes)
+                    .ok()
+                    .and_then(|body| {
+                        body.get("email")
+                            .unwrap_or(&body)
+                            .as_str()
+                            .map(|email| email.trim().to_lowercase())
+                    });
+                request = Request::from_parts(parts, Body::from(bytes));
+                account
+            };
+
+            let now = Utc::now().timestamp_millis() as f64 / 1000.0;
+            let buckets: Vec<_> = policies
+                .into_iter()
+                .map(|policy| {
+                    (
+                        policy,
+                        get_bucket_keys(policy, client_ip, account.as_deref()),
+                    )
+                })
+                .collect();
+            if let Err(retry_after) = check_rate_limit(&buckets, now) {
+                event!(
+                    Level::WARN,
+                    "Rate limit exceeded for {} {}",
+                    request.method(),
+                    path
+                );
+                return Ok(too_many_requests(retry_after));
+            }
+
+            inner.call(request).await
+        })
+    }
+}
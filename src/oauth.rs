@@ -0,0 +1,795 @@
+use crate::default_route_handlers::ErrorList;
+use crate::jwt::{
+    get_jwt_config, revoke_refresh_token_family, sign_claims, store_refresh_token,
+    use_refresh_token, verify_token,
+};
+use crate::oidc::pkce_challenge;
+use crate::user::{Profile, User, get_user_by_id};
+use crate::utilities::{generate_unique_id, hash_token};
+use crate::{AppState, OAUTH_CODE_STORE, OAUTH_REQUEST_STORE};
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::Utc;
+use http::{HeaderMap, StatusCode, header::AUTHORIZATION};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const SCOPE_EMAIL: &str = "email";
+const SUPPORTED_SCOPES: [&str; 3] = [SCOPE_OPENID, SCOPE_PROFILE, SCOPE_EMAIL];
+
+const CLIENT_ID_LENGTH: u8 = 24;
+const CLIENT_SECRET_LENGTH: u8 = 48;
+const AUTHORIZATION_CODE_LENGTH: u8 = 40;
+// Users have this long to sign in and give their consent
+const AUTHORIZATION_REQUEST_EXPIRATION: i64 = 600;
+const AUTHORIZATION_CODE_EXPIRATION: i64 = 60;
+
+// Errors from the token, revocation and userinfo endpoints use the format clients expect from RFC 6749
+#[derive(Debug)]
+pub enum OAuthError {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    UnsupportedGrantType,
+    InvalidToken,
+    ServerError,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+}
+
+impl OAuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            OAuthError::InvalidRequest => "invalid_request",
+            OAuthError::InvalidClient => "invalid_client",
+            OAuthError::InvalidGrant => "invalid_grant",
+            OAuthError::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthError::InvalidToken => "invalid_token",
+            OAuthError::ServerError => "server_error",
+        }
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            OAuthError::InvalidClient | OAuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+            OAuthError::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let body = OAuthErrorResponse {
+            error: self.code().to_string(),
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl<E> From<E> for OAuthError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        event!(Level::ERROR, "OAuth request failed due to {}", err.into());
+        OAuthError::ServerError
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    // Trusted clients are first-party apps which don't need the user's consent
+    pub trusted: bool,
+    // Confidential clients have a secret, public clients such as mobile apps rely on PKCE alone
+    pub confidential: bool,
+    pub created_ts: i64,
+}
+
+// The secret is only ever returned here, when the client is created
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreatedOAuthClient {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthorizationRequest {
+    pub client_id: String,
+    pub client_name: String,
+    pub trusted: bool,
+    pub redirect_uri: String,
+    pub scope: Vec<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub created_ts: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthorizationCode {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub user_id: Uuid,
+    pub scope: Vec<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub created_ts: i64,
+}
+
+// What the consent screen shows the user
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuthorizationRequestDetails {
+    pub client_name: String,
+    pub scope: Vec<String>,
+    pub trusted: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthorizationParameters {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub response_type: Option<String>,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientAccessTokenClaims {
+    pub iss: String,
+    pub sub: Uuid,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    pub jti: String,
+    pub client_id: String,
+    pub scope: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub revocation_endpoint: String,
+    pub jwks_uri: String,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+}
+
+fn to_strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+// The issuer is expected to be the URL axumatic is served from
+pub fn get_openid_configuration(state: &AppState) -> Result<OpenIdConfiguration, ErrorList> {
+    let (config, _key_store) = get_jwt_config(state)?;
+    let base_url = config.issuer.trim_end_matches('/');
+
+    Ok(OpenIdConfiguration {
+        issuer: config.issuer.clone(),
+        authorization_endpoint: format!("{}/oauth/authorize", base_url),
+        token_endpoint: format!("{}/oauth/token", base_url),
+        userinfo_endpoint: format!("{}/oauth/userinfo", base_url),
+        revocation_endpoint: format!("{}/oauth/revoke", base_url),
+        jwks_uri: format!("{}/.well-known/jwks.json", base_url),
+        response_types_supported: to_strings(&["code"]),
+        grant_types_supported: to_strings(&["authorization_code", "refresh_token"]),
+        subject_types_supported: to_strings(&["public"]),
+        id_token_signing_alg_values_supported: vec![format!("{:?}", config.algorithm)],
+        scopes_supported: to_strings(&SUPPORTED_SCOPES),
+        token_endpoint_auth_methods_supported: to_strings(&[
+            "client_secret_basic",
+            "client_secret_post",
+            "none",
+        ]),
+        code_challenge_methods_supported: to_strings(&["S256"]),
+        claims_supported: to_strings(&[
+            "iss",
+            "sub",
+            "aud",
+            "exp",
+            "iat",
+            "nonce",
+            "email",
+            "email_verified",
+            "preferred_username",
+        ]),
+    })
+}
+
+// Redirect URIs must use https, apart from loopback addresses used by native apps
+pub fn validate_redirect_uri(redirect_uri: &str) -> Result<bool, ErrorList> {
+    let Ok(url) = Url::parse(redirect_uri) else {
+        return Err(ErrorList::InvalidRedirectUri);
+    };
+    let loopback = matches!(
+        url.host_str(),
+        Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+    );
+    if url.fragment().is_none() && (url.scheme() == "https" || url.scheme() == "http" && loopback) {
+        return Ok(true);
+    }
+    Err(ErrorList::InvalidRedirectUri)
+}
+
+pub fn validate_client_scopes(scopes: &[String]) -> Result<bool, ErrorList> {
+    if !scopes.is_empty()
+        && scopes
+            .iter()
+            .all(|scope| SUPPORTED_SCOPES.contains(&scope.as_str()))
+    {
+        return Ok(true);
+    }
+    Err(ErrorList::InvalidOAuthScopes)
+}
+
+// Every requested scope must be allowed for the client, an empty scope requests all of them
+pub fn parse_scope(scope: Option<&str>, allowed_scopes: &[String]) -> Option<Vec<String>> {
+    let mut scopes: Vec<String> = match scope {
+        Some(scope) if !scope.trim().is_empty() => {
+            scope.split_whitespace().map(|s| s.to_string()).collect()
+        }
+        _ => allowed_scopes.to_vec(),
+    };
+    scopes.sort();
+    scopes.dedup();
+    scopes
+        .iter()
+        .all(|scope| allowed_scopes.contains(scope))
+        .then_some(scopes)
+}
+
+pub async fn create_client(
+    state: &AppState,
+    name: &str,
+    redirect_uris: &[String],
+    allowed_scopes: &[String],
+    trusted: bool,
+    confidential: bool,
+) -> Result<CreatedOAuthClient, anyhow::Error> {
+    let client_id = generate_unique_id(CLIENT_ID_LENGTH);
+    let client_secret = confidential.then(|| generate_unique_id(CLIENT_SECRET_LENGTH));
+
+    sqlx::query!(
+        "INSERT INTO oauth_clients (client_id, name, hashed_client_secret, redirect_uris, allowed_scopes, trusted, created_ts) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        &client_id,
+        name.trim(),
+        client_secret.as_deref().map(hash_token),
+        redirect_uris,
+        allowed_scopes,
+        trusted,
+        Utc::now().timestamp()
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(CreatedOAuthClient {
+        client_id,
+        client_secret,
+    })
+}
+
+pub async fn get_clients(state: &AppState) -> Result<Vec<OAuthClient>, anyhow::Error> {
+    let clients = sqlx::query_as!(
+        OAuthClient,
+        r#"SELECT
+            client_id as "client_id!",
+            name as "name!",
+            redirect_uris as "redirect_uris!",
+            allowed_scopes as "allowed_scopes!",
+            trusted as "trusted!",
+            hashed_client_secret IS NOT NULL as "confidential!",
+            created_ts as "created_ts!"
+        FROM oauth_clients ORDER BY created_ts"#
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    Ok(clients)
+}
+
+pub async fn delete_client(state: &AppState, client_id: &str) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!("DELETE FROM oauth_clients WHERE client_id = $1", client_id)
+        .execute(&state.db_connection_pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn get_client_with_secret(
+    state: &AppState,
+    client_id: &str,
+) -> Result<Option<(OAuthClient, Option<String>)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT
+            client_id as "client_id!",
+            name as "name!",
+            hashed_client_secret,
+            redirect_uris as "redirect_uris!",
+            allowed_scopes as "allowed_scopes!",
+            trusted as "trusted!",
+            created_ts as "created_ts!"
+        FROM oauth_clients WHERE client_id = $1"#,
+        client_id
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    Ok(row.map(|row| {
+        let client = OAuthClient {
+            client_id: row.client_id,
+            name: row.name,
+            redirect_uris: row.redirect_uris,
+            allowed_scopes: row.allowed_scopes,
+            trusted: row.trusted,
+            confidential: row.hashed_client_secret.is_some(),
+            created_ts: row.created_ts,
+        };
+        (client, row.hashed_client_secret)
+    }))
+}
+
+// Credentials can be sent with HTTP Basic authentication or in the form body
+pub fn get_client_credentials(
+    headers: &HeaderMap,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+) -> Option<(String, Option<String>)> {
+    let basic = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|value| STANDARD.decode(value).ok())
+        .and_then(|value| String::from_utf8(value).ok());
+
+    if let Some(basic) = basic {
+        let (id, secret) = basic.split_once(':')?;
+        let id = urlencoding::decode(id).ok()?.into_owned();
+        let secret = urlencoding::decode(secret).ok()?.into_owned();
+        return Some((id, Some(secret)));
+    }
+    client_id.map(|id| (id, client_secret))
+}
+
+pub async fn authenticate_client(
+    state: &AppState,
+    client_id: &str,
+    client_secret: Option<&str>,
+) -> Result<OAuthClient, OAuthError> {
+    let Some((client, hashed_client_secret)) = get_client_with_secret(state, client_id).await?
+    else {
+        return Err(OAuthError::InvalidClient);
+    };
+
+    if let Some(hashed_client_secret) = hashed_client_secret
+        && client_secret.map(hash_token) != Some(hashed_client_secret)
+    {
+        event!(
+            Level::WARN,
+            "OAuth client {} failed to authenticate",
+            client_id
+        );
+        return Err(OAuthError::InvalidClient);
+    }
+    Ok(client)
+}
+
+fn error_redirect(
+    redirect_uri: &str,
+    error: &str,
+    state: Option<&str>,
+) -> Result<Url, anyhow::Error> {
+    let mut url = Url::parse(redirect_uri)?;
+    url.query_pairs_mut().append_pair("error", error);
+    if let Some(state) = state {
+        url.query_pairs_mut().append_pair("state", state);
+    }
+    Ok(url)
+}
+
+// An unknown client or redirect URI is an error for the user, anything else is sent back to the client
+pub async fn validate_authorization_request(
+    state: &AppState,
+    parameters: AuthorizationParameters,
+) -> Result<Result<AuthorizationRequest, Url>, anyhow::Error> {
+    get_jwt_config(state)?;
+    let Some((client, _)) = get_client_with_secret(state, &parameters.client_id).await? else {
+        return Err(ErrorList::InvalidOAuthClient.into());
+    };
+    if !client.redirect_uris.contains(&parameters.redirect_uri) {
+        return Err(ErrorList::InvalidOAuthClient.into());
+    }
+
+    let redirect_state = parameters.state.as_deref();
+    if parameters.response_type.as_deref() != Some("code") {
+        return Ok(Err(error_redirect(
+            &parameters.redirect_uri,
+            "unsupported_response_type",
+            redirect_state,
+        )?));
+    }
+    // PKCE is required for every client
+    let code_challenge = match (
+        parameters.code_challenge,
+        parameters.code_challenge_method.as_deref(),
+    ) {
+        (Some(code_challenge), Some("S256")) => code_challenge,
+        _ => {
+            return Ok(Err(error_redirect(
+                &parameters.redirect_uri,
+                "invalid_request",
+                redirect_state,
+            )?));
+        }
+    };
+    let Some(scope) = parse_scope(parameters.scope.as_deref(), &client.allowed_scopes) else {
+        return Ok(Err(error_redirect(
+            &parameters.redirect_uri,
+            "invalid_scope",
+            redirect_state,
+        )?));
+    };
+
+    Ok(Ok(AuthorizationRequest {
+        client_id: client.client_id,
+        client_name: client.name,
+        trusted: client.trusted,
+        redirect_uri: parameters.redirect_uri,
+        scope,
+        state: parameters.state,
+        nonce: parameters.nonce,
+        code_challenge,
+        created_ts: Utc::now().timestamp(),
+    }))
+}
+
+pub fn store_authorization_request(request: AuthorizationRequest) -> String {
+    let mut lock = OAUTH_REQUEST_STORE.write().expect("Couldn't acquire lock");
+    let request_id = generate_unique_id(40);
+    let now = Utc::now().timestamp();
+
+    lock.retain(|_k, v| v.created_ts + AUTHORIZATION_REQUEST_EXPIRATION > now);
+
+    lock.insert(request_id.clone(), request);
+    request_id
+}
+
+pub fn get_authorization_request(request_id: &str) -> Option<AuthorizationRequest> {
+    let lock = OAUTH_REQUEST_STORE.read().expect("Couldn't acquire lock");
+    let now = Utc::now().timestamp();
+
+    lock.get(request_id)
+        .filter(|request| request.created_ts + AUTHORIZATION_REQUEST_EXPIRATION > now)
+        .cloned()
+}
+
+// Requests are single use so a decision can't be replayed
+pub fn take_authorization_request(request_id: &str) -> Option<AuthorizationRequest> {
+    let mut lock = OAUTH_REQUEST_STORE.write().expect("Couldn't acquire lock");
+    let now = Utc::now().timestamp();
+
+    lock.remove(request_id)
+        .filter(|request| request.created_ts + AUTHORIZATION_REQUEST_EXPIRATION > now)
+}
+
+// Issues an authorization code and returns where to send the browser with it
+pub fn approve_authorization(
+    request: AuthorizationRequest,
+    user_id: Uuid,
+) -> Result<Url, anyhow::Error> {
+    let mut lock = OAUTH_CODE_STORE.write().expect("Couldn't acquire lock");
+    let code = generate_unique_id(AUTHORIZATION_CODE_LENGTH);
+    let now = Utc::now().timestamp();
+
+    lock.retain(|_k, v| v.created_ts + AUTHORIZATION_CODE_EXPIRATION > now);
+
+    let mut url = Url::parse(&request.redirect_uri)?;
+    url.query_pairs_mut().append_pair("code", &code);
+    if let Some(state) = &request.state {
+        url.query_pairs_mut().append_pair("state", state);
+    }
+
+    lock.insert(
+        code,
+        AuthorizationCode {
+            client_id: request.client_id,
+            redirect_uri: request.redirect_uri,
+            user_id,
+            scope: request.scope,
+            nonce: request.nonce,
+            code_challenge: request.code_challenge,
+            created_ts: now,
+        },
+    );
+    Ok(url)
+}
+
+pub fn deny_authorization(request: AuthorizationRequest) -> Result<Url, anyhow::Error> {
+    error_redirect(
+        &request.redirect_uri,
+        "access_denied",
+        request.state.as_deref(),
+    )
+}
+
+fn take_authorization_code(code: &str) -> Option<AuthorizationCode> {
+    let mut lock = OAUTH_CODE_STORE.write().expect("Couldn't acquire lock");
+    let now = Utc::now().timestamp();
+
+    lock.remove(code)
+        .filter(|code| code.created_ts + AUTHORIZATION_CODE_EXPIRATION > now)
+}
+
+async fn issue_client_tokens(
+    state: &AppState,
+    client: &OAuthClient,
+    user: &User,
+    scope: &[String],
+    nonce: Option<String>,
+    family_id: Option<String>,
+) -> Result<OAuthTokenResponse, OAuthError> {
+    let (config, _key_store) = get_jwt_config(state)?;
+    let now = Utc::now().timestamp();
+    let scope_string = scope.join(" ");
+    let has_scope = |s: &str| scope.iter().any(|scope| scope == s);
+
+    let access_token = sign_claims(
+        state,
+        &ClientAccessTokenClaims {
+            iss: config.issuer.clone(),
+            sub: user.id,
+            aud: client.client_id.clone(),
+            exp: now + config.access_token_ttl,
+            iat: now,
+            jti: generate_unique_id(20),
+            client_id: client.client_id.clone(),
+            scope: scope_string.clone(),
+        },
+    )
+    .await?;
+
+    let id_token = if has_scope(SCOPE_OPENID) {
+        let claims = IdTokenClaims {
+            iss: config.issuer.clone(),
+            sub: user.id.to_string(),
+            aud: client.client_id.clone(),
+            exp: now + config.access_token_ttl,
+            iat: now,
+            nonce,
+            email: has_scope(SCOPE_EMAIL).then(|| user.email.clone()),
+            email_verified: has_scope(SCOPE_EMAIL).then_some(user.email_verified),
+            preferred_username: has_scope(SCOPE_PROFILE).then(|| user.username.clone()),
+        };
+        Some(sign_claims(state, &claims).await?)
+    } else {
+        None
+    };
+
+    let refresh_token = store_refresh_token(
+        state,
+        user.id,
+        family_id,
+        Some(&client.client_id),
+        Some(&scope_string),
+    )
+    .await?;
+
+    Ok(OAuthTokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in: config.access_token_ttl,
+        refresh_token,
+        scope: scope_string,
+        id_token,
+    })
+}
+
+pub async fn exchange_authorization_code(
+    state: &Arc<AppState>,
+    client: &OAuthClient,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<OAuthTokenResponse, OAuthError> {
+    let authorization_code = take_authorization_code(code).ok_or(OAuthError::InvalidGrant)?;
+    if authorization_code.client_id != client.client_id
+        || authorization_code.redirect_uri != redirect_uri
+        || pkce_challenge(code_verifier) != authorization_code.code_challenge
+    {
+        event!(
+            Level::WARN,
+            "Authorization code exchange failed verification"
+        );
+        return Err(OAuthError::InvalidGrant);
+    }
+
+    let user = get_user_by_id(state.clone(), authorization_code.user_id).await?;
+    issue_client_tokens(
+        state,
+        client,
+        &user,
+        &authorization_code.scope,
+        authorization_code.nonce,
+        None,
+    )
+    .await
+}
+
+pub async fn refresh_client_tokens(
+    state: &Arc<AppState>,
+    client: &OAuthClient,
+    refresh_token: &str,
+) -> Result<OAuthTokenResponse, OAuthError> {
+    let owner = use_refresh_token(state, refresh_token, Some(&client.client_id))
+        .await
+        .map_err(|e| match e.downcast_ref::<ErrorList>() {
+            Some(ErrorList::InvalidRefreshToken) => OAuthError::InvalidGrant,
+            _ => OAuthError::from(e),
+        })?;
+    let scope: Vec<String> = owner
+        .scope
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|scope| scope.to_string())
+        .collect();
+
+    let user = get_user_by_id(state.clone(), owner.user_id).await?;
+    issue_client_tokens(state, client, &user, &scope, None, Some(owner.family_id)).await
+}
+
+// Access tokens are JWTs which can't be revoked, so only refresh tokens are affected
+pub async fn revoke_token(
+    state: &AppState,
+    client: &OAuthClient,
+    token: &str,
+) -> Result<(), OAuthError> {
+    if revoke_refresh_token_family(state, token, Some(&client.client_id)).await? > 0 {
+        event!(
+            Level::INFO,
+            "OAuth client {} revoked a token",
+            client.client_id
+        );
+    }
+    Ok(())
+}
+
+// Only tokens issued to a client are accepted, not the access tokens used for axumatic's own API
+pub async fn get_user_info(
+    state: &Arc<AppState>,
+    access_token: &str,
+) -> Result<UserInfo, OAuthError> {
+    let claims: ClientAccessTokenClaims = verify_token(access_token, state, None)
+        .await
+        .map_err(|_| OAuthError::InvalidToken)?;
+    if claims.aud != claims.client_id {
+        return Err(OAuthError::InvalidToken);
+    }
+    let user = get_user_by_id(state.clone(), claims.sub)
+        .await
+        .map_err(|_| OAuthError::InvalidToken)?;
+
+    let has_scope = |s: &str| claims.scope.split_whitespace().any(|scope| scope == s);
+    let profile = Profile::from(user);
+    Ok(UserInfo {
+        sub: claims.sub.to_string(),
+        preferred_username: has_scope(SCOPE_PROFILE).then(|| profile.username.clone()),
+        email: has_scope(SCOPE_EMAIL).then(|| profile.email.clone()),
+        email_verified: has_scope(SCOPE_EMAIL).then_some(profile.email_verified),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        to_strings(&[SCOPE_OPENID, SCOPE_EMAIL])
+    }
+
+    #[test]
+    fn scope_must_be_allowed() {
+        assert_eq!(
+            parse_scope(Some("email openid email"), &allowed()),
+            Some(to_strings(&[SCOPE_EMAIL, SCOPE_OPENID]))
+        );
+        assert_eq!(parse_scope(Some("openid profile"), &allowed()), None);
+    }
+
+    #[test]
+    fn missing_scope_requests_all_allowed_scopes() {
+        assert_eq!(
+            parse_scope(None, &allowed()),
+            Some(to_strings(&[SCOPE_EMAIL, SCOPE_OPENID]))
+        );
+    }
+
+    #[test]
+    fn redirect_uris_must_be_https_or_loopback() {
+        assert!(validate_redirect_uri("https://app.tld.com/callback").is_ok());
+        assert!(validate_redirect_uri("http://127.0.0.1:8080/callback").is_ok());
+        assert!(validate_redirect_uri("http://app.tld.com/callback").is_err());
+        assert!(validate_redirect_uri("https://app.tld.com/callback#fragment").is_err());
+        assert!(validate_redirect_uri("not a url").is_err());
+    }
+
+    #[test]
+    fn client_credentials_from_basic_authentication() {
+        let mut headers = HeaderMap::new();
+        let credentials = STANDARD.encode("client:secret%3A1");
+        headers.insert(
+            AUTHORIZATION,
+            format!("Basic {}", credentials).parse().unwrap(),
+        );
+        assert_eq!(
+            get_client_credentials(&headers, None, None),
+            Some(("client".to_string(), Some("secret:1".to_string())))
+        );
+
+        let headers = HeaderMap::new();
+        assert_eq!(
+            get_client_credentials(&headers, Some("client".to_string()), None),
+            Some(("client".to_string(), None))
+        );
+    }
+}
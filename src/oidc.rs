@@ -0,0 +1,313 @@
+use crate::auth::get_cookie;
+use crate::config::OidcProviderConfig;
+use crate::default_route_handlers::ErrorList;
+use crate::passkey::BASE64_URL;
+use crate::utilities::{generate_unique_id, hash_token};
+use crate::{AppState, OIDC_STATE_STORE};
+use base64::Engine;
+use chrono::Utc;
+use cookie::{Cookie, SameSite, time::Duration};
+use http::HeaderMap;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+const STATE_EXPIRATION: i64 = 600;
+const STATE_COOKIE_NAME: &str = "oidc-state";
+const CODE_VERIFIER_LENGTH: u8 = 64;
+
+// Symmetric algorithms are excluded as we only ever have the provider's public keys
+const ALLOWED_ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+#[derive(Clone, Debug)]
+pub struct OidcState {
+    pub provider_id: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    // Set when an already signed in user is linking the identity to their account
+    pub link_user_id: Option<Uuid>,
+    pub created_ts: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiscoveryDocument {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenResponse {
+    pub id_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CallbackParameters {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+pub fn get_provider(
+    state: &Arc<AppState>,
+    provider_id: &str,
+) -> Result<OidcProviderConfig, ErrorList> {
+    state
+        .config
+        .oidc_providers
+        .iter()
+        .find(|provider| provider.id == provider_id)
+        .cloned()
+        .ok_or(ErrorList::UnknownProvider)
+}
+
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    BASE64_URL.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+// The cookie holds a hash of the state so a callback only completes in the browser which started it.
+// Lax is needed as the provider redirects back to us with a top level GET
+pub fn create_state(
+    provider_id: &str,
+    link_user_id: Option<Uuid>,
+) -> (String, OidcState, Cookie<'static>) {
+    let mut lock = OIDC_STATE_STORE.write().expect("Couldn't acquire lock");
+    let state = generate_unique_id(40);
+    let now = Utc::now().timestamp();
+
+    lock.retain(|_k, v| v.created_ts + STATE_EXPIRATION > now);
+
+    let oidc_state = OidcState {
+        provider_id: provider_id.to_string(),
+        nonce: generate_unique_id(40),
+        code_verifier: generate_unique_id(CODE_VERIFIER_LENGTH),
+        link_user_id,
+        created_ts: now,
+    };
+    lock.insert(state.clone(), oidc_state.clone());
+
+    let cookie = Cookie::build((STATE_COOKIE_NAME, hash_token(&state)))
+        .max_age(Duration::seconds(STATE_EXPIRATION))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build();
+    (state, oidc_state, cookie)
+}
+
+// States are single use so a callback can't be replayed
+pub fn take_state(state: &str, headers: &HeaderMap) -> Option<OidcState> {
+    let mut lock = OIDC_STATE_STORE.write().expect("Couldn't acquire lock");
+    let now = Utc::now().timestamp();
+
+    let oidc_state = lock
+        .remove(state)
+        .filter(|oidc_state| oidc_state.created_ts + STATE_EXPIRATION > now)?;
+    get_cookie(headers, STATE_COOKIE_NAME)
+        .filter(|cookie| *cookie == hash_token(state))
+        .map(|_| oidc_state)
+}
+
+pub async fn get_discovery_document(
+    state: Arc<AppState>,
+    provider: &OidcProviderConfig,
+) -> Result<DiscoveryDocument, anyhow::Error> {
+    let document: DiscoveryDocument = state
+        .http_client
+        .get(&provider.discovery_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if document.issuer != provider.issuer {
+        event!(
+            Level::WARN,
+            "Discovery document issuer {} did not match configured issuer",
+            document.issuer
+        );
+        return Err(ErrorList::OidcProviderError.into());
+    }
+    Ok(document)
+}
+
+pub async fn get_authorization_url(
+    state: Arc<AppState>,
+    provider: &OidcProviderConfig,
+    link_user_id: Option<Uuid>,
+) -> Result<(Url, Cookie<'static>), anyhow::Error> {
+    let discovery_document = get_discovery_document(state, provider).await?;
+    let (state_value, oidc_state, cookie) = create_state(&provider.id, link_user_id);
+    let scope = provider.scopes.join(" ");
+    let code_challenge = pkce_challenge(&oidc_state.code_verifier);
+
+    let url = Url::parse_with_params(
+        &discovery_document.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", provider.client_id.as_str()),
+            ("redirect_uri", provider.redirect_uri.as_str()),
+            ("scope", scope.as_str()),
+            ("state", state_value.as_str()),
+            ("nonce", oidc_state.nonce.as_str()),
+            ("code_challenge", code_challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )?;
+    Ok((url, cookie))
+}
+
+pub async fn verify_id_token(
+    state: Arc<AppState>,
+    provider: &OidcProviderConfig,
+    discovery_document: &DiscoveryDocument,
+    id_token: &str,
+    expected_nonce: &str,
+) -> Result<IdTokenClaims, anyhow::Error> {
+    let header = decode_header(id_token).map_err(|_| ErrorList::InvalidJwt)?;
+    if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+        return Err(ErrorList::InvalidJwt.into());
+    }
+
+    let jwks: JwkSet = state
+        .http_client
+        .get(&discovery_document.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or(ErrorList::InvalidJwt)?;
+    let decoding_key = DecodingKey::from_jwk(jwk).map_err(|_| ErrorList::InvalidJwt)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&provider.issuer]);
+    validation.set_audience(&[&provider.client_id]);
+
+    let claims = decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+        .map_err(|e| {
+            event!(Level::WARN, "ID token failed validation: {}", e);
+            ErrorList::InvalidJwt
+        })?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(expected_nonce) {
+        return Err(ErrorList::InvalidJwt.into());
+    }
+    Ok(claims)
+}
+
+// Exchanges the authorization code and returns the verified ID token claims
+pub async fn complete_authorization(
+    state: Arc<AppState>,
+    provider: &OidcProviderConfig,
+    oidc_state: &OidcState,
+    code: &str,
+) -> Result<IdTokenClaims, anyhow::Error> {
+    let discovery_document = get_discovery_document(state.clone(), provider).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", provider.redirect_uri.as_str()),
+        ("client_id", provider.client_id.as_str()),
+        ("code_verifier", oidc_state.code_verifier.as_str()),
+    ];
+    if let Some(client_secret) = &provider.client_secret {
+        form.push(("client_secret", client_secret.as_str()));
+    }
+
+    let response = state
+        .http_client
+        .post(&discovery_document.token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        event!(
+            Level::WARN,
+            "Token endpoint returned status {}",
+            response.status()
+        );
+        return Err(ErrorList::OidcProviderError.into());
+    }
+    let token_response: TokenResponse = response.json().await?;
+
+    verify_id_token(
+        state,
+        provider,
+        &discovery_document,
+        &token_response.id_token,
+        &oidc_state.nonce,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pkce_challenge_matches_rfc_example() {
+        // Example from RFC 7636 Appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn state_is_single_use() {
+        let (state, oidc_state, cookie) = create_state("test", None);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::COOKIE,
+            cookie.stripped().to_string().parse().unwrap(),
+        );
+        let taken = take_state(&state, &headers).unwrap();
+        assert_eq!(taken.nonce, oidc_state.nonce);
+        assert!(take_state(&state, &headers).is_none());
+    }
+
+    #[test]
+    fn state_requires_the_browser_cookie() {
+        let (state, _, _) = create_state("test", None);
+        let (_, _, other_cookie) = create_state("test", None);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::COOKIE,
+            other_cookie.stripped().to_string().parse().unwrap(),
+        );
+        assert!(take_state(&state, &headers).is_none());
+    }
+}
@@ -0,0 +1,626 @@
+use crate::default_route_handlers::ErrorList;
+use crate::user::User;
+use crate::utilities::generate_unique_id;
+use crate::{AppState, PASSKEY_CHALLENGE_STORE};
+use base64::{
+    Engine,
+    alphabet::URL_SAFE,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use chrono::Utc;
+use ciborium::Value;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{Level, event};
+use uuid::Uuid;
+
+// WebAuthn uses unpadded base64url but some clients pad it
+pub const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+const CHALLENGE_EXPIRATION: i64 = 300;
+const CEREMONY_TIMEOUT_MS: i64 = 300_000;
+
+// COSE algorithm identifier for ECDSA with P-256 and SHA-256
+const COSE_ALG_ES256: i64 = -7;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+const RP_ID_HASH_LENGTH: usize = 32;
+const AAGUID_LENGTH: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct PasskeyChallenge {
+    // Set for registrations so the challenge can only be used by the user who requested it
+    pub user_id: Option<Uuid>,
+    pub created_ts: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Passkey {
+    pub id: i32,
+    pub name: String,
+    pub created_ts: i64,
+    pub last_used_ts: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationCredential {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClientData {
+    #[serde(rename = "type")]
+    pub ceremony_type: String,
+    pub challenge: String,
+    pub origin: String,
+}
+
+#[derive(Debug)]
+pub struct AuthenticatorData {
+    pub rp_id_hash: Vec<u8>,
+    pub flags: u8,
+    pub sign_count: u32,
+    pub attested_credential: Option<AttestedCredential>,
+}
+
+#[derive(Debug)]
+pub struct AttestedCredential {
+    pub credential_id: Vec<u8>,
+    // SEC1 encoded P-256 public key
+    pub public_key: Vec<u8>,
+    // The counter at registration, which the first assertion is checked against
+    pub sign_count: u32,
+}
+
+pub fn create_challenge(user_id: Option<Uuid>) -> String {
+    let mut lock = PASSKEY_CHALLENGE_STORE
+        .write()
+        .expect("Couldn't acquire lock");
+    let challenge = BASE64_URL.encode(generate_unique_id(32));
+    let now = Utc::now().timestamp();
+
+    lock.retain(|_k, v| v.created_ts + CHALLENGE_EXPIRATION > now);
+
+    lock.insert(
+        challenge.clone(),
+        PasskeyChallenge {
+            user_id,
+            created_ts: now,
+        },
+    );
+    challenge
+}
+
+// Challenges are single use so they are removed whether or not the ceremony succeeds
+pub fn take_challenge(challenge: &str) -> Option<PasskeyChallenge> {
+    let mut lock = PASSKEY_CHALLENGE_STORE
+        .write()
+        .expect("Couldn't acquire lock");
+    let now = Utc::now().timestamp();
+
+    lock.remove(challenge)
+        .filter(|pending| pending.created_ts + CHALLENGE_EXPIRATION > now)
+}
+
+pub fn get_registration_options(
+    state: Arc<AppState>,
+    user: &User,
+    existing_credential_ids: &[String],
+) -> serde_json::Value {
+    let challenge = create_challenge(Some(user.id));
+    let user_handle = BASE64_URL.encode(user.id.as_bytes());
+    let exclude_credentials: Vec<serde_json::Value> = existing_credential_ids
+        .iter()
+        .map(|id| serde_json::json!({ "type": "public-key", "id": id }))
+        .collect();
+
+    serde_json::json!({
+        "challenge": challenge,
+        "rp": {
+            "id": state.config.webauthn.rp_id,
+            "name": state.config.webauthn.rp_name,
+        },
+        "user": {
+            "id": user_handle,
+            "name": user.email,
+            "displayName": user.username,
+        },
+        "pubKeyCredParams": [{ "type": "public-key", "alg": COSE_ALG_ES256 }],
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "attestation": "none",
+        "excludeCredentials": exclude_credentials,
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "userVerification": "preferred",
+        },
+    })
+}
+
+pub fn get_authentication_options(state: Arc<AppState>) -> serde_json::Value {
+    let challenge = create_challenge(None);
+
+    // Credentials are discoverable so the authenticator chooses which one to use
+    serde_json::json!({
+        "challenge": challenge,
+        "rpId": state.config.webauthn.rp_id,
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "userVerification": "preferred",
+        "allowCredentials": [],
+    })
+}
+
+pub fn verify_client_data(
+    client_data_json: &[u8],
+    expected_type: &str,
+    expected_origin: &str,
+) -> Result<ClientData, ErrorList> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| ErrorList::InvalidPasskey)?;
+
+    if client_data.ceremony_type != expected_type {
+        event!(Level::WARN, "Unexpected WebAuthn ceremony type");
+        return Err(ErrorList::InvalidPasskey);
+    }
+    if client_data.origin != expected_origin {
+        event!(
+            Level::WARN,
+            "WebAuthn origin {} did not match",
+            client_data.origin
+        );
+        return Err(ErrorList::InvalidPasskey);
+    }
+    Ok(client_data)
+}
+
+pub fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, ErrorList> {
+    const HEADER_LENGTH: usize = RP_ID_HASH_LENGTH + 1 + 4;
+
+    if data.len() < HEADER_LENGTH {
+        return Err(ErrorList::InvalidPasskey);
+    }
+    let rp_id_hash = data[..RP_ID_HASH_LENGTH].to_vec();
+    let flags = data[RP_ID_HASH_LENGTH];
+    let sign_count = u32::from_be_bytes(
+        data[RP_ID_HASH_LENGTH + 1..HEADER_LENGTH]
+            .try_into()
+            .map_err(|_| ErrorList::InvalidPasskey)?,
+    );
+
+    let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        let rest = &data[HEADER_LENGTH..];
+        let id_start = AAGUID_LENGTH + 2;
+        if rest.len() < id_start {
+            return Err(ErrorList::InvalidPasskey);
+        }
+        let id_length = u16::from_be_bytes([rest[AAGUID_LENGTH], rest[AAGUID_LENGTH + 1]]) as usize;
+        if rest.len() < id_start + id_length {
+            return Err(ErrorList::InvalidPasskey);
+        }
+        let credential_id = rest[id_start..id_start + id_length].to_vec();
+        let cose_key: Value = ciborium::from_reader(&rest[id_start + id_length..])
+            .map_err(|_| ErrorList::InvalidPasskey)?;
+
+        Some(AttestedCredential {
+            credential_id,
+            public_key: parse_cose_key(&cose_key)?,
+            sign_count,
+        })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        rp_id_hash,
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+// Only ES256 keys are requested in the registration options
+pub fn parse_cose_key(cose_key: &Value) -> Result<Vec<u8>, ErrorList> {
+    let entries = cose_key.as_map().ok_or(ErrorList::InvalidPasskey)?;
+    let get = |label: i64| {
+        entries
+            .iter()
+            .find(|(key, _)| key.as_integer() == Some(label.into()))
+            .map(|(_, value)| value)
+    };
+    let get_integer = |label: i64| {
+        get(label)
+            .and_then(Value::as_integer)
+            .and_then(|value| i64::try_from(value).ok())
+    };
+
+    if get_integer(1) != Some(COSE_KTY_EC2)
+        || get_integer(3) != Some(COSE_ALG_ES256)
+        || get_integer(-1) != Some(COSE_CRV_P256)
+    {
+        return Err(ErrorList::UnsupportedPasskeyAlgorithm);
+    }
+
+    let x = get(-2)
+        .and_then(Value::as_bytes)
+        .ok_or(ErrorList::InvalidPasskey)?;
+    let y = get(-3)
+        .and_then(Value::as_bytes)
+        .ok_or(ErrorList::InvalidPasskey)?;
+
+    let mut public_key = vec![0x04];
+    public_key.extend_from_slice(x);
+    public_key.extend_from_slice(y);
+
+    VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| ErrorList::InvalidPasskey)?;
+    Ok(public_key)
+}
+
+// Returns the authenticator data from a "none" attestation, the attestation
+// statement itself isn't verified as we don't restrict which authenticators can be used
+pub fn parse_attestation_object(attestation_object: &[u8]) -> Result<Vec<u8>, ErrorList> {
+    let attestation: Value =
+        ciborium::from_reader(attestation_object).map_err(|_| ErrorList::InvalidPasskey)?;
+    let entries = attestation.as_map().ok_or(ErrorList::InvalidPasskey)?;
+
+    entries
+        .iter()
+        .find(|(key, _)| key.as_text() == Some("authData"))
+        .and_then(|(_, value)| value.as_bytes())
+        .cloned()
+        .ok_or(ErrorList::InvalidPasskey)
+}
+
+fn verify_rp_and_flags(
+    authenticator_data: &AuthenticatorData,
+    rp_id: &str,
+) -> Result<(), ErrorList> {
+    if authenticator_data.rp_id_hash != Sha256::digest(rp_id.as_bytes()).as_slice() {
+        event!(Level::WARN, "WebAuthn relying party id hash did not match");
+        return Err(ErrorList::InvalidPasskey);
+    }
+    if authenticator_data.flags & FLAG_USER_PRESENT == 0 {
+        event!(Level::WARN, "WebAuthn user presence flag not set");
+        return Err(ErrorList::InvalidPasskey);
+    }
+    Ok(())
+}
+
+pub fn verify_registration(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    credential: &RegistrationCredential,
+) -> Result<AttestedCredential, ErrorList> {
+    let client_data_json = BASE64_URL
+        .decode(&credential.response.client_data_json)
+        .map_err(|_| ErrorList::InvalidPasskey)?;
+    let client_data = verify_client_data(
+        &client_data_json,
+        "webauthn.create",
+        &state.config.webauthn.origin,
+    )?;
+
+    let challenge = take_challenge(&client_data.challenge).ok_or(ErrorList::InvalidPasskey)?;
+    if challenge.user_id != Some(user_id) {
+        return Err(ErrorList::InvalidPasskey);
+    }
+
+    let attestation_object = BASE64_URL
+        .decode(&credential.response.attestation_object)
+        .map_err(|_| ErrorList::InvalidPasskey)?;
+    let authenticator_data =
+        parse_authenticator_data(&parse_attestation_object(&attestation_object)?)?;
+    verify_rp_and_flags(&authenticator_data, &state.config.webauthn.rp_id)?;
+
+    authenticator_data
+        .attested_credential
+        .ok_or(ErrorList::InvalidPasskey)
+}
+
+// Returns the new signature counter on success
+pub fn verify_assertion(
+    state: Arc<AppState>,
+    credential: &AuthenticationCredential,
+    public_key: &[u8],
+    stored_sign_count: u32,
+) -> Result<u32, ErrorList> {
+    let client_data_json = BASE64_URL
+        .decode(&credential.response.client_data_json)
+        .map_err(|_| ErrorList::InvalidPasskey)?;
+    let client_data = verify_client_data(
+        &client_data_json,
+        "webauthn.get",
+        &state.config.webauthn.origin,
+    )?;
+
+    let challenge = take_challenge(&client_data.challenge).ok_or(ErrorList::InvalidPasskey)?;
+    if challenge.user_id.is_some() {
+        return Err(ErrorList::InvalidPasskey);
+    }
+
+    let raw_authenticator_data = BASE64_URL
+        .decode(&credential.response.authenticator_data)
+        .map_err(|_| ErrorList::InvalidPasskey)?;
+    let authenticator_data = parse_authenticator_data(&raw_authenticator_data)?;
+    verify_rp_and_flags(&authenticator_data, &state.config.webauthn.rp_id)?;
+
+    let signature = BASE64_URL
+        .decode(&credential.response.signature)
+        .map_err(|_| ErrorList::InvalidPasskey)?;
+    let signature = Signature::from_der(&signature).map_err(|_| ErrorList::InvalidPasskey)?;
+    let verifying_key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| ErrorList::InvalidPasskey)?;
+
+    let mut signed_data = raw_authenticator_data.clone();
+    signed_data.extend_from_slice(&Sha256::digest(&client_data_json));
+    verifying_key
+        .verify(&signed_data, &signature)
+        .map_err(|_| ErrorList::InvalidPasskey)?;
+
+    // A counter which doesn't increase suggests the authenticator has been cloned,
+    // authenticators which don't support counters always report zero
+    let sign_count = authenticator_data.sign_count;
+    if (sign_count != 0 || stored_sign_count != 0) && sign_count <= stored_sign_count {
+        event!(Level::WARN, "Passkey signature counter did not increase");
+        return Err(ErrorList::InvalidPasskey);
+    }
+
+    Ok(sign_count)
+}
+
+pub async fn get_passkeys(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<Vec<Passkey>, anyhow::Error> {
+    let passkeys = sqlx::query_as!(
+        Passkey,
+        r#"SELECT
+            id,
+            name as "name!",
+            created_ts as "created_ts!",
+            last_used_ts
+        FROM passkeys WHERE user_id = $1
+        ORDER BY created_ts"#,
+        user_id
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    Ok(passkeys)
+}
+
+pub async fn get_credential_ids(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<Vec<String>, anyhow::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT credential_id as "credential_id!" FROM passkeys WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| row.credential_id).collect())
+}
+
+pub async fn add_passkey(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    name: &str,
+    credential: &AttestedCredential,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "INSERT INTO passkeys (user_id, credential_id, public_key, sign_count, name, created_ts) VALUES ($1, $2, $3, $4, $5, $6)",
+        user_id,
+        BASE64_URL.encode(&credential.credential_id),
+        BASE64_URL.encode(&credential.public_key),
+        i64::from(credential.sign_count),
+        name,
+        Utc::now().timestamp()
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(())
+}
+
+// Refuses to remove the user's last way of signing in. The user's row is locked so concurrent removals
+// can't both pass the check
+pub async fn delete_passkey(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    passkey_id: i32,
+) -> Result<bool, anyhow::Error> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+
+    let has_password = sqlx::query!(
+        r#"SELECT hashed_password IS NOT NULL as "has_password!" FROM users WHERE id = $1 FOR UPDATE"#,
+        user_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?
+    .has_password;
+    let credentials = sqlx::query!(
+        r#"SELECT
+            (SELECT COUNT(*) FROM user_identities WHERE user_id = $1) as "identities!",
+            (SELECT COUNT(*) FROM passkeys WHERE user_id = $1) as "passkeys!",
+            (SELECT id FROM passkeys WHERE user_id = $1 AND id = $2) as passkey_id
+        FROM (SELECT 1) as credentials"#,
+        user_id,
+        passkey_id
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    if credentials.passkey_id.is_none() {
+        return Ok(false);
+    }
+    let remaining = credentials.identities + credentials.passkeys - 1 + i64::from(has_password);
+    if remaining < 1 {
+        return Err(ErrorList::LastCredential.into());
+    }
+
+    sqlx::query!(
+        "DELETE FROM passkeys WHERE user_id = $1 AND id = $2",
+        user_id,
+        passkey_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(true)
+}
+
+// Verifies an assertion against the stored credential and returns the owner's id
+pub async fn authenticate_passkey(
+    state: Arc<AppState>,
+    credential: &AuthenticationCredential,
+) -> Result<Uuid, anyhow::Error> {
+    let credential_id = BASE64_URL.encode(
+        BASE64_URL
+            .decode(&credential.id)
+            .map_err(|_| ErrorList::InvalidPasskey)?,
+    );
+
+    let row = sqlx::query!(
+        r#"SELECT
+            user_id,
+            public_key as "public_key!",
+            sign_count as "sign_count!"
+        FROM passkeys WHERE credential_id = $1"#,
+        &credential_id
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?
+    .ok_or(ErrorList::InvalidPasskey)?;
+
+    let public_key = BASE64_URL.decode(&row.public_key)?;
+    let sign_count = verify_assertion(
+        state.clone(),
+        credential,
+        &public_key,
+        row.sign_count as u32,
+    )?;
+
+    sqlx::query!(
+        "UPDATE passkeys SET sign_count = $1, last_used_ts = $2 WHERE credential_id = $3",
+        sign_count as i64,
+        Utc::now().timestamp(),
+        &credential_id
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(row.user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::SigningKey;
+    use p256::elliptic_curve::rand_core::OsRng;
+
+    fn cose_key(signing_key: &SigningKey) -> Value {
+        let point = signing_key.verifying_key().to_encoded_point(false);
+        Value::Map(vec![
+            (Value::from(1), Value::from(COSE_KTY_EC2)),
+            (Value::from(3), Value::from(COSE_ALG_ES256)),
+            (Value::from(-1), Value::from(COSE_CRV_P256)),
+            (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+            (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+        ])
+    }
+
+    #[test]
+    fn parses_es256_cose_key() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let public_key = parse_cose_key(&cose_key(&signing_key)).unwrap();
+        assert_eq!(
+            public_key,
+            signing_key
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+        );
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let mut key = cose_key(&signing_key);
+        if let Value::Map(entries) = &mut key {
+            entries[1].1 = Value::from(-257);
+        }
+        assert!(matches!(
+            parse_cose_key(&key),
+            Err(ErrorList::UnsupportedPasskeyAlgorithm)
+        ));
+    }
+
+    #[test]
+    fn parses_attested_credential_data() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let credential_id = vec![7u8; 16];
+
+        let mut data = Sha256::digest(b"localhost").to_vec();
+        data.push(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA);
+        data.extend_from_slice(&5u32.to_be_bytes());
+        data.extend_from_slice(&[0u8; AAGUID_LENGTH]);
+        data.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+        data.extend_from_slice(&credential_id);
+        ciborium::into_writer(&cose_key(&signing_key), &mut data).unwrap();
+
+        let authenticator_data = parse_authenticator_data(&data).unwrap();
+        assert_eq!(authenticator_data.sign_count, 5);
+        let attested_credential = authenticator_data.attested_credential.unwrap();
+        assert_eq!(attested_credential.credential_id, credential_id);
+        assert_eq!(attested_credential.sign_count, 5);
+    }
+
+    #[test]
+    fn rejects_truncated_authenticator_data() {
+        assert!(parse_authenticator_data(&[0u8; 10]).is_err());
+    }
+}
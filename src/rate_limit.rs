@@ -0,0 +1,206 @@
+use crate::RATE_LIMIT_STORE;
+use crate::config::{RateLimitKey, RateLimitPolicy};
+use http::HeaderMap;
+use std::net::IpAddr;
+
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated: f64,
+    // When the bucket will have refilled, after which it can be forgotten
+    pub full_at: f64,
+}
+
+// Takes a token from the bucket, or returns how many seconds until one is available
+fn take_token(
+    bucket: Option<&TokenBucket>,
+    policy: &RateLimitPolicy,
+    now: f64,
+) -> Result<TokenBucket, u64> {
+    let capacity = policy.requests as f64;
+    let refill_rate = capacity / policy.period_in_seconds.max(1) as f64;
+
+    let tokens = bucket.map_or(capacity, |bucket| {
+        (bucket.tokens + (now - bucket.updated) * refill_rate).min(capacity)
+    });
+
+    if tokens < 1.0 {
+        return Err(((1.0 - tokens) / refill_rate).ceil().max(1.0) as u64);
+    }
+
+    let tokens = tokens - 1.0;
+    Ok(TokenBucket {
+        tokens,
+        updated: now,
+        full_at: now + (capacity - tokens) / refill_rate,
+    })
+}
+
+// The client is the right-most address not added by one of our own proxies
+pub fn get_client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded_for = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<IpAddr>>();
+
+    Some(
+        forwarded_for
+            .iter()
+            .rev()
+            .find(|address| !trusted_proxies.contains(address))
+            .or(forwarded_for.first())
+            .copied()
+            .unwrap_or(peer),
+    )
+}
+
+pub fn get_bucket_keys(
+    policy: &RateLimitPolicy,
+    client_ip: Option<IpAddr>,
+    account: Option<&str>,
+) -> Vec<String> {
+    policy
+        .keys
+        .iter()
+        .filter_map(|key| match key {
+            RateLimitKey::Ip => client_ip.map(|ip| format!("ip:{ip}")),
+            RateLimitKey::Account => account.map(|account| format!("account:{account}")),
+        })
+        .map(|key| {
+            format!(
+                "{} {} {}/{} {}",
+                policy.method, policy.path, policy.requests, policy.period_in_seconds, key
+            )
+        })
+        .collect()
+}
+
+// Every bucket of every policy must have a token for any to be taken, otherwise returns the longest wait
+pub fn check_rate_limit(policies: &[(&RateLimitPolicy, Vec<String>)], now: f64) -> Result<(), u64> {
+    let mut lock = RATE_LIMIT_STORE.write().expect("Couldn't acquire lock");
+    lock.retain(|_k, bucket| bucket.full_at > now);
+
+    let mut buckets = Vec::new();
+    let mut retry_after = None;
+    for (policy, keys) in policies {
+        for key in keys {
+            match take_token(lock.get(key), policy, now) {
+                Ok(bucket) => buckets.push((key.clone(), bucket)),
+                Err(seconds) => retry_after = retry_after.max(Some(seconds)),
+            }
+        }
+    }
+
+    if let Some(seconds) = retry_after {
+        return Err(seconds);
+    }
+    lock.extend(buckets);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(requests: u32, period_in_seconds: u64) -> RateLimitPolicy {
+        RateLimitPolicy {
+            method: "POST".to_string(),
+            path: "/account/login".to_string(),
+            requests,
+            period_in_seconds,
+            keys: vec![RateLimitKey::Ip, RateLimitKey::Account],
+        }
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let policy = policy(2, 2);
+        let bucket = take_token(None, &policy, 0.0).unwrap();
+        let bucket = take_token(Some(&bucket), &policy, 0.0).unwrap();
+        assert_eq!(bucket.full_at, 2.0);
+        assert_eq!(take_token(Some(&bucket), &policy, 0.0).unwrap_err(), 1);
+        assert_eq!(take_token(Some(&bucket), &policy, 0.5).unwrap_err(), 1);
+        assert!(take_token(Some(&bucket), &policy, 1.0).is_ok());
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.7".parse().unwrap(),
+        );
+
+        assert_eq!(
+            get_client_ip(Some(client), &headers, &[proxy]),
+            Some(client)
+        );
+        assert_eq!(get_client_ip(Some(proxy), &headers, &[proxy]), Some(client));
+        assert_eq!(
+            get_client_ip(Some(proxy), &HeaderMap::new(), &[proxy]),
+            Some(proxy)
+        );
+    }
+
+    #[test]
+    fn keys_are_only_built_when_known() {
+        let policy = policy(2, 60);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            get_bucket_keys(&policy, Some(ip), Some("user@tld.com")).len(),
+            2
+        );
+        assert_eq!(
+            get_bucket_keys(&policy, None, Some("user@tld.com")).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn no_token_is_taken_unless_every_bucket_has_one() {
+        let policy = policy(1, 60);
+        let keys = vec![
+            "test unique ip".to_string(),
+            "test unique account".to_string(),
+        ];
+        assert!(check_rate_limit(&[(&policy, keys[..1].to_vec())], 0.0).is_ok());
+        assert!(check_rate_limit(&[(&policy, keys.clone())], 0.0).is_err());
+        assert!(check_rate_limit(&[(&policy, keys[1..].to_vec())], 0.0).is_ok());
+    }
+
+    #[test]
+    fn no_token_is_taken_unless_every_policy_has_one() {
+        let loose = policy(5, 60);
+        let strict = policy(1, 60);
+        let loose_keys = vec!["test unique loose".to_string()];
+        let strict_keys = vec!["test unique strict".to_string()];
+        assert!(check_rate_limit(&[(&strict, strict_keys.clone())], 0.0).is_ok());
+        for _ in 0..5 {
+            assert!(
+                check_rate_limit(
+                    &[(&loose, loose_keys.clone()), (&strict, strict_keys.clone())],
+                    0.0
+                )
+                .is_err()
+            );
+        }
+        // The rejected requests didn't use up the loose policy's tokens
+        for _ in 0..5 {
+            assert!(check_rate_limit(&[(&loose, loose_keys.clone())], 0.0).is_ok());
+        }
+    }
+}
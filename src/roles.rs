@@ -0,0 +1,78 @@
+use crate::AppState;
+use crate::config::AuthLevel;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Granting this permission to a role grants it every permission
+pub const WILDCARD_PERMISSION: &str = "*";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Role {
+    pub name: String,
+    pub description: Option<String>,
+    pub permissions: Vec<String>,
+}
+
+// Admins satisfy every role requirement
+pub async fn user_has_role(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    role: &str,
+) -> Result<bool, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM users WHERE id = $1 AND (auth_level = $2 OR auth_level = $3)
+        ) as "has_role!""#,
+        user_id,
+        role,
+        String::from(AuthLevel::Admin)
+    )
+    .fetch_one(&state.db_connection_pool)
+    .await?;
+
+    Ok(row.has_role)
+}
+
+pub async fn user_has_permission(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    permission: &str,
+) -> Result<bool, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT EXISTS(
+            SELECT 1 FROM users
+            JOIN role_permissions ON role_permissions.role = users.auth_level
+            WHERE users.id = $1 AND (role_permissions.permission = $2 OR role_permissions.permission = $3)
+        ) as "has_permission!""#,
+        user_id,
+        permission,
+        WILDCARD_PERMISSION
+    )
+    .fetch_one(&state.db_connection_pool)
+    .await?;
+
+    Ok(row.has_permission)
+}
+
+pub async fn get_roles(state: Arc<AppState>) -> Result<Vec<Role>, anyhow::Error> {
+    let roles = sqlx::query_as!(
+        Role,
+        r#"SELECT
+            roles.name as "name!",
+            roles.description,
+            COALESCE(
+                ARRAY_AGG(role_permissions.permission ORDER BY role_permissions.permission)
+                    FILTER (WHERE role_permissions.permission IS NOT NULL),
+                '{}'
+            ) as "permissions!"
+        FROM roles
+        LEFT JOIN role_permissions ON role_permissions.role = roles.name
+        GROUP BY roles.name, roles.description
+        ORDER BY roles.name"#
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    Ok(roles)
+}
@@ -1,7 +1,7 @@
 use crate::{AppState, default_route_handlers};
 use axum::{
     Router,
-    routing::{get, patch, post},
+    routing::{delete, get, patch, post},
 };
 use std::sync::Arc;
 
@@ -15,18 +15,167 @@ pub fn get_protected_routes() -> Router<Arc<AppState>> {
             "/account/changePassword",
             patch(default_route_handlers::change_password),
         )
+        .route(
+            "/account/changeEmail",
+            post(default_route_handlers::change_email),
+        )
+        .route(
+            "/account/changeEmail/confirm",
+            post(default_route_handlers::email_change_confirm),
+        )
         .route("/account/profile", get(default_route_handlers::get_profile))
         .route("/account/logout", get(default_route_handlers::logout))
+        .route(
+            "/account/sessions",
+            get(default_route_handlers::get_sessions)
+                .delete(default_route_handlers::revoke_other_sessions),
+        )
+        .route(
+            "/account/sessions/:session_id",
+            delete(default_route_handlers::revoke_session),
+        )
+        .route(
+            "/account/passkeys",
+            get(default_route_handlers::get_user_passkeys)
+                .post(default_route_handlers::register_passkey),
+        )
+        .route(
+            "/account/passkeys/registrationOptions",
+            post(default_route_handlers::passkey_registration_options),
+        )
+        .route(
+            "/account/passkeys/:passkey_id",
+            delete(default_route_handlers::remove_passkey),
+        )
+        .route(
+            "/account/identities",
+            get(default_route_handlers::get_identities),
+        )
+        .route(
+            "/account/identities/link/:provider",
+            post(default_route_handlers::link_user_identity),
+        )
+        .route(
+            "/account/identities/:identity_id",
+            delete(default_route_handlers::unlink_user_identity),
+        )
+        .route(
+            "/account/tokens",
+            get(default_route_handlers::get_user_api_tokens)
+                .post(default_route_handlers::create_user_api_token),
+        )
+        .route(
+            "/account/tokens/:token_id",
+            delete(default_route_handlers::revoke_api_token),
+        )
+        .route(
+            "/oauth/requests/:request_id",
+            get(default_route_handlers::get_oauth_request)
+                .post(default_route_handlers::decide_oauth_request),
+        )
+        .route(
+            "/account/twoFactor/enroll",
+            post(default_route_handlers::two_factor_enroll),
+        )
+        .route(
+            "/account/twoFactor/confirm",
+            post(default_route_handlers::two_factor_confirm),
+        )
+        .route(
+            "/account/twoFactor/disable",
+            post(default_route_handlers::two_factor_disable),
+        )
         .route(
             "/account/verificationEmail",
             get(default_route_handlers::resend_verification_email),
         )
 }
 
+// Only accessible to admins, these also require a valid session
+pub fn get_admin_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin/roles", get(default_route_handlers::get_all_roles))
+        .route(
+            "/admin/users",
+            get(default_route_handlers::admin_list_users),
+        )
+        .route(
+            "/admin/users/:username",
+            get(default_route_handlers::admin_get_user)
+                .delete(default_route_handlers::admin_delete_user),
+        )
+        .route(
+            "/admin/users/:username/verifyEmail",
+            post(default_route_handlers::admin_verify_email),
+        )
+        .route(
+            "/admin/users/:username/unlock",
+            post(default_route_handlers::admin_unlock_user),
+        )
+        .route(
+            "/admin/users/:username/authLevel",
+            patch(default_route_handlers::admin_change_auth_level),
+        )
+        .route(
+            "/admin/users/:username/sessions",
+            delete(default_route_handlers::admin_revoke_sessions),
+        )
+        .route(
+            "/admin/oauthClients",
+            get(default_route_handlers::admin_list_oauth_clients)
+                .post(default_route_handlers::admin_create_oauth_client),
+        )
+        .route(
+            "/admin/oauthClients/:client_id",
+            delete(default_route_handlers::admin_delete_oauth_client),
+        )
+}
+
 pub fn get_open_routes() -> Router<Arc<AppState>> {
     Router::new()
         .route("/account/register", post(default_route_handlers::register))
         .route("/account/login", post(default_route_handlers::login))
+        .route("/account/token", post(default_route_handlers::issue_token))
+        .route(
+            "/account/token/refresh",
+            post(default_route_handlers::refresh_token),
+        )
+        .route(
+            "/account/login/twoFactor",
+            post(default_route_handlers::login_two_factor),
+        )
+        .route(
+            "/account/login/email",
+            post(default_route_handlers::magic_login_initiate),
+        )
+        .route(
+            "/account/login/email/complete",
+            post(default_route_handlers::magic_login_complete),
+        )
+        .route(
+            "/account/login/passkeyOptions",
+            post(default_route_handlers::passkey_login_options),
+        )
+        .route(
+            "/account/login/passkey",
+            post(default_route_handlers::passkey_login),
+        )
+        .route(
+            "/account/login/github",
+            get(default_route_handlers::github_login),
+        )
+        .route(
+            "/account/callback/github",
+            get(default_route_handlers::github_callback),
+        )
+        .route(
+            "/account/login/:provider",
+            get(default_route_handlers::oidc_login),
+        )
+        .route(
+            "/account/callback/:provider",
+            get(default_route_handlers::oidc_callback),
+        )
         .route(
             "/account/login/google",
             post(default_route_handlers::google_login),
@@ -39,6 +188,26 @@ pub fn get_open_routes() -> Router<Arc<AppState>> {
             "/account/resetPassword",
             patch(default_route_handlers::password_reset_complete),
         )
+        .route(
+            "/account/changeEmail/revert",
+            post(default_route_handlers::email_change_revert),
+        )
         .route("/healthCheck", get(default_route_handlers::health_check))
         .route("/nonce", get(default_route_handlers::get_nonce))
+        .route("/csrfToken", get(default_route_handlers::get_csrf_token))
+        .route(
+            "/oauth/authorize",
+            get(default_route_handlers::oauth_authorize),
+        )
+        .route("/oauth/token", post(default_route_handlers::oauth_token))
+        .route("/oauth/revoke", post(default_route_handlers::oauth_revoke))
+        .route(
+            "/oauth/userinfo",
+            get(default_route_handlers::oauth_userinfo),
+        )
+        .route("/.well-known/jwks.json", get(default_route_handlers::jwks))
+        .route(
+            "/.well-known/openid-configuration",
+            get(default_route_handlers::openid_configuration),
+        )
 }
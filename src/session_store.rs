@@ -0,0 +1,389 @@
+use axum::async_trait;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicI32, Ordering};
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
+pub struct SessionRecord {
+    pub id: i32,
+    pub hashed_session_key: String,
+    pub user_id: Uuid,
+    pub expiry: i64,
+    pub created_ts: Option<i64>,
+    pub last_seen_ts: Option<i64>,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NewSession {
+    pub hashed_session_key: String,
+    pub user_id: Uuid,
+    pub expiry: i64,
+    pub created_ts: i64,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+// Where sessions are kept, Postgres is shared between instances while memory is only suitable for a single node.
+// Sessions are looked up by a hash of their key so the store never holds anything which could be used as a cookie
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn insert(&self, session: NewSession) -> Result<(), anyhow::Error>;
+
+    // Moves an unexpired session's expiry and records when it was seen, returning false if it has expired
+    async fn extend(
+        &self,
+        hashed_session_key: &str,
+        expiry: i64,
+        now: i64,
+    ) -> Result<bool, anyhow::Error>;
+
+    async fn get(&self, hashed_session_key: &str) -> Result<Option<SessionRecord>, anyhow::Error>;
+
+    // Unexpired sessions for the user, newest first
+    async fn list(&self, user_id: Uuid, now: i64) -> Result<Vec<SessionRecord>, anyhow::Error>;
+
+    async fn delete(&self, user_id: Uuid, session_id: i32) -> Result<bool, anyhow::Error>;
+
+    async fn delete_by_key(
+        &self,
+        user_id: Uuid,
+        hashed_session_key: &str,
+    ) -> Result<bool, anyhow::Error>;
+
+    async fn delete_others(
+        &self,
+        user_id: Uuid,
+        hashed_session_key: &str,
+    ) -> Result<u64, anyhow::Error>;
+
+    async fn delete_all(&self, user_id: Uuid) -> Result<u64, anyhow::Error>;
+
+    async fn delete_expired(&self, now: i64) -> Result<u64, anyhow::Error>;
+}
+
+pub struct PostgresSessionStore {
+    pool: Pool<Postgres>,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl SessionStore for PostgresSessionStore {
+    async fn insert(&self, session: NewSession) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            "INSERT INTO sessions (hashed_session_key, user_id, expiry, created_ts, last_seen_ts, user_agent, ip_address) VALUES ($1, $2, $3, $4, $4, $5, $6)",
+            &session.hashed_session_key,
+            session.user_id,
+            session.expiry,
+            session.created_ts,
+            session.user_agent,
+            session.ip_address
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn extend(
+        &self,
+        hashed_session_key: &str,
+        expiry: i64,
+        now: i64,
+    ) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            "UPDATE sessions SET expiry = $1, last_seen_ts = $2 WHERE hashed_session_key = $3 AND expiry > $4",
+            expiry,
+            now,
+            hashed_session_key,
+            now
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get(&self, hashed_session_key: &str) -> Result<Option<SessionRecord>, anyhow::Error> {
+        let session = sqlx::query_as!(
+            SessionRecord,
+            r#"SELECT
+                id,
+                hashed_session_key as "hashed_session_key!",
+                user_id,
+                expiry as "expiry!",
+                created_ts,
+                last_seen_ts,
+                user_agent,
+                ip_address
+            FROM sessions WHERE hashed_session_key = $1"#,
+            hashed_session_key
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    async fn list(&self, user_id: Uuid, now: i64) -> Result<Vec<SessionRecord>, anyhow::Error> {
+        let sessions = sqlx::query_as!(
+            SessionRecord,
+            r#"SELECT
+                id,
+                hashed_session_key as "hashed_session_key!",
+                user_id,
+                expiry as "expiry!",
+                created_ts,
+                last_seen_ts,
+                user_agent,
+                ip_address
+            FROM sessions WHERE user_id = $1 AND expiry > $2
+            ORDER BY created_ts DESC"#,
+            user_id,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    async fn delete(&self, user_id: Uuid, session_id: i32) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = $1 AND id = $2",
+            user_id,
+            session_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_by_key(
+        &self,
+        user_id: Uuid,
+        hashed_session_key: &str,
+    ) -> Result<bool, anyhow::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = $1 AND hashed_session_key = $2",
+            user_id,
+            hashed_session_key
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_others(
+        &self,
+        user_id: Uuid,
+        hashed_session_key: &str,
+    ) -> Result<u64, anyhow::Error> {
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = $1 AND hashed_session_key <> $2",
+            user_id,
+            hashed_session_key
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_all(&self, user_id: Uuid) -> Result<u64, anyhow::Error> {
+        let result = sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_expired(&self, now: i64) -> Result<u64, anyhow::Error> {
+        let result = sqlx::query!("DELETE FROM sessions WHERE $1 > expiry", now)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+// Sessions are lost on restart and aren't shared between instances
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: RwLock<HashMap<String, SessionRecord>>,
+    next_id: AtomicI32,
+}
+
+impl MemorySessionStore {
+    fn retain(&self, keep: impl Fn(&SessionRecord) -> bool) -> u64 {
+        let mut lock = self.sessions.write().expect("Couldn't acquire lock");
+        let before = lock.len();
+        lock.retain(|_k, v| keep(v));
+        (before - lock.len()) as u64
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn insert(&self, session: NewSession) -> Result<(), anyhow::Error> {
+        let mut lock = self.sessions.write().expect("Couldn't acquire lock");
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+        lock.insert(
+            session.hashed_session_key.clone(),
+            SessionRecord {
+                id,
+                hashed_session_key: session.hashed_session_key,
+                user_id: session.user_id,
+                expiry: session.expiry,
+                created_ts: Some(session.created_ts),
+                last_seen_ts: Some(session.created_ts),
+                user_agent: session.user_agent,
+                ip_address: session.ip_address,
+            },
+        );
+        Ok(())
+    }
+
+    async fn extend(
+        &self,
+        hashed_session_key: &str,
+        expiry: i64,
+        now: i64,
+    ) -> Result<bool, anyhow::Error> {
+        let mut lock = self.sessions.write().expect("Couldn't acquire lock");
+
+        match lock
+            .get_mut(hashed_session_key)
+            .filter(|session| session.expiry > now)
+        {
+            Some(session) => {
+                session.expiry = expiry;
+                session.last_seen_ts = Some(now);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn get(&self, hashed_session_key: &str) -> Result<Option<SessionRecord>, anyhow::Error> {
+        let lock = self.sessions.read().expect("Couldn't acquire lock");
+        Ok(lock.get(hashed_session_key).cloned())
+    }
+
+    async fn list(&self, user_id: Uuid, now: i64) -> Result<Vec<SessionRecord>, anyhow::Error> {
+        let lock = self.sessions.read().expect("Couldn't acquire lock");
+        let mut sessions: Vec<SessionRecord> = lock
+            .values()
+            .filter(|session| session.user_id == user_id && session.expiry > now)
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| b.created_ts.cmp(&a.created_ts).then(b.id.cmp(&a.id)));
+        Ok(sessions)
+    }
+
+    async fn delete(&self, user_id: Uuid, session_id: i32) -> Result<bool, anyhow::Error> {
+        Ok(self.retain(|session| !(session.user_id == user_id && session.id == session_id)) > 0)
+    }
+
+    async fn delete_by_key(
+        &self,
+        user_id: Uuid,
+        hashed_session_key: &str,
+    ) -> Result<bool, anyhow::Error> {
+        Ok(self.retain(|session| {
+            !(session.user_id == user_id && session.hashed_session_key == hashed_session_key)
+        }) > 0)
+    }
+
+    async fn delete_others(
+        &self,
+        user_id: Uuid,
+        hashed_session_key: &str,
+    ) -> Result<u64, anyhow::Error> {
+        Ok(self.retain(|session| {
+            session.user_id != user_id || session.hashed_session_key == hashed_session_key
+        }))
+    }
+
+    async fn delete_all(&self, user_id: Uuid) -> Result<u64, anyhow::Error> {
+        Ok(self.retain(|session| session.user_id != user_id))
+    }
+
+    async fn delete_expired(&self, now: i64) -> Result<u64, anyhow::Error> {
+        Ok(self.retain(|session| session.expiry >= now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: Uuid = Uuid::from_u128(1);
+    const OTHER: Uuid = Uuid::from_u128(2);
+
+    fn new_session(hashed_session_key: &str, user_id: Uuid, created_ts: i64) -> NewSession {
+        NewSession {
+            hashed_session_key: hashed_session_key.to_string(),
+            user_id,
+            expiry: created_ts + 100,
+            created_ts,
+            user_agent: None,
+            ip_address: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_store_only_extends_unexpired_sessions() {
+        let store = MemorySessionStore::default();
+        store.insert(new_session("key", USER, 0)).await.unwrap();
+
+        assert!(store.extend("key", 150, 50).await.unwrap());
+        let session = store.get("key").await.unwrap().unwrap();
+        assert_eq!(session.expiry, 150);
+        assert_eq!(session.last_seen_ts, Some(50));
+
+        assert!(!store.extend("key", 300, 150).await.unwrap());
+        assert!(!store.extend("unknown", 150, 50).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn memory_store_lists_newest_first() {
+        let store = MemorySessionStore::default();
+        store.insert(new_session("first", USER, 0)).await.unwrap();
+        store.insert(new_session("second", USER, 10)).await.unwrap();
+        store.insert(new_session("other", OTHER, 10)).await.unwrap();
+
+        let sessions = store.list(USER, 50).await.unwrap();
+        let keys: Vec<&str> = sessions
+            .iter()
+            .map(|s| s.hashed_session_key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["second", "first"]);
+        assert_eq!(store.list(USER, 105).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_store_deletes_only_the_users_sessions() {
+        let store = MemorySessionStore::default();
+        store.insert(new_session("first", USER, 0)).await.unwrap();
+        store.insert(new_session("second", USER, 0)).await.unwrap();
+        store.insert(new_session("other", OTHER, 0)).await.unwrap();
+
+        assert!(!store.delete_by_key(OTHER, "first").await.unwrap());
+        assert_eq!(store.delete_others(USER, "first").await.unwrap(), 1);
+        assert_eq!(store.delete_all(USER).await.unwrap(), 1);
+        assert!(store.get("other").await.unwrap().is_some());
+        assert_eq!(store.delete_expired(200).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::user::User;
+use crate::utilities::{generate_unique_id, hash_token};
+use crate::{AppState, PENDING_LOGIN_STORE};
+use chrono::Utc;
+use rand::{RngCore, thread_rng};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use totp_rs::{Algorithm, Secret, TOTP};
+use tracing::{Level, event};
+use uuid::Uuid;
+
+// RFC 6238 defaults, these are what authenticator apps expect
+const TOTP_DIGITS: usize = 6;
+const TOTP_SKEW: u8 = 1;
+const TOTP_STEP: u64 = 30;
+const SECRET_LENGTH: usize = 20;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: u8 = 10;
+
+const PENDING_LOGIN_EXPIRATION: i64 = 300;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct PendingLogin {
+    pub user_id: Uuid,
+    pub created_ts: i64,
+}
+
+pub struct TotpSecret {
+    pub secret: String,
+    pub confirmed: bool,
+    pub last_used_step: i64,
+}
+
+pub fn build_totp(secret: &str, issuer: &str, email: &str) -> Result<TOTP, anyhow::Error> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()?;
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW,
+        TOTP_STEP,
+        secret,
+        Some(issuer.to_string()),
+        email.to_string(),
+    )?;
+    Ok(totp)
+}
+
+// Returns the time step the code is valid for, ignoring any step at or before
+// the last one used so a code can't be replayed
+pub fn matching_step(totp: &TOTP, code: &str, last_used_step: i64, now: u64) -> Option<i64> {
+    let current_step = (now / TOTP_STEP) as i64;
+    let skew = TOTP_SKEW as i64;
+
+    (current_step - skew..=current_step + skew)
+        .filter(|step| *step > last_used_step)
+        .find(|step| totp.generate(*step as u64 * TOTP_STEP) == code)
+}
+
+pub async fn get_totp_secret(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<Option<TotpSecret>, anyhow::Error> {
+    let row = sqlx::query_as!(
+        TotpSecret,
+        r#"SELECT
+            secret as "secret!",
+            confirmed as "confirmed!",
+            last_used_step as "last_used_step!"
+        FROM totp_secrets WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    Ok(row)
+}
+
+pub async fn is_two_factor_enabled(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<bool, anyhow::Error> {
+    Ok(get_totp_secret(state, user_id)
+        .await?
+        .is_some_and(|secret| secret.confirmed))
+}
+
+pub async fn start_enrollment(
+    state: Arc<AppState>,
+    user: &User,
+) -> Result<TwoFactorEnrollment, anyhow::Error> {
+    let mut secret_bytes = vec![0u8; SECRET_LENGTH];
+    thread_rng().fill_bytes(&mut secret_bytes);
+    let secret = Secret::Raw(secret_bytes).to_encoded().to_string();
+
+    let totp = build_totp(&secret, &state.config.server.totp_issuer, &user.email)?;
+
+    // Starting again replaces any enrollment which was never confirmed
+    sqlx::query!(
+        "INSERT INTO totp_secrets (user_id, secret, confirmed, last_used_step, created_ts) VALUES ($1, $2, false, 0, $3)
+        ON CONFLICT (user_id) DO UPDATE SET secret = $2, confirmed = false, last_used_step = 0, created_ts = $3",
+        user.id,
+        &secret,
+        Utc::now().timestamp()
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(TwoFactorEnrollment {
+        provisioning_uri: totp.get_url(),
+        secret,
+    })
+}
+
+pub async fn verify_totp_code(
+    state: Arc<AppState>,
+    user: &User,
+    totp_secret: &TotpSecret,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let totp = build_totp(
+        &totp_secret.secret,
+        &state.config.server.totp_issuer,
+        &user.email,
+    )?;
+    let now = Utc::now().timestamp() as u64;
+
+    match matching_step(&totp, code.trim(), totp_secret.last_used_step, now) {
+        // The step is only recorded if no other request has used it, so the code can't be raced
+        Some(step) => {
+            let result = sqlx::query!(
+                "UPDATE totp_secrets SET last_used_step = $1
+                WHERE user_id = $2 AND (last_used_step IS NULL OR last_used_step < $1)",
+                step,
+                user.id
+            )
+            .execute(&state.db_connection_pool)
+            .await?;
+            Ok(result.rows_affected() == 1)
+        }
+        None => Ok(false),
+    }
+}
+
+pub async fn confirm_enrollment(state: Arc<AppState>, user_id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE totp_secrets SET confirmed = true WHERE user_id = $1",
+        user_id
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+    Ok(())
+}
+
+// Both are removed together so a failure can't leave recovery codes without a second factor
+pub async fn disable_two_factor(state: Arc<AppState>, user_id: Uuid) -> Result<(), anyhow::Error> {
+    let mut transaction = state.db_connection_pool.begin().await?;
+    sqlx::query!("DELETE FROM totp_secrets WHERE user_id = $1", user_id)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Replaces any existing recovery codes, the plain codes are only ever returned here
+pub async fn generate_recovery_codes(
+    state: Arc<AppState>,
+    user_id: Uuid,
+) -> Result<Vec<String>, anyhow::Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_unique_id(RECOVERY_CODE_LENGTH))
+        .collect();
+    let hashed_codes: Vec<String> = codes.iter().map(|code| hash_token(code)).collect();
+
+    let mut transaction = state.db_connection_pool.begin().await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO recovery_codes (user_id, hashed_code) SELECT $1, * FROM UNNEST($2::VARCHAR[])",
+        user_id,
+        &hashed_codes
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(codes)
+}
+
+pub async fn use_recovery_code(
+    state: Arc<AppState>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        "UPDATE recovery_codes SET used = true WHERE user_id = $1 AND hashed_code = $2 AND used = false",
+        user_id,
+        hash_token(&code.trim().to_uppercase())
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        event!(Level::INFO, "Recovery code used for second factor");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+// Accepts either a code from the user's authenticator or one of their recovery codes
+pub async fn verify_second_factor(
+    state: Arc<AppState>,
+    user: &User,
+    code: &str,
+) -> Result<bool, anyhow::Error> {
+    let Some(totp_secret) = get_totp_secret(state.clone(), user.id).await? else {
+        return Ok(false);
+    };
+    if !totp_secret.confirmed {
+        return Ok(false);
+    }
+
+    if verify_totp_code(state.clone(), user, &totp_secret, code).await? {
+        return Ok(true);
+    }
+    use_recovery_code(state, user.id, code).await
+}
+
+pub fn create_pending_login(user_id: Uuid) -> String {
+    let mut lock = PENDING_LOGIN_STORE.write().expect("Couldn't acquire lock");
+    let token = generate_unique_id(50);
+    let now = Utc::now().timestamp();
+
+    lock.retain(|_k, v| v.created_ts + PENDING_LOGIN_EXPIRATION > now);
+
+    lock.insert(
+        token.clone(),
+        PendingLogin {
+            user_id,
+            created_ts: now,
+        },
+    );
+    token
+}
+
+pub fn get_pending_login(token: &str) -> Option<PendingLogin> {
+    let lock = PENDING_LOGIN_STORE.read().expect("Couldn't acquire lock");
+    let now = Utc::now().timestamp();
+
+    lock.get(token)
+        .filter(|pending_login| pending_login.created_ts + PENDING_LOGIN_EXPIRATION > now)
+        .cloned()
+}
+
+pub fn remove_pending_login(token: &str) {
+    let mut lock = PENDING_LOGIN_STORE.write().expect("Couldn't acquire lock");
+    lock.remove(token);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SECRET: &str = "OBWGC2LOFVZXI4TJNZTS243FMNZGK5BNGEZDG";
+
+    #[test]
+    fn code_for_current_step_matches() {
+        let totp = build_totp(TEST_SECRET, "Axumatic", "test@example.com").unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now);
+        assert_eq!(
+            matching_step(&totp, &code, 0, now),
+            Some((now / TOTP_STEP) as i64)
+        );
+    }
+
+    #[test]
+    fn code_within_skew_matches() {
+        let totp = build_totp(TEST_SECRET, "Axumatic", "test@example.com").unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now - TOTP_STEP);
+        assert!(matching_step(&totp, &code, 0, now).is_some());
+    }
+
+    #[test]
+    fn code_outside_skew_does_not_match() {
+        let totp = build_totp(TEST_SECRET, "Axumatic", "test@example.com").unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now - 3 * TOTP_STEP);
+        assert!(matching_step(&totp, &code, 0, now).is_none());
+    }
+
+    #[test]
+    fn used_step_cannot_be_replayed() {
+        let totp = build_totp(TEST_SECRET, "Axumatic", "test@example.com").unwrap();
+        let now = 1_700_000_000;
+        let code = totp.generate(now);
+        let step = matching_step(&totp, &code, 0, now).unwrap();
+        assert!(matching_step(&totp, &code, step, now).is_none());
+    }
+}
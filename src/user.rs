@@ -2,23 +2,29 @@ use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
+use crate::auth::Session;
 use crate::config::AppState;
 use std::sync::Arc;
+use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Debug, FromRow)]
+#[derive(Clone, Serialize, Deserialize, Debug, FromRow)]
 pub struct User {
+    pub id: Uuid,
     pub username: String,
     pub email: String,
     pub email_verified: bool,
     pub hashed_password: Option<String>,
     pub auth_level: String,
     pub login_attempts: i32,
+    pub last_failed_login_ts: Option<i64>,
+    pub locked_until_ts: Option<i64>,
     pub registration_ts: i64,
     pub identity_provider: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Profile {
+    pub id: Uuid,
     pub username: String,
     pub email: String,
     pub email_verified: bool,
@@ -30,6 +36,7 @@ pub struct Profile {
 impl From<User> for Profile {
     fn from(value: User) -> Self {
         Self {
+            id: value.id,
             username: value.username,
             email: value.email,
             email_verified: value.email_verified,
@@ -40,20 +47,40 @@ impl From<User> for Profile {
     }
 }
 
-pub async fn get_user_by_email(state: Arc<AppState>, email: &str) -> Result<User, anyhow::Error> {
+// A page of users as returned by the admin user listing
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserPage {
+    pub users: Vec<Profile>,
+    pub total: i64,
+    pub page: i64,
+    pub page_size: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminUserDetails {
+    pub profile: Profile,
+    pub login_attempts: i32,
+    pub locked_until_ts: Option<i64>,
+    pub sessions: Vec<Session>,
+}
+
+pub async fn get_user_by_id(state: Arc<AppState>, id: Uuid) -> Result<User, anyhow::Error> {
     let row = sqlx::query_as!(
         User,
         r#"SELECT 
+            id,
             username as "username!", 
             email as "email!", 
             email_verified as "email_verified!", 
             hashed_password, 
             auth_level as "auth_level!", 
             login_attempts as "login_attempts!", 
+            last_failed_login_ts, 
+            locked_until_ts, 
             registration_ts as "registration_ts!", 
             identity_provider as "identity_provider!" 
-        FROM users WHERE email = $1"#,
-        email
+        FROM users WHERE id = $1"#,
+        id
     )
     .fetch_optional(&state.db_connection_pool)
     .await?;
@@ -64,19 +91,57 @@ pub async fn get_user_by_email(state: Arc<AppState>, email: &str) -> Result<User
     }
 }
 
-pub async fn get_user_by_sub(state: Arc<AppState>, sub: &str) -> Result<User, anyhow::Error> {
+pub async fn get_user_by_email(state: Arc<AppState>, email: &str) -> Result<User, anyhow::Error> {
     let row = sqlx::query_as!(
         User,
         r#"SELECT 
+            id,
             username as "username!", 
             email as "email!", 
             email_verified as "email_verified!", 
             hashed_password, 
             auth_level as "auth_level!", 
             login_attempts as "login_attempts!", 
+            last_failed_login_ts, 
+            locked_until_ts, 
             registration_ts as "registration_ts!", 
             identity_provider as "identity_provider!" 
-        FROM users WHERE sub = $1"#,
+        FROM users WHERE email = $1"#,
+        email
+    )
+    .fetch_optional(&state.db_connection_pool)
+    .await?;
+
+    match row {
+        Some(r) => Ok(r),
+        None => Err(anyhow!("User not found")),
+    }
+}
+
+// Subject identifiers are only unique within a single identity provider
+pub async fn get_user_by_sub(
+    state: Arc<AppState>,
+    identity_provider: &str,
+    sub: &str,
+) -> Result<User, anyhow::Error> {
+    let row = sqlx::query_as!(
+        User,
+        r#"SELECT 
+            users.id,
+            users.username as "username!", 
+            users.email as "email!", 
+            users.email_verified as "email_verified!", 
+            users.hashed_password, 
+            users.auth_level as "auth_level!", 
+            users.login_attempts as "login_attempts!", 
+            users.last_failed_login_ts, 
+            users.locked_until_ts, 
+            users.registration_ts as "registration_ts!", 
+            users.identity_provider as "identity_provider!" 
+        FROM users
+        JOIN user_identities ON user_identities.user_id = users.id
+        WHERE user_identities.identity_provider = $1 AND user_identities.sub = $2"#,
+        identity_provider,
         sub
     )
     .fetch_optional(&state.db_connection_pool)
@@ -95,12 +160,15 @@ pub async fn get_user_by_username(
     let row = sqlx::query_as!(
         User,
         r#"SELECT 
+            id,
             username as "username!", 
             email as "email!", 
             email_verified as "email_verified!", 
             hashed_password, 
             auth_level as "auth_level!", 
             login_attempts as "login_attempts!", 
+            last_failed_login_ts, 
+            locked_until_ts, 
             registration_ts as "registration_ts!", 
             identity_provider as "identity_provider!" 
         FROM users WHERE username = $1"#,
@@ -122,7 +190,8 @@ pub async fn update_google_user_email(
     sub: &str,
 ) -> Result<(), anyhow::Error> {
     sqlx::query!(
-        "UPDATE users SET email = $1, email_verified = $2 WHERE sub = $3",
+        "UPDATE users SET email = $1, email_verified = $2
+        WHERE id = (SELECT user_id FROM user_identities WHERE identity_provider = 'google' AND sub = $3)",
         new_email,
         email_verified,
         sub
@@ -132,3 +201,103 @@ pub async fn update_google_user_email(
 
     Ok(())
 }
+
+// Matches the search against usernames and emails, returning a page of users and the total matched
+pub async fn search_users(
+    state: Arc<AppState>,
+    search: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<User>, i64), anyhow::Error> {
+    let pattern = match search {
+        Some(search) => format!(
+            "%{}%",
+            search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        ),
+        None => "%".to_string(),
+    };
+
+    let users = sqlx::query_as!(
+        User,
+        r#"SELECT 
+            id,
+            username as "username!", 
+            email as "email!", 
+            email_verified as "email_verified!", 
+            hashed_password, 
+            auth_level as "auth_level!", 
+            login_attempts as "login_attempts!", 
+            last_failed_login_ts, 
+            locked_until_ts, 
+            registration_ts as "registration_ts!", 
+            identity_provider as "identity_provider!" 
+        FROM users WHERE username ILIKE $1 OR email ILIKE $1
+        ORDER BY registration_ts DESC, username
+        LIMIT $2 OFFSET $3"#,
+        &pattern,
+        limit,
+        offset
+    )
+    .fetch_all(&state.db_connection_pool)
+    .await?;
+
+    let total = sqlx::query!(
+        r#"SELECT COUNT(*) as "total!" FROM users WHERE username ILIKE $1 OR email ILIKE $1"#,
+        &pattern
+    )
+    .fetch_one(&state.db_connection_pool)
+    .await?
+    .total;
+
+    Ok((users, total))
+}
+
+pub async fn set_email_verified(state: Arc<AppState>, id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!("UPDATE users SET email_verified = true WHERE id = $1", id)
+        .execute(&state.db_connection_pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn reset_login_attempts(state: Arc<AppState>, id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        "UPDATE users SET login_attempts = 0, last_failed_login_ts = NULL, locked_until_ts = NULL WHERE id = $1",
+        id
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(())
+}
+
+// Returns false if there is no role with that name
+pub async fn set_auth_level(
+    state: Arc<AppState>,
+    id: Uuid,
+    auth_level: &str,
+) -> Result<bool, anyhow::Error> {
+    let result = sqlx::query!(
+        "UPDATE users SET auth_level = $1 WHERE id = $2 AND EXISTS(SELECT 1 FROM roles WHERE name = $1)",
+        auth_level,
+        id
+    )
+    .execute(&state.db_connection_pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+// Sessions, codes and other credentials are removed along with the user
+pub async fn delete_user(state: Arc<AppState>, id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!("DELETE FROM users WHERE id = $1", id)
+        .execute(&state.db_connection_pool)
+        .await?;
+    // Sessions aren't necessarily in the database so can't rely on the cascade
+    state.session_store.delete_all(id).await?;
+
+    Ok(())
+}
@@ -4,13 +4,18 @@ use argon2::{
 };
 use lettre::{Message, Transport};
 use rand::{Rng, thread_rng};
+use sha2::{Digest, Sha256};
 
 use tracing::{Level, event};
 
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
 
 use crate::AppState;
 
+// Checked against when there is no real hash so failed logins take as long whether or not the account exists
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password(&generate_unique_id(20)));
+
 use chrono::Utc;
 
 #[derive(Debug)]
@@ -58,6 +63,15 @@ pub fn verify_password(hash: &str, password: &str) -> bool {
         .is_ok()
 }
 
+pub fn verify_dummy_password(password: &str) {
+    let _ = verify_password(&DUMMY_PASSWORD_HASH, password);
+}
+
+// Used for high entropy random values such as recovery codes where argon2 isn't needed
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 pub fn generate_unique_id(length: u8) -> String {
     let mut rng = thread_rng();
     const CHARACTER_SET: [char; 36] = [
@@ -72,14 +86,23 @@ pub fn generate_unique_id(length: u8) -> String {
 pub async fn start_session_cleaner(state: Arc<AppState>) {
     tokio::spawn(async move {
         loop {
-            let now = Utc::now().timestamp() as i32;
-            let delete = sqlx::query!("DELETE FROM sessions WHERE $1 > expiry", now)
-                .execute(&state.db_connection_pool)
+            let delete = state
+                .session_store
+                .delete_expired(Utc::now().timestamp())
                 .await;
             match delete {
                 Ok(_v) => event!(Level::INFO, "Expired sessions deleted"),
                 Err(e) => event!(Level::WARN, "Failed to delete sessions due to {}", e),
             };
+            let delete = sqlx::query!(
+                "DELETE FROM refresh_tokens WHERE $1 > expiry_ts",
+                Utc::now().timestamp()
+            )
+            .execute(&state.db_connection_pool)
+            .await;
+            if let Err(e) = delete {
+                event!(Level::WARN, "Failed to delete refresh tokens due to {}", e);
+            }
             tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
         }
     });
@@ -1,17 +1,55 @@
-use axumatic::config::get_config;
+use axum::Json;
+use axum::extract::{Form, Query, State};
+use axum::response::Redirect;
+use axum::routing::{get, post};
+use axumatic::api_token::{ApiToken, CreatedApiToken};
+use axumatic::auth::{AuthContext, Session, SessionMetadata};
+use axumatic::config::{
+    AppState, GitHubConfig, JwtConfig, OidcProviderConfig, RateLimitKey, RateLimitPolicy,
+    get_config,
+};
 use axumatic::default_route_handlers::{
-    ApiResponse, ChangePassword, LoginDetails, PasswordResetCompleteRequest,
-    PasswordResetInitiateRequest, ResponseType,
+    ApiResponse, ChangeAuthLevel, ChangeEmail, ChangePassword, CreateApiTokenRequest,
+    CreateOAuthClientRequest, DisableTwoFactor, EmailChangeCode, GoogleIdentity,
+    LinkIdentityRequest, LoginDetails, MagicLoginCompleteRequest, MagicLoginRequest, OAuthConsent,
+    PasswordResetCompleteRequest, PasswordResetInitiateRequest, RefreshTokenRequest, ResponseType,
+    TokenRequest, TwoFactorCode, TwoFactorLoginDetails, complete_google_login,
+};
+use axumatic::identity::UserIdentity;
+use axumatic::jwt::{AccessTokenClaims, TokenPair};
+use axumatic::keys::{JwkSet, KeyStore};
+use axumatic::middleware::ValidateSessionLayer;
+use axumatic::oauth::{
+    AuthorizationRequestDetails, CreatedOAuthClient, IdTokenClaims as OAuthIdTokenClaims,
+    OAuthErrorResponse, OAuthTokenResponse, OpenIdConfiguration, UserInfo,
 };
-use axumatic::utilities::generate_unique_id;
+use axumatic::oidc::{IdTokenClaims, pkce_challenge};
+use axumatic::passkey::{
+    AssertionResponse, AttestationResponse, AuthenticationCredential, BASE64_URL, Passkey,
+    RegistrationCredential,
+};
+use axumatic::roles::Role;
+use axumatic::session_store::MemorySessionStore;
+use axumatic::two_factor::{TwoFactorEnrollment, build_totp};
+use axumatic::user::{AdminUserDetails, Profile, User, UserPage};
+use axumatic::utilities::{generate_unique_id, hash_token};
 use axumatic::{default_route_handlers::RegistrationDetails, get_app, get_app_state};
+use base64::Engine;
+use ciborium::Value;
 use http::header::{CONTENT_TYPE, COOKIE};
 use http::{HeaderValue, StatusCode};
+use jsonwebtoken::{EncodingKey, Header};
+use p256::ecdsa::{DerSignature, SigningKey, signature::Signer};
+use p256::elliptic_curve::rand_core::OsRng;
+use p256::pkcs8::EncodePrivateKey;
 use reqwest::header::HeaderMap;
 use reqwest::{Client, Response};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::sync::Once;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Once};
+use uuid::Uuid;
 
 static INIT: Once = Once::new();
 
@@ -28,7 +66,7 @@ fn init_tracing() {
 #[derive(Serialize, Deserialize, Debug)]
 struct Code {
     code_type: Option<String>,
-    email: Option<String>,
+    user_id: Option<Uuid>,
     code: Option<String>,
     created_ts: Option<i64>,
     expiry_ts: Option<i64>,
@@ -39,16 +77,337 @@ async fn run_test_app() -> u16 {
     init_tracing();
 
     let state = get_app_state().await;
-    let app = get_app(state.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    serve_test_app(listener, state)
+}
+
+fn serve_test_app(listener: tokio::net::TcpListener, state: Arc<AppState>) -> u16 {
+    let app = get_app(state);
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    port
+}
+
+const MOCK_CLIENT_ID: &str = "axumatic-test";
+
+// A minimal OpenID Connect issuer which signs ID tokens for a single subject
+struct MockIssuer {
+    issuer: String,
+    signing_key: SigningKey,
+    sub: String,
+    email: String,
+    // Authorization code mapped to the nonce and PKCE challenge it was issued for
+    codes: Mutex<HashMap<String, (String, String)>>,
+}
+
+#[derive(Deserialize)]
+struct MockAuthorizeRequest {
+    redirect_uri: String,
+    state: String,
+    nonce: String,
+    code_challenge: String,
+    code_challenge_method: String,
+}
+
+#[derive(Deserialize)]
+struct MockTokenRequest {
+    code: String,
+    code_verifier: String,
+    client_id: String,
+}
+
+#[derive(Serialize)]
+struct MockIdToken {
+    #[serde(flatten)]
+    claims: IdTokenClaims,
+    iss: String,
+    aud: String,
+    exp: i64,
+    iat: i64,
+}
+
+async fn mock_discovery(State(issuer): State<Arc<MockIssuer>>) -> axum::Json<serde_json::Value> {
+    axum::Json(serde_json::json!({
+        "issuer": issuer.issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer.issuer),
+        "token_endpoint": format!("{}/token", issuer.issuer),
+        "jwks_uri": format!("{}/jwks", issuer.issuer),
+    }))
+}
+
+async fn mock_jwks(State(issuer): State<Arc<MockIssuer>>) -> axum::Json<serde_json::Value> {
+    let point = issuer.signing_key.verifying_key().to_encoded_point(false);
+    axum::Json(serde_json::json!({
+        "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "test-key",
+            "use": "sig",
+            "alg": "ES256",
+            "x": BASE64_URL.encode(point.x().unwrap()),
+            "y": BASE64_URL.encode(point.y().unwrap()),
+        }]
+    }))
+}
+
+async fn mock_authorize(
+    State(issuer): State<Arc<MockIssuer>>,
+    Query(request): Query<MockAuthorizeRequest>,
+) -> Redirect {
+    assert_eq!(request.code_challenge_method, "S256");
+    let code = generate_unique_id(20);
+    issuer
+        .codes
+        .lock()
+        .unwrap()
+        .insert(code.clone(), (request.nonce, request.code_challenge));
+    Redirect::to(&format!(
+        "{}?code={}&state={}",
+        request.redirect_uri, code, request.state
+    ))
+}
+
+async fn mock_token(
+    State(issuer): State<Arc<MockIssuer>>,
+    Form(request): Form<MockTokenRequest>,
+) -> Result<axum::Json<serde_json::Value>, StatusCode> {
+    let (nonce, code_challenge) = issuer
+        .codes
+        .lock()
+        .unwrap()
+        .remove(&request.code)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if pkce_challenge(&request.code_verifier) != code_challenge {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let id_token = MockIdToken {
+        claims: IdTokenClaims {
+            sub: issuer.sub.clone(),
+            email: Some(issuer.email.clone()),
+            email_verified: Some(true),
+            nonce: Some(nonce),
+        },
+        iss: issuer.issuer.clone(),
+        aud: request.client_id,
+        exp: now + 300,
+        iat: now,
+    };
+    let mut header = Header::new(jsonwebtoken::Algorithm::ES256);
+    header.kid = Some("test-key".to_string());
+    let der = issuer.signing_key.to_pkcs8_der().unwrap();
+    let id_token = jsonwebtoken::encode(
+        &header,
+        &id_token,
+        &EncodingKey::from_ec_der(der.as_bytes()),
+    )
+    .unwrap();
+
+    Ok(axum::Json(serde_json::json!({
+        "access_token": generate_unique_id(20),
+        "token_type": "Bearer",
+        "id_token": id_token,
+    })))
+}
 
+async fn run_mock_issuer(email: &str) -> String {
     let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer_url = format!("{}:{}", SERVER_URL, listener.local_addr().unwrap().port());
+    let issuer = Arc::new(MockIssuer {
+        issuer: issuer_url.clone(),
+        signing_key: SigningKey::random(&mut OsRng),
+        sub: generate_unique_id(20),
+        email: email.to_string(),
+        codes: Mutex::new(HashMap::new()),
+    });
+    let app = axum::Router::new()
+        .route("/.well-known/openid-configuration", get(mock_discovery))
+        .route("/jwks", get(mock_jwks))
+        .route("/authorize", get(mock_authorize))
+        .route("/token", post(mock_token))
+        .with_state(issuer);
 
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    issuer_url
+}
+
+// Runs the app with the mock issuer configured as the "mock" provider
+async fn run_test_app_with_oidc_provider(issuer_url: &str) -> u16 {
+    init_tracing();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
     let port = listener.local_addr().unwrap().port();
 
+    let mut state = (*get_app_state().await).clone();
+    state.config.oidc_providers.push(OidcProviderConfig {
+        id: "mock".to_string(),
+        issuer: issuer_url.to_string(),
+        discovery_url: format!("{}/.well-known/openid-configuration", issuer_url),
+        client_id: MOCK_CLIENT_ID.to_string(),
+        client_secret: None,
+        scopes: vec!["openid".to_string(), "email".to_string()],
+        redirect_uri: format!("{}:{}/account/callback/mock", SERVER_URL, port),
+    });
+    serve_test_app(listener, Arc::new(state))
+}
+
+// Stands in for both github.com and api.github.com
+struct MockGitHub {
+    id: i64,
+    email: String,
+    email_verified: bool,
+    // Authorization code mapped to the PKCE challenge it was issued for
+    codes: Mutex<HashMap<String, String>>,
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct MockGitHubAuthorizeRequest {
+    redirect_uri: String,
+    state: String,
+    code_challenge: String,
+}
+
+async fn mock_github_authorize(
+    State(github): State<Arc<MockGitHub>>,
+    Query(request): Query<MockGitHubAuthorizeRequest>,
+) -> Redirect {
+    let code = generate_unique_id(20);
+    github
+        .codes
+        .lock()
+        .unwrap()
+        .insert(code.clone(), request.code_challenge);
+    Redirect::to(&format!(
+        "{}?code={}&state={}",
+        request.redirect_uri, code, request.state
+    ))
+}
+
+async fn mock_github_access_token(
+    State(github): State<Arc<MockGitHub>>,
+    Form(request): Form<MockTokenRequest>,
+) -> axum::Json<serde_json::Value> {
+    let code_challenge = github.codes.lock().unwrap().remove(&request.code);
+    if code_challenge != Some(pkce_challenge(&request.code_verifier)) {
+        return axum::Json(serde_json::json!({ "error": "bad_verification_code" }));
+    }
+    axum::Json(serde_json::json!({
+        "access_token": github.access_token,
+        "token_type": "bearer",
+        "scope": "read:user,user:email",
+    }))
+}
+
+fn check_github_token(github: &MockGitHub, headers: &http::HeaderMap) -> Result<(), StatusCode> {
+    let expected = format!("Bearer {}", github.access_token);
+    if headers.get("authorization").and_then(|v| v.to_str().ok()) != Some(expected.as_str())
+        || headers.get("user-agent").is_none()
+    {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+async fn mock_github_user(
+    State(github): State<Arc<MockGitHub>>,
+    headers: http::HeaderMap,
+) -> Result<axum::Json<serde_json::Value>, StatusCode> {
+    check_github_token(&github, &headers)?;
+    Ok(axum::Json(
+        serde_json::json!({ "id": github.id, "login": "octocat" }),
+    ))
+}
+
+async fn mock_github_emails(
+    State(github): State<Arc<MockGitHub>>,
+    headers: http::HeaderMap,
+) -> Result<axum::Json<serde_json::Value>, StatusCode> {
+    check_github_token(&github, &headers)?;
+    Ok(axum::Json(serde_json::json!([
+        { "email": "octocat@users.noreply.github.com", "primary": false, "verified": true },
+        { "email": github.email, "primary": true, "verified": github.email_verified },
+    ])))
+}
+
+async fn run_mock_github(email: &str, email_verified: bool) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let github_url = format!("{}:{}", SERVER_URL, listener.local_addr().unwrap().port());
+    let github = Arc::new(MockGitHub {
+        id: rand::random::<u32>() as i64,
+        email: email.to_string(),
+        email_verified,
+        codes: Mutex::new(HashMap::new()),
+        access_token: generate_unique_id(30),
+    });
+    let app = axum::Router::new()
+        .route("/login/oauth/authorize", get(mock_github_authorize))
+        .route("/login/oauth/access_token", post(mock_github_access_token))
+        .route("/user", get(mock_github_user))
+        .route("/user/emails", get(mock_github_emails))
+        .with_state(github);
+
     tokio::spawn(async move {
         axum::serve(listener, app).await.unwrap();
     });
-    port
+    github_url
+}
+
+async fn run_test_app_with_github(github_url: &str) -> u16 {
+    init_tracing();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut state = (*get_app_state().await).clone();
+    state.config.github = Some(GitHubConfig {
+        client_id: MOCK_CLIENT_ID.to_string(),
+        client_secret: Some(generate_unique_id(20)),
+        redirect_uri: format!("{}:{}/account/callback/github", SERVER_URL, port),
+        oauth_url: github_url.to_string(),
+        api_url: github_url.to_string(),
+    });
+    serve_test_app(listener, Arc::new(state))
+}
+
+// The cookie binding the OIDC state to the browser, ready to send back at the callback
+fn state_cookie(response: &Response) -> String {
+    response
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok()?.split(';').next())
+        .find(|cookie| cookie.starts_with("oidc-state="))
+        .unwrap()
+        .to_string()
+}
+
+// Follows the redirects from the app to the provider and back, returning the callback response
+async fn external_login(client: &Client, login_url: &str) -> Response {
+    let response = client.get(login_url).send().await.unwrap();
+    assert!(response.status().is_redirection());
+    let state_cookie = state_cookie(&response);
+    let authorize_url = response.headers()["location"].to_str().unwrap().to_string();
+
+    let response = client.get(authorize_url).send().await.unwrap();
+    let callback_url = response.headers()["location"].to_str().unwrap().to_string();
+    client
+        .get(callback_url)
+        .header(COOKIE, state_cookie)
+        .send()
+        .await
+        .unwrap()
 }
 
 async fn _cleanup() -> Result<(), anyhow::Error> {
@@ -70,12 +429,6 @@ async fn delete_reg(email: String) -> Result<(), anyhow::Error> {
     sqlx::query!("DELETE FROM users WHERE email = $1", &email)
         .execute(&state.db_connection_pool)
         .await?;
-    sqlx::query!("DELETE FROM codes WHERE email = $1", &email)
-        .execute(&state.db_connection_pool)
-        .await?;
-    sqlx::query!("DELETE FROM sessions WHERE email = $1", &email)
-        .execute(&state.db_connection_pool)
-        .await?;
 
     state.db_connection_pool.close().await;
     Ok(())
@@ -130,6 +483,233 @@ async fn login(email: String, password: String, port: u16) -> Option<String> {
     None
 }
 
+async fn get_with_session(path: &str, session_key: &str, port: u16) -> Response {
+    let client = Client::new();
+    let url = format!("{}:{}{}", SERVER_URL, port, path);
+    client
+        .get(url)
+        .header(COOKIE, format!("session-key={session_key}"))
+        .send()
+        .await
+        .unwrap()
+}
+
+// The double-submit CSRF token must accompany the session cookie on state-changing requests
+const TEST_CSRF_TOKEN: &str = "TESTCSRFTOKEN0000000000000000000";
+
+fn session_cookies(session_key: &str) -> String {
+    format!("session-key={session_key}; csrf-token={TEST_CSRF_TOKEN}")
+}
+
+async fn delete_with_session(path: &str, session_key: &str, port: u16) -> Response {
+    let client = Client::new();
+    let url = format!("{}:{}{}", SERVER_URL, port, path);
+    client
+        .delete(url)
+        .header(COOKIE, session_cookies(session_key))
+        .header("X-CSRF-Token", TEST_CSRF_TOKEN)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn post_with_session<T: Serialize>(
+    path: &str,
+    session_key: &str,
+    body: &T,
+    port: u16,
+) -> Response {
+    let client = Client::new();
+    let url = format!("{}:{}{}", SERVER_URL, port, path);
+    client
+        .post(url)
+        .header(COOKIE, session_cookies(session_key))
+        .header("X-CSRF-Token", TEST_CSRF_TOKEN)
+        .json(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn login_response(email: String, password: String, port: u16) -> ApiResponse {
+    let url = format!("{}:{}/account/login", SERVER_URL, port);
+    let client = Client::new();
+    let login_details = LoginDetails { email, password };
+    client
+        .post(url)
+        .json(&login_details)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+async fn login_second_factor(token: String, code: String, port: u16) -> Response {
+    let url = format!("{}:{}/account/login/twoFactor", SERVER_URL, port);
+    let client = Client::new();
+    client
+        .post(url)
+        .json(&TwoFactorLoginDetails { token, code })
+        .send()
+        .await
+        .unwrap()
+}
+
+// Enables two factor for the user, returning their secret and recovery codes
+async fn enable_two_factor(session_key: &str, port: u16) -> (String, Vec<String>) {
+    let response: ApiResponse = post_with_session(
+        "/account/twoFactor/enroll",
+        session_key,
+        &serde_json::json!({}),
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::TwoFactorEnrollment);
+    let enrollment: TwoFactorEnrollment = serde_json::from_str(&response.message).unwrap();
+    assert!(enrollment.provisioning_uri.starts_with("otpauth://totp/"));
+
+    let totp = build_totp(&enrollment.secret, "Axumatic", "").unwrap();
+    let code = totp.generate_current().unwrap();
+    let response: ApiResponse = post_with_session(
+        "/account/twoFactor/confirm",
+        session_key,
+        &TwoFactorCode { code },
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::TwoFactorEnabled);
+    let recovery_codes: Vec<String> = serde_json::from_str(&response.message).unwrap();
+
+    (enrollment.secret, recovery_codes)
+}
+
+async fn get_sessions(session_key: &str, port: u16) -> Vec<Session> {
+    let response: ApiResponse = get_with_session("/account/sessions", session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::SessionList);
+    serde_json::from_str(&response.message).unwrap()
+}
+
+// Stands in for a platform authenticator, producing "none" attestations
+// and ES256 assertions the same way a browser would
+struct SoftwareAuthenticator {
+    signing_key: SigningKey,
+    credential_id: Vec<u8>,
+    sign_count: u32,
+}
+
+impl SoftwareAuthenticator {
+    fn new() -> Self {
+        Self {
+            signing_key: SigningKey::random(&mut OsRng),
+            credential_id: generate_unique_id(32).into_bytes(),
+            sign_count: 0,
+        }
+    }
+
+    fn client_data(ceremony_type: &str, options: &serde_json::Value) -> Vec<u8> {
+        serde_json::json!({
+            "type": ceremony_type,
+            "challenge": options["challenge"],
+            "origin": get_config().webauthn.origin,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn authenticator_data(&mut self, rp_id: &str, attested: bool) -> Vec<u8> {
+        self.sign_count += 1;
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(if attested { 0x45 } else { 0x05 });
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+
+        if attested {
+            let point = self.signing_key.verifying_key().to_encoded_point(false);
+            let cose_key = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(-7)),
+                (Value::from(-1), Value::from(1)),
+                (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+                (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            ciborium::into_writer(&cose_key, &mut data).unwrap();
+        }
+        data
+    }
+
+    fn register(&mut self, options: &serde_json::Value) -> RegistrationCredential {
+        let rp_id = options["rp"]["id"].as_str().unwrap().to_string();
+        let attestation_object = Value::Map(vec![
+            (Value::from("fmt"), Value::from("none")),
+            (Value::from("attStmt"), Value::Map(vec![])),
+            (
+                Value::from("authData"),
+                Value::Bytes(self.authenticator_data(&rp_id, true)),
+            ),
+        ]);
+        let mut encoded_attestation_object = Vec::new();
+        ciborium::into_writer(&attestation_object, &mut encoded_attestation_object).unwrap();
+
+        RegistrationCredential {
+            id: BASE64_URL.encode(&self.credential_id),
+            credential_type: "public-key".to_string(),
+            response: AttestationResponse {
+                client_data_json: BASE64_URL.encode(Self::client_data("webauthn.create", options)),
+                attestation_object: BASE64_URL.encode(encoded_attestation_object),
+            },
+        }
+    }
+
+    fn authenticate(&mut self, options: &serde_json::Value) -> AuthenticationCredential {
+        let rp_id = options["rpId"].as_str().unwrap().to_string();
+        let client_data = Self::client_data("webauthn.get", options);
+        let authenticator_data = self.authenticator_data(&rp_id, false);
+
+        let mut signed_data = authenticator_data.clone();
+        signed_data.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: DerSignature = self.signing_key.sign(&signed_data);
+
+        AuthenticationCredential {
+            id: BASE64_URL.encode(&self.credential_id),
+            credential_type: "public-key".to_string(),
+            response: AssertionResponse {
+                client_data_json: BASE64_URL.encode(client_data),
+                authenticator_data: BASE64_URL.encode(authenticator_data),
+                signature: BASE64_URL.encode(signature.as_bytes()),
+                user_handle: None,
+            },
+        }
+    }
+}
+
+async fn get_passkey_login_options(port: u16) -> serde_json::Value {
+    let client = Client::new();
+    let url = format!("{}:{}/account/login/passkeyOptions", SERVER_URL, port);
+    let response: ApiResponse = client.post(url).send().await.unwrap().json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::PasskeyLoginOptions);
+    serde_json::from_str(&response.message).unwrap()
+}
+
+async fn passkey_login(credential: &AuthenticationCredential, port: u16) -> Response {
+    let client = Client::new();
+    let url = format!("{}:{}/account/login/passkey", SERVER_URL, port);
+    client.post(url).json(credential).send().await.unwrap()
+}
+
 const SERVER_URL: &str = "http://localhost";
 
 #[tokio::test]
@@ -302,7 +882,7 @@ async fn change_password() {
     let session_key = login(email.clone(), password.clone(), port).await.unwrap();
 
     let new_password = generate_unique_id(20);
-    let session_cookie = format!("session-key={session_key}");
+    let session_cookie = session_cookies(&session_key);
 
     let change_password_request = ChangePassword {
         old_password: password.clone(),
@@ -319,6 +899,7 @@ async fn change_password() {
     );
 
     headers.insert(COOKIE, HeaderValue::from_str(&session_cookie).unwrap());
+    headers.insert("X-CSRF-Token", HeaderValue::from_static(TEST_CSRF_TOKEN));
 
     let response: ApiResponse = client
         .patch(url)
@@ -339,6 +920,189 @@ async fn change_password() {
     let _ = delete_reg(email).await;
 }
 
+#[tokio::test]
+async fn change_email() {
+    let port = run_test_app().await;
+    let client = Client::new();
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+    let new_email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+    let get_profile = async |session_key: &str| -> Profile {
+        let response: ApiResponse = get_with_session("/account/profile", session_key, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+        serde_json::from_str(&response.message).unwrap()
+    };
+    let original_profile = get_profile(&session_key).await;
+
+    let change_email = |password: &str| ChangeEmail {
+        password: password.to_string(),
+        new_email: new_email.clone(),
+    };
+    let response: ApiResponse = post_with_session(
+        "/account/changeEmail",
+        &session_key,
+        &change_email("incorrect_password"),
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.message, "Incorrect password");
+
+    let response: ApiResponse = post_with_session(
+        "/account/changeEmail",
+        &session_key,
+        &change_email(&password),
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::EmailChangeInitiated);
+
+    let pool = get_config().get_db_pool().await;
+    let get_code = |code_type: &'static str| {
+        sqlx::query!(
+            r#"SELECT code as "code!" FROM codes JOIN users ON users.id = codes.user_id
+            WHERE users.email = $1 AND code_type = $2 AND used = false"#,
+            &email,
+            code_type
+        )
+        .fetch_one(&pool)
+    };
+    let code = get_code("EmailChange").await.unwrap().code;
+    let revert_code = get_code("EmailChangeRevert").await.unwrap().code;
+
+    let response: ApiResponse = post_with_session(
+        "/account/changeEmail/confirm",
+        &session_key,
+        &EmailChangeCode {
+            code: "incorrect".to_string(),
+        },
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let response: ApiResponse = post_with_session(
+        "/account/changeEmail/confirm",
+        &session_key,
+        &EmailChangeCode { code },
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::EmailChanged);
+
+    // The session moves over with the account, which keeps its id
+    let profile = get_profile(&session_key).await;
+    assert_eq!(profile.email, new_email);
+    assert_eq!(profile.id, original_profile.id);
+    assert!(login(email.clone(), password.clone(), port).await.is_none());
+    assert!(
+        login(new_email.clone(), password.clone(), port)
+            .await
+            .is_some()
+    );
+
+    // The old address can move the account back and sign everyone out
+    let url = format!("{}:{}/account/changeEmail/revert", SERVER_URL, port);
+    let revert = EmailChangeCode { code: revert_code };
+    let response: ApiResponse = client
+        .post(&url)
+        .json(&revert)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::EmailChangeReverted);
+    let profile = get_with_session("/account/profile", &session_key, port).await;
+    assert_eq!(profile.status(), StatusCode::UNAUTHORIZED);
+    assert!(login(email.clone(), password.clone(), port).await.is_some());
+
+    let response: ApiResponse = client
+        .post(&url)
+        .json(&revert)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let _ = delete_reg(email).await;
+}
+
+// Custom routes get the signed in user from the extensions ValidateSession inserts
+#[tokio::test]
+async fn auth_context_extractors() {
+    init_tracing();
+    let state = get_app_state().await;
+
+    async fn whoami(user: Option<User>) -> String {
+        user.map(|user| user.username).unwrap_or_default()
+    }
+    async fn context(context: AuthContext, user: User) -> String {
+        assert_eq!(context.user_id, user.id);
+        format!("{} {}", context.session_id.is_some(), context.auth_level)
+    }
+    let app = axum::Router::new()
+        .route("/context", get(context))
+        .layer(ValidateSessionLayer::new(state.clone()))
+        .merge(
+            axum::Router::new()
+                .route("/whoami", get(whoami))
+                .layer(ValidateSessionLayer::optional(state.clone())),
+        )
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let custom_port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let port = run_test_app().await;
+    let (username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+
+    let response = get_with_session("/whoami", &session_key, custom_port).await;
+    assert_eq!(response.text().await.unwrap(), username);
+    let response = get_with_session("/context", &session_key, custom_port).await;
+    assert_eq!(response.text().await.unwrap(), "true user");
+
+    // Open routes carry on without a user, protected ones reject the request
+    let client = Client::new();
+    let response = client
+        .get(format!("{}:{}/whoami", SERVER_URL, custom_port))
+        .header("email", &email)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "");
+    let response = get_with_session("/whoami", "invalid", custom_port).await;
+    assert_eq!(response.text().await.unwrap(), "");
+    let response = client
+        .get(format!("{}:{}/context", SERVER_URL, custom_port))
+        .header("email", &email)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    delete_reg(email).await.unwrap();
+}
+
 #[tokio::test]
 async fn change_password_and_use_old_creds() {
     let port = run_test_app().await;
@@ -348,7 +1112,7 @@ async fn change_password_and_use_old_creds() {
     let session_key = login(email.clone(), password.clone(), port).await.unwrap();
 
     let new_password = generate_unique_id(20);
-    let session_cookie = format!("session-key={session_key}");
+    let session_cookie = session_cookies(&session_key);
 
     let change_password_request = ChangePassword {
         old_password: password.clone(),
@@ -365,6 +1129,7 @@ async fn change_password_and_use_old_creds() {
     );
 
     headers.insert(COOKIE, HeaderValue::from_str(&session_cookie).unwrap());
+    headers.insert("X-CSRF-Token", HeaderValue::from_static(TEST_CSRF_TOKEN));
 
     let response: ApiResponse = client
         .patch(url)
@@ -394,7 +1159,7 @@ async fn change_password_invalid_password() {
     let session_key = login(email.clone(), password.clone(), port).await.unwrap();
 
     let new_password = generate_unique_id(110);
-    let session_cookie = format!("session-key={session_key}");
+    let session_cookie = session_cookies(&session_key);
 
     let change_password_request = ChangePassword {
         old_password: password.clone(),
@@ -411,6 +1176,7 @@ async fn change_password_invalid_password() {
     );
 
     headers.insert(COOKIE, HeaderValue::from_str(&session_cookie).unwrap());
+    headers.insert("X-CSRF-Token", HeaderValue::from_static(TEST_CSRF_TOKEN));
 
     let response = client
         .patch(url)
@@ -447,7 +1213,7 @@ async fn reset_password() {
 
     let code = sqlx::query_as!(
         Code,
-        "SELECT code, email, code_type, created_ts, expiry_ts, used FROM codes WHERE email = $1 AND code_type = 'PasswordReset' AND used = false",
+        "SELECT code, user_id, code_type, codes.created_ts, expiry_ts, used FROM codes JOIN users ON users.id = codes.user_id WHERE users.email = $1 AND code_type = 'PasswordReset' AND used = false",
         &email
     )
     .fetch_optional(&pool)
@@ -499,6 +1265,7 @@ async fn check_max_login_attempts() {
     }
 
     let url = format!("{}:{}/account/login", SERVER_URL, port);
+    let password_copy = password.clone();
     let login_details = LoginDetails {
         email: email.clone(),
         password,
@@ -518,8 +1285,1940 @@ async fn check_max_login_attempts() {
     assert_eq!(response.response_type, ResponseType::Error);
     assert_eq!(
         response.message,
-        *"Too many login attempts, please reset your password"
+        *"Your account is temporarily locked after too many login attempts, please try again later or reset your password"
     );
 
+    // The lock is lifted automatically once the cool-down has passed
+    let pool = get_config().get_db_pool().await;
+    let lockout = sqlx::query!(
+        r#"SELECT locked_until_ts as "locked_until_ts!" FROM users WHERE email = $1"#,
+        &email
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    let now = chrono::Utc::now().timestamp();
+    assert!(lockout.locked_until_ts > now + 14 * 60);
+    sqlx::query!(
+        "UPDATE users SET locked_until_ts = $1 WHERE email = $2",
+        now - 1,
+        &email
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert!(login(email.clone(), password_copy, port).await.is_some());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn enumeration_safe_mode() {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    state.config.server.enumeration_safe = true;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = serve_test_app(listener, Arc::new(state));
+    let client = Client::new();
+
+    let (_username, email, password, response) = create_valid_reg(port).await;
+    let response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::RegistrationSuccess);
+    let unknown_email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+
+    // Unknown accounts and wrong passwords can't be told apart
+    let wrong_password =
+        login_response(email.clone(), "incorrect_password".to_string(), port).await;
+    let unknown_account = login_response(unknown_email.clone(), password.clone(), port).await;
+    assert_eq!(wrong_password.message, "Incorrect email or password");
+    assert_eq!(unknown_account.message, wrong_password.message);
+    assert!(login(email.clone(), password.clone(), port).await.is_some());
+
+    let reset = |email: String| {
+        client
+            .post(format!("{}:{}/account/resetPassword", SERVER_URL, port))
+            .json(&PasswordResetInitiateRequest(email))
+            .send()
+    };
+    let known: ApiResponse = reset(email.clone()).await.unwrap().json().await.unwrap();
+    let unknown: ApiResponse = reset(unknown_email).await.unwrap().json().await.unwrap();
+    assert_eq!(
+        known.response_type,
+        ResponseType::PasswordResetInitiationSuccess
+    );
+    assert_eq!(known.response_type, unknown.response_type);
+    assert_eq!(known.message, unknown.message);
+
+    // Registering an existing email looks successful but doesn't create another account
+    let new_password = generate_unique_id(30);
+    let response: ApiResponse = client
+        .post(format!("{}:{}/account/register", SERVER_URL, port))
+        .json(&RegistrationDetails {
+            username: generate_unique_id(20),
+            email: email.clone(),
+            password: new_password.clone(),
+            confirm_password: new_password.clone(),
+            sub: None,
+        })
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::RegistrationSuccess);
+    assert!(login(email.clone(), new_password, port).await.is_none());
+
+    let pool = get_config().get_db_pool().await;
+    let users = sqlx::query!(
+        r#"SELECT COUNT(*) as "count!" FROM users WHERE email = $1"#,
+        &email
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(users.count, 1);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn progressive_login_throttling() {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    state.config.server.login_backoff_base_in_seconds = 60;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = serve_test_app(listener, Arc::new(state));
+
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let response = login_response(email.clone(), "incorrect_password".to_string(), port).await;
+    assert_eq!(response.message, "Incorrect password");
+
+    // Even the right password is refused until the backoff has passed, without counting as a failure
+    let response = login_response(email.clone(), password.clone(), port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+    assert_eq!(
+        response.message,
+        "Too many unsuccessful login attempts, please wait a moment and try again"
+    );
+
+    let pool = get_config().get_db_pool().await;
+    let user = sqlx::query!(
+        r#"SELECT login_attempts as "login_attempts!", last_failed_login_ts as "last_failed_login_ts!" FROM users WHERE email = $1"#,
+        &email
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(user.login_attempts, 1);
+
+    sqlx::query!(
+        "UPDATE users SET last_failed_login_ts = $1 WHERE email = $2",
+        user.last_failed_login_ts - 60,
+        &email
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    assert!(login(email.clone(), password, port).await.is_some());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn cors_policy() {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    state.config.cors.allowed_origins = vec!["https://*.tld.com".to_string()];
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = serve_test_app(listener, Arc::new(state));
+
+    let client = Client::new();
+    let preflight = |origin: &'static str| {
+        client
+            .request(
+                http::Method::OPTIONS,
+                format!("{}:{}/account/profile", SERVER_URL, port),
+            )
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "DELETE")
+            .header("Access-Control-Request-Headers", "x-csrf-token")
+            .send()
+    };
+
+    let response = preflight("https://app.tld.com").await.unwrap();
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://app.tld.com"
+    );
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+    assert!(
+        headers["access-control-allow-methods"]
+            .to_str()
+            .unwrap()
+            .contains("DELETE")
+    );
+    assert_eq!(headers["access-control-max-age"], "3600");
+
+    let response = preflight("https://eviltld.com").await.unwrap();
+    assert!(
+        !response
+            .headers()
+            .contains_key("access-control-allow-origin")
+    );
+}
+
+#[tokio::test]
+async fn security_headers() {
+    let port = run_test_app().await;
+    let client = Client::new();
+
+    let response = client
+        .get(format!("{}:{}/healthCheck", SERVER_URL, port))
+        .send()
+        .await
+        .unwrap();
+    let headers = response.headers();
+    assert_eq!(
+        headers["content-security-policy"],
+        "default-src 'none'; frame-ancestors 'none'"
+    );
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(
+        headers["strict-transport-security"],
+        "max-age=31536000; includeSubDomains"
+    );
+    assert_eq!(
+        headers["referrer-policy"],
+        "strict-origin-when-cross-origin"
+    );
+    assert!(headers.contains_key("permissions-policy"));
+
+    // The frontend gets its own policy with a new nonce for every response
+    let get_frontend_policy = || async {
+        client
+            .get(format!("{}:{}/", SERVER_URL, port))
+            .send()
+            .await
+            .unwrap()
+            .headers()["content-security-policy"]
+            .to_str()
+            .unwrap()
+            .to_string()
+    };
+    let first_policy = get_frontend_policy().await;
+    let second_policy = get_frontend_policy().await;
+    assert!(first_policy.contains("script-src 'self' 'nonce-"));
+    assert!(first_policy.ends_with("frame-ancestors 'none'"));
+    assert!(!first_policy.contains("{nonce}"));
+    assert_ne!(first_policy, second_policy);
+}
+
+#[tokio::test]
+async fn rate_limiting() {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    state.config.rate_limits.policies = vec![
+        RateLimitPolicy {
+            method: "POST".to_string(),
+            path: "/account/login".to_string(),
+            requests: 2,
+            period_in_seconds: 3600,
+            keys: vec![RateLimitKey::Account],
+        },
+        RateLimitPolicy {
+            method: "GET".to_string(),
+            path: "/account/verificationEmail".to_string(),
+            requests: 1,
+            period_in_seconds: 3600,
+            keys: vec![RateLimitKey::Account],
+        },
+        RateLimitPolicy {
+            method: "POST".to_string(),
+            path: "/account/resetPassword".to_string(),
+            requests: 3,
+            period_in_seconds: 3600,
+            keys: vec![RateLimitKey::Account],
+        },
+    ];
+    state.config.rate_limits.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = serve_test_app(listener, Arc::new(state));
+
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let (_other_username, other_email, other_password, _response) = create_valid_reg(port).await;
+    let client = Client::new();
+    let login_attempt = |email: String, password: String| {
+        client
+            .post(format!("{}:{}/account/login", SERVER_URL, port))
+            .json(&LoginDetails { email, password })
+            .send()
+    };
+
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+    let response = login_attempt(email.to_uppercase(), "wrong".to_string())
+        .await
+        .unwrap();
+    assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // The account is limited however its email is written, other accounts aren't affected
+    let response = login_attempt(email.clone(), password.clone())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 1800);
+    let body: ApiResponse = response.json().await.unwrap();
+    assert_eq!(body.response_type, ResponseType::Error);
+
+    assert!(
+        login(other_email.clone(), other_password, port)
+            .await
+            .is_some()
+    );
+
+    // On protected routes the account is the signed in user
+    let response = get_with_session("/account/verificationEmail", &session_key, port).await;
+    assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = get_with_session("/account/verificationEmail", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // The reset body is just the email, which is limited however many addresses it comes from
+    let reset_attempt = |client_ip: String| {
+        client
+            .post(format!("{}:{}/account/resetPassword", SERVER_URL, port))
+            .header("X-Forwarded-For", client_ip)
+            .json(&PasswordResetInitiateRequest(email.clone()))
+            .send()
+    };
+    for i in 1..=3 {
+        let response = reset_attempt(format!("10.0.0.{i}")).await.unwrap();
+        assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+    let response = reset_attempt("10.0.0.4".to_string()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let _ = delete_reg(email).await;
+    let _ = delete_reg(other_email).await;
+}
+
+async fn run_test_app_with_memory_sessions() -> u16 {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    state.session_store = Arc::new(MemorySessionStore::default());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    serve_test_app(listener, Arc::new(state))
+}
+
+#[tokio::test]
+async fn memory_session_store() {
+    let port = run_test_app_with_memory_sessions().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let first_session = login(email.clone(), password.clone(), port).await.unwrap();
+    let second_session = login(email.clone(), password.clone(), port).await.unwrap();
+
+    // Nothing is written to the sessions table
+    let pool = get_config().get_db_pool().await;
+    let stored = sqlx::query!("SELECT COUNT(*) FROM sessions JOIN users ON users.id = sessions.user_id WHERE users.email = $1", &email)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(stored.count, Some(0));
+
+    let sessions = get_sessions(&first_session, port).await;
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions[1].current);
+
+    let response = get_with_session("/account/logout", &first_session, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = get_with_session("/account/profile", &first_session, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = get_with_session("/account/profile", &second_session, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let _ = delete_reg(email).await;
+}
+
+async fn run_test_app_with_idle_timeout(idle_timeout_in_minutes: i64) -> u16 {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    state.config.server.session_idle_timeout_in_minutes = idle_timeout_in_minutes;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    serve_test_app(listener, Arc::new(state))
+}
+
+#[tokio::test]
+async fn sliding_session_expiry() {
+    let port = run_test_app_with_idle_timeout(60).await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let pool = get_config().get_db_pool().await;
+
+    let response = Client::new()
+        .post(format!("{}:{}/account/login", SERVER_URL, port))
+        .json(&LoginDetails {
+            email: email.clone(),
+            password,
+        })
+        .send()
+        .await
+        .unwrap();
+    let raw_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(raw_cookie.contains("Max-Age=3600"));
+    let session_key = raw_cookie
+        .split(';')
+        .next()
+        .unwrap()
+        .split_once('=')
+        .unwrap()
+        .1
+        .to_string();
+
+    let hashed_session_key = hash_token(&session_key);
+
+    // Recently extended sessions aren't written to again
+    let response = get_with_session("/account/profile", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("set-cookie").is_none());
+
+    // Once the extension interval has passed the expiry and cookie are moved forward
+    let now = chrono::Utc::now().timestamp();
+    sqlx::query!(
+        "UPDATE sessions SET last_seen_ts = $1, expiry = $2 WHERE hashed_session_key = $3",
+        now - 600,
+        now + 3000,
+        &hashed_session_key
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = get_with_session("/account/profile", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let raw_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(raw_cookie.starts_with(&format!("session-key={}", session_key)));
+    let session = sqlx::query!(
+        r#"SELECT expiry as "expiry!" FROM sessions WHERE hashed_session_key = $1"#,
+        &hashed_session_key
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(session.expiry >= now + 3600);
+
+    // Sessions never outlive their absolute lifetime
+    sqlx::query!(
+        "UPDATE sessions SET last_seen_ts = $1, created_ts = $2 WHERE hashed_session_key = $3",
+        now - 600,
+        now - 180 * 86400 + 60,
+        &hashed_session_key
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = get_with_session("/account/profile", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let session = sqlx::query!(
+        r#"SELECT expiry as "expiry!" FROM sessions WHERE hashed_session_key = $1"#,
+        &hashed_session_key
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert!(session.expiry <= now + 60);
+
+    // Idle sessions are rejected
+    sqlx::query!(
+        "UPDATE sessions SET last_seen_ts = $1, expiry = $2, created_ts = $1 WHERE hashed_session_key = $3",
+        now - 7200,
+        now + 3600,
+        &hashed_session_key
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response = get_with_session("/account/profile", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn logout_only_ends_current_session() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let first_session = login(email.clone(), password.clone(), port).await.unwrap();
+    let second_session = login(email.clone(), password.clone(), port).await.unwrap();
+
+    let response = get_with_session("/account/logout", &first_session, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = get_with_session("/account/profile", &first_session, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = get_with_session("/account/profile", &second_session, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn session_keys_are_stored_hashed() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password, port).await.unwrap();
+
+    let pool = get_config().get_db_pool().await;
+    let session = sqlx::query!(
+        r#"SELECT hashed_session_key as "hashed_session_key!" FROM sessions
+        JOIN users ON users.id = sessions.user_id WHERE users.email = $1"#,
+        &email
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(session.hashed_session_key, hash_token(&session_key));
+
+    // The stored value can't be used as a cookie
+    let response = get_with_session("/account/profile", &session.hashed_session_key, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn csrf_protection() {
+    let port = run_test_app().await;
+    let client = Client::new();
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password, port).await.unwrap();
+    let url = format!("{}:{}/account/sessions", SERVER_URL, port);
+
+    // The token is returned in the body and set as a cookie to be sent back with it
+    let response = client
+        .get(format!("{}:{}/csrfToken", SERVER_URL, port))
+        .send()
+        .await
+        .unwrap();
+    let raw_cookie = response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let token_response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(token_response.response_type, ResponseType::CsrfToken);
+    let token = token_response.message;
+    assert!(raw_cookie.starts_with(&format!("csrf-token={token}")));
+    assert!(raw_cookie.contains("SameSite=Strict"));
+
+    let cookies = format!("session-key={session_key}; csrf-token={token}");
+
+    // Without the header the cookie alone isn't enough
+    let response = client
+        .delete(&url)
+        .header(COOKIE, &cookies)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .delete(&url)
+        .header(COOKIE, &cookies)
+        .header("X-CSRF-Token", "NOT-THE-TOKEN")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .delete(&url)
+        .header(COOKIE, &cookies)
+        .header("X-CSRF-Token", &token)
+        .header("Sec-Fetch-Site", "cross-site")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .delete(&url)
+        .header(COOKIE, &cookies)
+        .header("X-CSRF-Token", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Safe methods don't need the token
+    let response = get_with_session("/account/sessions", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn list_and_revoke_sessions() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let first_session = login(email.clone(), password.clone(), port).await.unwrap();
+    let second_session = login(email.clone(), password.clone(), port).await.unwrap();
+    let third_session = login(email.clone(), password.clone(), port).await.unwrap();
+
+    let sessions = get_sessions(&first_session, port).await;
+    assert_eq!(sessions.len(), 3);
+    assert_eq!(sessions.iter().filter(|session| session.current).count(), 1);
+    assert!(sessions.iter().all(|session| session.ip_address.is_some()));
+
+    // Revoke a single session by id
+    let second_session_id = get_sessions(&second_session, port)
+        .await
+        .into_iter()
+        .find(|session| session.current)
+        .unwrap()
+        .id;
+    let response: ApiResponse = delete_with_session(
+        &format!("/account/sessions/{second_session_id}"),
+        &first_session,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::SessionRevoked);
+
+    let response = get_with_session("/account/profile", &second_session, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Revoke everything except the session making the request
+    let response: ApiResponse = delete_with_session("/account/sessions", &first_session, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::OtherSessionsRevoked);
+
+    let response = get_with_session("/account/profile", &third_session, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let sessions = get_sessions(&first_session, port).await;
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].current);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn two_factor_login() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+    let (secret, recovery_codes) = enable_two_factor(&session_key, port).await;
+    assert_eq!(recovery_codes.len(), 10);
+
+    // Password alone no longer creates a session
+    assert!(login(email.clone(), password.clone(), port).await.is_none());
+
+    let response = login_response(email.clone(), password.clone(), port).await;
+    assert_eq!(response.response_type, ResponseType::SecondFactorRequired);
+
+    // The code used to confirm enrollment can't be replayed so use the next one
+    let totp = build_totp(&secret, "Axumatic", "").unwrap();
+    let now = chrono::Utc::now().timestamp() as u64;
+    let response = login_second_factor(response.message, totp.generate(now + 30), port).await;
+    assert!(response.headers().get("set-cookie").is_some());
+    let response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::LoginSuccess);
+
+    // Recovery codes work once
+    let response = login_response(email.clone(), password.clone(), port).await;
+    let response = login_second_factor(response.message, recovery_codes[0].clone(), port).await;
+    assert!(response.headers().get("set-cookie").is_some());
+
+    let response = login_response(email.clone(), password.clone(), port).await;
+    let response: ApiResponse =
+        login_second_factor(response.message, recovery_codes[0].clone(), port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+    assert_eq!(response.message, *"Invalid two factor code");
+
+    let _ = delete_reg(email).await;
+}
+
+// Google's ID tokens can't be signed in tests, so the sign in is completed from an already verified identity
+#[tokio::test]
+async fn google_login_requires_second_factor() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+    enable_two_factor(&session_key, port).await;
+
+    let state = get_app_state().await;
+    let sub = generate_unique_id(20);
+    sqlx::query!(
+        "INSERT INTO user_identities (user_id, identity_provider, sub, created_ts)
+        SELECT id, 'google', $1, 0 FROM users WHERE email = $2",
+        &sub,
+        &email
+    )
+    .execute(&state.db_connection_pool)
+    .await
+    .unwrap();
+
+    let identity = GoogleIdentity {
+        sub,
+        email: Some(email.clone()),
+        email_verified: Some(true),
+    };
+    let Ok((headers, Json(response))) =
+        complete_google_login(state, identity, &SessionMetadata::default()).await
+    else {
+        panic!("Google login failed");
+    };
+    assert!(headers.get("set-cookie").is_none());
+    assert_eq!(response.response_type, ResponseType::SecondFactorRequired);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn two_factor_disable() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+    let (_secret, recovery_codes) = enable_two_factor(&session_key, port).await;
+
+    let response: ApiResponse = post_with_session(
+        "/account/twoFactor/disable",
+        &session_key,
+        &DisableTwoFactor {
+            password: password.clone(),
+            code: recovery_codes[0].clone(),
+        },
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::TwoFactorDisabled);
+
+    assert!(login(email.clone(), password.clone(), port).await.is_some());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn passkey_registration_and_login() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+    let mut authenticator = SoftwareAuthenticator::new();
+
+    let response: ApiResponse = post_with_session(
+        "/account/passkeys/registrationOptions",
+        &session_key,
+        &serde_json::json!({}),
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(
+        response.response_type,
+        ResponseType::PasskeyRegistrationOptions
+    );
+    let options: serde_json::Value = serde_json::from_str(&response.message).unwrap();
+
+    let registration = serde_json::json!({
+        "name": "Test authenticator",
+        "credential": authenticator.register(&options),
+    });
+    let response: ApiResponse =
+        post_with_session("/account/passkeys", &session_key, &registration, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::PasskeyRegistered);
+
+    // The registration challenge can't be reused
+    let response: ApiResponse =
+        post_with_session("/account/passkeys", &session_key, &registration, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    // The counter from registration is stored, so an assertion which doesn't advance it looks cloned
+    authenticator.sign_count = 0;
+    let options = get_passkey_login_options(port).await;
+    let assertion = authenticator.authenticate(&options);
+    let response: ApiResponse = passkey_login(&assertion, port).await.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let options = get_passkey_login_options(port).await;
+    let assertion = authenticator.authenticate(&options);
+    let response = passkey_login(&assertion, port).await;
+    assert!(response.headers().get("set-cookie").is_some());
+    let response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::LoginSuccess);
+
+    // Replaying an assertion fails as its challenge has been used
+    let response: ApiResponse = passkey_login(&assertion, port).await.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    // The password still works alongside the passkey
+    assert!(login(email.clone(), password.clone(), port).await.is_some());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn list_and_remove_passkeys() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+
+    for name in ["Phone", "Laptop"] {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let response: ApiResponse = post_with_session(
+            "/account/passkeys/registrationOptions",
+            &session_key,
+            &serde_json::json!({}),
+            port,
+        )
+        .await
+        .json()
+        .await
+        .unwrap();
+        let options: serde_json::Value = serde_json::from_str(&response.message).unwrap();
+        let registration = serde_json::json!({
+            "name": name,
+            "credential": authenticator.register(&options),
+        });
+        let response: ApiResponse =
+            post_with_session("/account/passkeys", &session_key, &registration, port)
+                .await
+                .json()
+                .await
+                .unwrap();
+        assert_eq!(response.response_type, ResponseType::PasskeyRegistered);
+    }
+
+    let response: ApiResponse = get_with_session("/account/passkeys", &session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let passkeys: Vec<Passkey> = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(passkeys.len(), 2);
+
+    let response: ApiResponse = delete_with_session(
+        &format!("/account/passkeys/{}", passkeys[0].id),
+        &session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::PasskeyRemoved);
+
+    let response: ApiResponse = get_with_session("/account/passkeys", &session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let passkeys: Vec<Passkey> = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(passkeys.len(), 1);
+    assert_eq!(passkeys[0].name, "Laptop");
+
+    // Without a password or linked identity the last passkey is the only way of signing in
+    let pool = get_config().get_db_pool().await;
+    sqlx::query!(
+        "UPDATE users SET hashed_password = NULL WHERE email = $1",
+        &email
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let response: ApiResponse = delete_with_session(
+        &format!("/account/passkeys/{}", passkeys[0].id),
+        &session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+    assert_eq!(
+        response.message,
+        "You can't remove your only way of signing in"
+    );
+
+    let response: ApiResponse = get_with_session("/account/passkeys", &session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let passkeys: Vec<Passkey> = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(passkeys.len(), 1);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn magic_login() {
+    let port = run_test_app().await;
+    let client = Client::new();
+    let (_username, email, _password, _response) = create_valid_reg(port).await;
+
+    let url = format!("{}:{}/account/login/email", SERVER_URL, port);
+    let response: ApiResponse = client
+        .post(&url)
+        .json(&MagicLoginRequest {
+            email: email.clone(),
+        })
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::MagicLoginEmailSent);
+
+    let pool = get_config().get_db_pool().await;
+    let code = sqlx::query_as!(
+        Code,
+        "SELECT code, user_id, code_type, codes.created_ts, expiry_ts, used FROM codes JOIN users ON users.id = codes.user_id WHERE users.email = $1 AND code_type = 'MagicLogin' AND used = false",
+        &email
+    )
+    .fetch_optional(&pool)
+    .await
+    .unwrap()
+    .unwrap();
+
+    let complete_request = MagicLoginCompleteRequest {
+        email: email.clone(),
+        code: code.code.unwrap(),
+    };
+    let url = format!("{}:{}/account/login/email/complete", SERVER_URL, port);
+    let response = client
+        .post(&url)
+        .json(&complete_request)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.headers().get("set-cookie").is_some());
+    let response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::LoginSuccess);
+
+    // Codes are single use
+    let response: ApiResponse = client
+        .post(&url)
+        .json(&complete_request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn oidc_login() {
+    let email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+    let issuer_url = run_mock_issuer(&email).await;
+    let port = run_test_app_with_oidc_provider(&issuer_url).await;
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let login = || async {
+        let url = format!("{}:{}/account/login/mock", SERVER_URL, port);
+        let response = client.get(url).send().await.unwrap();
+        assert!(response.status().is_redirection());
+        let state_cookie = state_cookie(&response);
+        let authorize_url = response.headers()["location"].to_str().unwrap().to_string();
+        assert!(authorize_url.starts_with(&issuer_url));
+
+        let response = client.get(authorize_url).send().await.unwrap();
+        let callback_url = response.headers()["location"].to_str().unwrap().to_string();
+        (callback_url, state_cookie)
+    };
+    let callback = |callback_url: String, state_cookie: String| async {
+        client
+            .get(callback_url)
+            .header(COOKIE, state_cookie)
+            .send()
+            .await
+            .unwrap()
+    };
+
+    // A callback from a browser which didn't start the login is rejected
+    let (callback_url, _state_cookie) = login().await;
+    let response = callback(callback_url, "oidc-state=other".to_string()).await;
+    assert!(response.headers().get("set-cookie").is_none());
+
+    let (callback_url, state_cookie) = login().await;
+    let response = callback(callback_url.clone(), state_cookie.clone()).await;
+    assert!(response.status().is_redirection());
+    assert!(
+        response.headers()["location"]
+            .to_str()
+            .unwrap()
+            .ends_with("/profile")
+    );
+    let raw_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    let (_key, session_key) = raw_cookie
+        .split(';')
+        .next()
+        .unwrap()
+        .split_once('=')
+        .unwrap();
+
+    let profile: ApiResponse = get_with_session("/account/profile", session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(profile.response_type, ResponseType::UserProfile);
+    assert!(profile.message.contains(&email));
+    assert!(profile.message.contains("\"identity_provider\":\"mock\""));
+
+    // The state can't be reused to replay the callback
+    let response = callback(callback_url, state_cookie).await;
+    assert!(response.headers().get("set-cookie").is_none());
+
+    // Logging in again finds the existing user rather than registering a new one
+    let (callback_url, state_cookie) = login().await;
+    let response = callback(callback_url, state_cookie).await;
+    assert!(response.headers().get("set-cookie").is_some());
+
+    let url = format!("{}:{}/account/login/unknown", SERVER_URL, port);
+    let response = client.get(url).send().await.unwrap();
+    assert!(!response.status().is_redirection());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn github_login() {
+    let email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+    let github_url = run_mock_github(&email, true).await;
+    let port = run_test_app_with_github(&github_url).await;
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let login_url = format!("{}:{}/account/login/github", SERVER_URL, port);
+
+    let response = external_login(&client, &login_url).await;
+    assert!(response.status().is_redirection());
+    let raw_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    let (_key, session_key) = raw_cookie
+        .split(';')
+        .next()
+        .unwrap()
+        .split_once('=')
+        .unwrap();
+
+    let profile: ApiResponse = get_with_session("/account/profile", session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(profile.message.contains(&email));
+    assert!(profile.message.contains("\"identity_provider\":\"github\""));
+    assert!(profile.message.contains("\"email_verified\":true"));
+
+    // Logging in again finds the existing user
+    let response = external_login(&client, &login_url).await;
+    assert!(response.headers().get("set-cookie").is_some());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn github_login_requires_verified_email() {
+    let email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+    let github_url = run_mock_github(&email, false).await;
+    let port = run_test_app_with_github(&github_url).await;
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let login_url = format!("{}:{}/account/login/github", SERVER_URL, port);
+
+    let response = external_login(&client, &login_url).await;
+    assert!(response.headers().get("set-cookie").is_none());
+    let response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+}
+
+async fn get_identities(session_key: &str, port: u16) -> Vec<UserIdentity> {
+    let response: ApiResponse = get_with_session("/account/identities", session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::IdentityList);
+    serde_json::from_str(&response.message).unwrap()
+}
+
+#[tokio::test]
+async fn link_and_unlink_identity() {
+    let github_email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+    let github_url = run_mock_github(&github_email, true).await;
+    let port = run_test_app_with_github(&github_url).await;
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password.clone(), port).await.unwrap();
+
+    // Linking requires the password to be confirmed
+    let response: ApiResponse = post_with_session(
+        "/account/identities/link/github",
+        &session_key,
+        &LinkIdentityRequest {
+            password: Some("wrong password".to_string()),
+            jwt: None,
+        },
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let start_link = || async {
+        let response = post_with_session(
+            "/account/identities/link/github",
+            &session_key,
+            &LinkIdentityRequest {
+                password: Some(password.clone()),
+                jwt: None,
+            },
+            port,
+        )
+        .await;
+        let state_cookie = state_cookie(&response);
+        let response: ApiResponse = response.json().await.unwrap();
+        assert_eq!(response.response_type, ResponseType::IdentityLinkRedirect);
+
+        let response = client.get(&response.message).send().await.unwrap();
+        let callback_url = response.headers()["location"].to_str().unwrap().to_string();
+        (callback_url, state_cookie)
+    };
+
+    // The callback must arrive signed in as the user who started the link
+    let (callback_url, state_cookie) = start_link().await;
+    let response: ApiResponse = client
+        .get(callback_url)
+        .header(COOKIE, state_cookie)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+    assert!(get_identities(&session_key, port).await.is_empty());
+
+    let (callback_url, state_cookie) = start_link().await;
+    let response = client
+        .get(callback_url)
+        .header(COOKIE, format!("session-key={session_key}; {state_cookie}"))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_redirection());
+    assert!(response.headers().get("set-cookie").is_none());
+
+    let identities = get_identities(&session_key, port).await;
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].identity_provider, "github");
+
+    // Signing in with GitHub now reaches the existing account
+    let login_url = format!("{}:{}/account/login/github", SERVER_URL, port);
+    let response = external_login(&client, &login_url).await;
+    let raw_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    let (_key, github_session_key) = raw_cookie
+        .split(';')
+        .next()
+        .unwrap()
+        .split_once('=')
+        .unwrap();
+    let profile: ApiResponse = get_with_session("/account/profile", github_session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(profile.message.contains(&email));
+
+    let response: ApiResponse = delete_with_session(
+        &format!("/account/identities/{}", identities[0].id),
+        &session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::IdentityUnlinked);
+    assert!(get_identities(&session_key, port).await.is_empty());
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn last_identity_cannot_be_unlinked() {
+    let email = format!("{}@{}.com", generate_unique_id(20), generate_unique_id(10));
+    let github_url = run_mock_github(&email, true).await;
+    let port = run_test_app_with_github(&github_url).await;
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    let login_url = format!("{}:{}/account/login/github", SERVER_URL, port);
+    let response = external_login(&client, &login_url).await;
+    let raw_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    let (_key, session_key) = raw_cookie
+        .split(';')
+        .next()
+        .unwrap()
+        .split_once('=')
+        .unwrap();
+
+    let identities = get_identities(session_key, port).await;
+    assert_eq!(identities.len(), 1);
+
+    let response: ApiResponse = delete_with_session(
+        &format!("/account/identities/{}", identities[0].id),
+        session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+    assert_eq!(get_identities(session_key, port).await.len(), 1);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn admin_routes_require_admin_role() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password, port).await.unwrap();
+
+    let response = get_with_session("/admin/roles", "invalid-session", port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = get_with_session("/admin/roles", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let pool = get_config().get_db_pool().await;
+    sqlx::query!(
+        "UPDATE users SET auth_level = 'admin' WHERE email = $1",
+        &email
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let response = get_with_session("/admin/roles", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response: ApiResponse = response.json().await.unwrap();
+    assert_eq!(response.response_type, ResponseType::RoleList);
+    let roles: Vec<Role> = serde_json::from_str(&response.message).unwrap();
+    let admin = roles.iter().find(|role| role.name == "admin").unwrap();
+    assert_eq!(admin.permissions, vec!["*".to_string()]);
+
+    let _ = delete_reg(email).await;
+}
+
+async fn create_admin(port: u16) -> (String, String) {
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let pool = get_config().get_db_pool().await;
+    sqlx::query!(
+        "UPDATE users SET auth_level = 'admin' WHERE email = $1",
+        &email
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let session_key = login(email.clone(), password, port).await.unwrap();
+    (email, session_key)
+}
+
+async fn admin_get_user(username: &str, session_key: &str, port: u16) -> AdminUserDetails {
+    let response: ApiResponse =
+        get_with_session(&format!("/admin/users/{}", username), session_key, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::UserDetails);
+    serde_json::from_str(&response.message).unwrap()
+}
+
+async fn admin_change_auth_level(
+    username: &str,
+    auth_level: &str,
+    session_key: &str,
+    port: u16,
+) -> ApiResponse {
+    Client::new()
+        .patch(format!(
+            "{}:{}/admin/users/{}/authLevel",
+            SERVER_URL, port, username
+        ))
+        .header(COOKIE, session_cookies(session_key))
+        .header("X-CSRF-Token", TEST_CSRF_TOKEN)
+        .json(&ChangeAuthLevel {
+            auth_level: auth_level.to_string(),
+        })
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn admin_user_management() {
+    let port = run_test_app().await;
+    let (admin_email, admin_session_key) = create_admin(port).await;
+    let admin_username = admin_email.split_once('@').unwrap().0.to_string();
+    let (username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password, port).await.unwrap();
+
+    let response: ApiResponse = get_with_session(
+        &format!("/admin/users?search={}&page=1&page_size=5", username),
+        &admin_session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::UserList);
+    let user_page: UserPage = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(user_page.total, 1);
+    assert_eq!(user_page.page_size, 5);
+    assert_eq!(user_page.users[0].email, email);
+
+    let details = admin_get_user(&username, &admin_session_key, port).await;
+    assert_eq!(details.sessions.len(), 1);
+    assert!(!details.profile.email_verified);
+
+    let pool = get_config().get_db_pool().await;
+    sqlx::query!(
+        "UPDATE users SET login_attempts = 10 WHERE email = $1",
+        &email
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let path = format!("/admin/users/{}/unlock", username);
+    let response: ApiResponse = post_with_session(&path, &admin_session_key, &(), port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::UserUpdated);
+
+    let path = format!("/admin/users/{}/verifyEmail", username);
+    let response: ApiResponse = post_with_session(&path, &admin_session_key, &(), port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::UserUpdated);
+
+    let details = admin_get_user(&username, &admin_session_key, port).await;
+    assert_eq!(details.login_attempts, 0);
+    assert!(details.profile.email_verified);
+
+    let response = admin_change_auth_level(&username, "superuser", &admin_session_key, port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+    let response = admin_change_auth_level(&admin_username, "user", &admin_session_key, port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+    let response = admin_change_auth_level(&username, "admin", &admin_session_key, port).await;
+    assert_eq!(response.response_type, ResponseType::UserUpdated);
+    let details = admin_get_user(&username, &admin_session_key, port).await;
+    assert_eq!(details.profile.auth_level, "admin");
+
+    let path = format!("/admin/users/{}/sessions", username);
+    let response: ApiResponse = delete_with_session(&path, &admin_session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::UserSessionsRevoked);
+    let response = get_with_session("/account/profile", &session_key, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let path = format!("/admin/users/{}", username);
+    let response: ApiResponse = delete_with_session(&path, &admin_session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::UserDeleted);
+    let response: ApiResponse = get_with_session(&path, &admin_session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let _ = delete_reg(admin_email).await;
+}
+
+async fn get_with_token(path: &str, token: &str, port: u16) -> Response {
+    let client = Client::new();
+    let url = format!("{}:{}{}", SERVER_URL, port, path);
+    client.get(url).bearer_auth(token).send().await.unwrap()
+}
+
+#[tokio::test]
+async fn api_token_authentication() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password, port).await.unwrap();
+
+    let token_request = CreateApiTokenRequest {
+        name: "CI".to_string(),
+        scopes: vec!["delete".to_string()],
+        expires_in_days: None,
+    };
+    let response: ApiResponse =
+        post_with_session("/account/tokens", &session_key, &token_request, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let token_request = CreateApiTokenRequest {
+        name: "CI".to_string(),
+        scopes: vec!["read".to_string()],
+        expires_in_days: Some(30),
+    };
+    let response: ApiResponse =
+        post_with_session("/account/tokens", &session_key, &token_request, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::ApiTokenCreated);
+    let created: CreatedApiToken = serde_json::from_str(&response.message).unwrap();
+    assert!(created.token.starts_with("axm_"));
+
+    let response = get_with_token("/account/profile", &created.token, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // A read only token can't be used for anything other than GET requests
+    let client = Client::new();
+    let response = client
+        .post(format!("{}:{}/account/twoFactor/enroll", SERVER_URL, port))
+        .bearer_auth(&created.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = get_with_token("/admin/roles", &created.token, port).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response: ApiResponse = get_with_token("/account/tokens", &created.token, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let response: ApiResponse = get_with_session("/account/tokens", &session_key, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response.response_type, ResponseType::ApiTokenList);
+    let tokens: Vec<ApiToken> = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].scopes, vec!["read".to_string()]);
+    assert!(tokens[0].expiry_ts.is_some());
+    assert!(tokens[0].last_used_ts.is_some());
+
+    let response: ApiResponse = delete_with_session(
+        &format!("/account/tokens/{}", created.id),
+        &session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::ApiTokenRevoked);
+
+    let response = get_with_token("/account/profile", &created.token, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let _ = delete_reg(email).await;
+}
+
+const TEST_JWT_AUDIENCE: &str = "axumatic-test";
+
+async fn run_test_app_with_jwt(algorithm: jsonwebtoken::Algorithm) -> u16 {
+    init_tracing();
+
+    let mut state = (*get_app_state().await).clone();
+    let jwt_config = JwtConfig {
+        issuer: "http://localhost".to_string(),
+        audience: TEST_JWT_AUDIENCE.to_string(),
+        algorithm,
+        access_token_ttl: 900,
+        refresh_token_ttl_in_days: 30,
+        key_rotation_interval_in_days: 30,
+        secret: Some(generate_unique_id(32)),
+    };
+    state.key_store = Some(Arc::new(KeyStore::new(&jwt_config).unwrap()));
+    state.config.jwt = Some(jwt_config);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    serve_test_app(listener, Arc::new(state))
+}
+
+async fn post_json<T: Serialize>(path: &str, body: &T, port: u16) -> ApiResponse {
+    let client = Client::new();
+    let url = format!("{}:{}{}", SERVER_URL, port, path);
+    client
+        .post(url)
+        .json(body)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn jwt_requires_configuration() {
+    let port = run_test_app().await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+
+    let token_request = TokenRequest {
+        email: email.clone(),
+        password,
+        code: None,
+    };
+    let response = post_json("/account/token", &token_request, port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn jwt_access_and_refresh_tokens() {
+    let port = run_test_app_with_jwt(jsonwebtoken::Algorithm::HS256).await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+
+    let token_request = TokenRequest {
+        email: email.clone(),
+        password: "wrong password".to_string(),
+        code: None,
+    };
+    let response = post_json("/account/token", &token_request, port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let token_request = TokenRequest {
+        email: email.clone(),
+        password,
+        code: None,
+    };
+    let response = post_json("/account/token", &token_request, port).await;
+    assert_eq!(response.response_type, ResponseType::AccessToken);
+    let tokens: TokenPair = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(tokens.token_type, "Bearer");
+
+    let response = get_with_token("/account/profile", &tokens.access_token, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = get_with_token("/account/profile", "not.a.jwt", port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Refreshing rotates the refresh token
+    let refresh_request = RefreshTokenRequest {
+        refresh_token: tokens.refresh_token.clone(),
+    };
+    let response = post_json("/account/token/refresh", &refresh_request, port).await;
+    assert_eq!(response.response_type, ResponseType::AccessToken);
+    let rotated: TokenPair = serde_json::from_str(&response.message).unwrap();
+    assert_ne!(rotated.refresh_token, tokens.refresh_token);
+
+    let response = get_with_token("/account/profile", &rotated.access_token, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Reusing a refresh token revokes every token in its family
+    let response = post_json("/account/token/refresh", &refresh_request, port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let refresh_request = RefreshTokenRequest {
+        refresh_token: rotated.refresh_token,
+    };
+    let response = post_json("/account/token/refresh", &refresh_request, port).await;
+    assert_eq!(response.response_type, ResponseType::Error);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn jwks_verifies_access_tokens() {
+    let port = run_test_app_with_jwt(jsonwebtoken::Algorithm::ES256).await;
+    let (_username, email, password, _response) = create_valid_reg(port).await;
+
+    let client = Client::new();
+    let configuration: OpenIdConfiguration = client
+        .get(format!(
+            "{}:{}/.well-known/openid-configuration",
+            SERVER_URL, port
+        ))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(configuration.issuer, "http://localhost");
+    assert_eq!(
+        configuration.jwks_uri,
+        "http://localhost/.well-known/jwks.json"
+    );
+    assert_eq!(
+        configuration.id_token_signing_alg_values_supported,
+        vec!["ES256"]
+    );
+
+    let token_request = TokenRequest {
+        email: email.clone(),
+        password,
+        code: None,
+    };
+    let response = post_json("/account/token", &token_request, port).await;
+    assert_eq!(response.response_type, ResponseType::AccessToken);
+    let tokens: TokenPair = serde_json::from_str(&response.message).unwrap();
+
+    let response = get_with_token("/account/profile", &tokens.access_token, port).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Another service should be able to verify the token using only the published keys
+    let jwks: JwkSet = client
+        .get(format!("{}:{}/.well-known/jwks.json", SERVER_URL, port))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let kid = jsonwebtoken::decode_header(&tokens.access_token)
+        .unwrap()
+        .kid
+        .unwrap();
+    let jwk = jwks.keys.iter().find(|jwk| jwk.kid == kid).unwrap();
+    let decoding_key = jsonwebtoken::DecodingKey::from_ec_components(&jwk.x, &jwk.y).unwrap();
+    let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+    validation.set_audience(&[TEST_JWT_AUDIENCE]);
+    let claims =
+        jsonwebtoken::decode::<AccessTokenClaims>(&tokens.access_token, &decoding_key, &validation)
+            .unwrap()
+            .claims;
+    assert_eq!(claims.email, email);
+
+    let _ = delete_reg(email).await;
+}
+
+#[tokio::test]
+async fn hmac_keys_are_not_published() {
+    let port = run_test_app_with_jwt(jsonwebtoken::Algorithm::HS256).await;
+
+    let jwks: JwkSet = Client::new()
+        .get(format!("{}:{}/.well-known/jwks.json", SERVER_URL, port))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(jwks.keys.is_empty());
+}
+
+async fn oauth_token_request(
+    form: &[(&str, &str)],
+    client_id: &str,
+    client_secret: &str,
+    path: &str,
+    port: u16,
+) -> Response {
+    Client::new()
+        .post(format!("{}:{}{}", SERVER_URL, port, path))
+        .basic_auth(client_id, Some(client_secret))
+        .form(form)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn oauth_authorization_code_flow() {
+    let port = run_test_app_with_jwt(jsonwebtoken::Algorithm::HS256).await;
+    let (admin_email, admin_session) = create_admin(port).await;
+    let redirect_uri = "https://app.tld.com/callback";
+
+    let client_request = CreateOAuthClientRequest {
+        name: "Test app".to_string(),
+        redirect_uris: vec![redirect_uri.to_string()],
+        allowed_scopes: vec![
+            "openid".to_string(),
+            "profile".to_string(),
+            "email".to_string(),
+        ],
+        trusted: false,
+        confidential: true,
+    };
+    let response: ApiResponse =
+        post_with_session("/admin/oauthClients", &admin_session, &client_request, port)
+            .await
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(response.response_type, ResponseType::OAuthClientCreated);
+    let created: CreatedOAuthClient = serde_json::from_str(&response.message).unwrap();
+    let client_secret = created.client_secret.unwrap();
+
+    let (username, email, password, _response) = create_valid_reg(port).await;
+    let session_key = login(email.clone(), password, port).await.unwrap();
+
+    let code_verifier = generate_unique_id(64);
+    let code_challenge = pkce_challenge(&code_verifier);
+    let client = Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let authorize = |redirect_uri: &'static str, session_key: Option<String>| {
+        let mut request = client
+            .get(format!("{}:{}/oauth/authorize", SERVER_URL, port))
+            .query(&[
+                ("client_id", created.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("response_type", "code"),
+                ("scope", "openid email profile"),
+                ("state", "client-state"),
+                ("nonce", "client-nonce"),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ]);
+        if let Some(session_key) = session_key {
+            request = request.header(COOKIE, format!("session-key={session_key}"));
+        }
+        request.send()
+    };
+
+    // An unregistered redirect URI is never redirected to
+    let response = authorize("https://evil.tld.com/callback", Some(session_key.clone()))
+        .await
+        .unwrap();
+    assert!(!response.status().is_redirection());
+
+    // Without a session the user is sent to log in first
+    let response = authorize(redirect_uri, None).await.unwrap();
+    let location = response.headers()["location"].to_str().unwrap();
+    assert!(location.contains("/login?redirect="));
+
+    let response = authorize(redirect_uri, Some(session_key.clone()))
+        .await
+        .unwrap();
+    let location = response.headers()["location"].to_str().unwrap();
+    assert!(location.contains("/oauth/consent/?request="));
+    let request_id = location.split_once("request=").unwrap().1.to_string();
+
+    let response: ApiResponse = get_with_session(
+        &format!("/oauth/requests/{}", request_id),
+        &session_key,
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(
+        response.response_type,
+        ResponseType::OAuthAuthorizationRequest
+    );
+    let details: AuthorizationRequestDetails = serde_json::from_str(&response.message).unwrap();
+    assert_eq!(details.client_name, "Test app");
+    assert_eq!(details.scope, vec!["email", "openid", "profile"]);
+
+    let response: ApiResponse = post_with_session(
+        &format!("/oauth/requests/{}", request_id),
+        &session_key,
+        &OAuthConsent { approved: true },
+        port,
+    )
+    .await
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(response.response_type, ResponseType::OAuthRedirect);
+    let callback_url = reqwest::Url::parse(&response.message).unwrap();
+    let parameters: HashMap<String, String> = callback_url.query_pairs().into_owned().collect();
+    assert_eq!(parameters["state"], "client-state");
+    let code = parameters["code"].clone();
+
+    let token_form = [
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("code_verifier", code_verifier.as_str()),
+    ];
+    let response = oauth_token_request(
+        &token_form,
+        &created.client_id,
+        "wrong secret",
+        "/oauth/token",
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = oauth_token_request(
+        &token_form,
+        &created.client_id,
+        &client_secret,
+        "/oauth/token",
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let tokens: OAuthTokenResponse = response.json().await.unwrap();
+
+    let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::HS256);
+    validation.insecure_disable_signature_validation();
+    validation.set_audience(&[&created.client_id]);
+    let id_token = jsonwebtoken::decode::<OAuthIdTokenClaims>(
+        tokens.id_token.as_ref().unwrap(),
+        &jsonwebtoken::DecodingKey::from_secret(b""),
+        &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(id_token.nonce.as_deref(), Some("client-nonce"));
+    assert_eq!(id_token.email.as_deref(), Some(email.as_str()));
+
+    // Codes are single use
+    let response = oauth_token_request(
+        &token_form,
+        &created.client_id,
+        &client_secret,
+        "/oauth/token",
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: OAuthErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error, "invalid_grant");
+
+    let user_info: UserInfo = get_with_token("/oauth/userinfo", &tokens.access_token, port)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(user_info.preferred_username, Some(username));
+    assert_eq!(user_info.email, Some(email.clone()));
+
+    // Tokens issued to a client can't be used on axumatic's own API
+    let response = get_with_token("/account/profile", &tokens.access_token, port).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = oauth_token_request(
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", tokens.refresh_token.as_str()),
+        ],
+        &created.client_id,
+        &client_secret,
+        "/oauth/token",
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let refreshed: OAuthTokenResponse = response.json().await.unwrap();
+    assert_eq!(refreshed.scope, "email openid profile");
+
+    let response = oauth_token_request(
+        &[("token", refreshed.refresh_token.as_str())],
+        &created.client_id,
+        &client_secret,
+        "/oauth/revoke",
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = oauth_token_request(
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refreshed.refresh_token.as_str()),
+        ],
+        &created.client_id,
+        &client_secret,
+        "/oauth/token",
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = delete_with_session(
+        &format!("/admin/oauthClients/{}", created.client_id),
+        &admin_session,
+        port,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
     let _ = delete_reg(email).await;
+    let _ = delete_reg(admin_email).await;
 }